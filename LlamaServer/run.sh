@@ -9,7 +9,8 @@
 # bin/llama-server: The path to the llama.cpp server binary
 # -m {{memfd}}: Use the memory file descriptor for the model (placeholder will be replaced by s3mem-run)
 # -fa: Enable Flash Attention for faster processing and better memory efficiency
-# -c 32768: Set the context window size to 32768 tokens (supports very long conversations)
+# -c "{{gguf:min({arch}.context_length,32768)}}": Set the context window to the model's trained context length,
+#   capped at 32768 tokens; s3mem-run expands the placeholder from the model's GGUF metadata
 # -t 6: Use 6 threads to fully utilize all available vCPUs in a 10GB Lambda
 # -b 2048: Set logical batch size to 2048 tokens (maximum tokens processed in a single forward pass)
 # -ub 512: Set physical batch size to 512 tokens (actual tokens processed in parallel by hardware)
@@ -21,4 +22,4 @@
 #   - Client requests can override these values on a per-request basis
 #   - Parameters not specified in client requests will use these server defaults
 
-exec /opt/bin/s3mem-run --bucket $S3_BUCKET --key $S3_KEY bin/llama-server -m {{memfd}} -fa -c 32768 -t 6 -b 2048 -ub 512 --cache-type-k q8_0 --cache-type-v f16 --top-k 40 --top-p 0.9 --temp 0.7 --repeat-penalty 1.1
\ No newline at end of file
+exec /opt/bin/s3mem-run --bucket $S3_BUCKET --key $S3_KEY bin/llama-server -m {{memfd}} -fa -c "{{gguf:min({arch}.context_length,32768)}}" -t 6 -b 2048 -ub 512 --cache-type-k q8_0 --cache-type-v f16 --top-k 40 --top-p 0.9 --temp 0.7 --repeat-penalty 1.1
\ No newline at end of file
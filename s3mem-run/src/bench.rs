@@ -0,0 +1,485 @@
+// The bench subcommand: download an object, discard it and report the throughput
+// It measures what exec's download would achieve from here, without needing the program.
+// Given lists of chunk sizes and concurrency levels it downloads once per combination
+// (or several times, for medians), and a local stand-in for S3 with injected latency and
+// bandwidth limits makes the same grid reproducible anywhere. The stand-in is a test
+// utility of the library, so the --local-size options need the test-utils feature.
+
+use crate::chunk_args::ChunkArgs;             // Chunk planning options
+use crate::progress_display::ProgressArgs;    // Progress display options
+use anyhow::Result;                           // Error handling
+use aws_sdk_s3::Client;                       // AWS S3 client
+use clap::ValueEnum;                          // Command-line enum parsing
+use s3mem_run::inspect::{format_bytes, parse_size};  // Sizes in and out
+#[cfg(feature = "test-utils")]
+use s3mem_run::local_s3::{LocalS3, NetworkConditions};  // The local stand-in
+use s3mem_run::progress::DownloadProgress;    // Timings of the download
+use s3mem_run::planner::{ChunkPlanner, FixedSize};  // Chunk plans of the grid
+use s3mem_run::Loader;                        // The parallel download
+use serde_json::{json, Value};                // Reports
+use std::sync::Arc;                           // Progress shared with the display
+#[cfg(feature = "test-utils")]
+use std::time::Duration;                      // Injected latency
+use tracing::info;                            // Structured logging
+
+#[cfg(feature = "test-utils")]
+const LOCAL_BUCKET: &str = "bench";           // Where the local stand-in serves its object
+#[cfg(feature = "test-utils")]
+const LOCAL_KEY: &str = "object";
+
+// Output format of the results
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum BenchFormat {
+    Human,
+    Json,
+    Csv,
+}
+
+// Where the downloaded bytes go
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Sink {
+    Memfd,                                // Into a memory file, as exec does
+    Discard,                              // Nowhere, which needs no memory for the object
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// S3 bucket containing the file (defaults to S3_BUCKET env var)
+    #[arg(long, env = "S3_BUCKET")]
+    pub bucket: Option<String>,
+
+    /// S3 key of the file (defaults to S3_KEY env var)
+    #[arg(long, env = "S3_KEY")]
+    pub key: Option<String>,
+
+    /// Chunk sizes to try, comma-separated, such as 8M,16M,64M (default: planned as
+    /// exec would with the chunk options below)
+    #[arg(long, value_delimiter = ',', value_parser = parse_size)]
+    pub chunk_sizes: Vec<u64>,
+
+    /// Concurrency levels to try, comma-separated, such as 8,16,32 (default: scaled with
+    /// the object size, as exec does)
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+    pub concurrency: Vec<u64>,
+
+    /// Downloads per combination; the median throughput is reported
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub runs: u32,
+
+    /// Where the bytes go: memfd, as exec does, or discard
+    #[arg(long, value_enum, default_value = "memfd")]
+    pub sink: Sink,
+
+    /// Output format: human, json, or csv with one row per combination
+    #[arg(long, value_enum, default_value = "human")]
+    pub format: BenchFormat,
+
+    /// Download a synthetic object of this size from a stand-in S3 on localhost instead
+    #[cfg(feature = "test-utils")]
+    #[arg(long, value_parser = parse_size)]
+    pub local_size: Option<u64>,
+
+    /// Milliseconds the stand-in waits before every response
+    #[cfg(feature = "test-utils")]
+    #[arg(long, requires = "local_size")]
+    pub latency: Option<u64>,
+
+    /// Bytes per second the stand-in sends in total, such as 500M
+    #[cfg(feature = "test-utils")]
+    #[arg(long, value_parser = parse_size, requires = "local_size")]
+    pub bandwidth: Option<u64>,
+
+    /// Bytes per second the stand-in sends on each connection, such as 90M
+    #[cfg(feature = "test-utils")]
+    #[arg(long, value_parser = parse_size, requires = "local_size")]
+    pub connection_bandwidth: Option<u64>,
+
+    #[command(flatten)]
+    pub progress: ProgressArgs,
+
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+}
+
+// The runs of one chunk size and concurrency
+#[derive(Debug)]
+struct Measurement {
+    chunk_size: u64,                      // As planned, also when scaled with the object size
+    concurrency: u64,
+    chunks: u64,
+    throughput_mbps: Vec<f64>,            // Of each run
+    wall_time_ms: Vec<f64>,
+    ttfb_p50_ms: Vec<f64>,
+    retries: u64,                         // Of all runs
+    report: Value,                        // Download timing report of the last run
+}
+
+impl Measurement {
+    fn new(reports: Vec<Value>) -> Self {
+        let number = |value: &Value| value.as_f64().unwrap_or(0.0);
+        let last = reports.last().cloned().unwrap_or(Value::Null);
+        Measurement {
+            chunk_size: last["objects"][0]["chunk_size_bytes"].as_u64().unwrap_or(0),
+            concurrency: last["concurrency"].as_u64().unwrap_or(0),
+            chunks: last["chunks"]["count"].as_u64().unwrap_or(0),
+            throughput_mbps: reports.iter().map(|report| number(&report["throughput_mbps"])).collect(),
+            wall_time_ms: reports.iter().map(|report| number(&report["wall_time_ms"])).collect(),
+            ttfb_p50_ms: reports.iter().map(|report| number(&report["chunks"]["ttfb_ms"]["p50"])).collect(),
+            retries: reports.iter().map(|report| report["chunks"]["retries"].as_u64().unwrap_or(0)).sum(),
+            report: last,
+        }
+    }
+
+    fn throughput(&self) -> f64 {
+        median(&self.throughput_mbps)
+    }
+
+    fn min_throughput(&self) -> f64 {
+        self.throughput_mbps.iter().copied().fold(f64::INFINITY, f64::min)
+    }
+
+    fn max_throughput(&self) -> f64 {
+        self.throughput_mbps.iter().copied().fold(0.0, f64::max)
+    }
+
+    fn to_json(&self, best: bool) -> Value {
+        json!({
+            "chunk_size_bytes": self.chunk_size,
+            "concurrency": self.concurrency,
+            "chunks": self.chunks,
+            "runs": self.throughput_mbps.len(),
+            "throughput_mbps": {
+                "median": self.throughput(),
+                "min": self.min_throughput(),
+                "max": self.max_throughput(),
+            },
+            "wall_time_ms": median(&self.wall_time_ms),
+            "ttfb_p50_ms": median(&self.ttfb_p50_ms),
+            "retries": self.retries,
+            "best": best,
+        })
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    match sorted.len() {
+        0 => 0.0,
+        len if len % 2 == 1 => sorted[len / 2],
+        len => (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0,
+    }
+}
+
+// The combination with the highest median throughput
+fn best(measurements: &[Measurement]) -> Option<usize> {
+    (0..measurements.len()).max_by(|&a, &b| measurements[a].throughput().total_cmp(&measurements[b].throughput()))
+}
+
+pub async fn run_bench(args: &BenchArgs) -> Result<()> {
+    #[cfg(not(feature = "test-utils"))]
+    let (client, bucket, key) = {
+        let (bucket, key) = crate::require_bucket_and_key(args.bucket.clone(), args.key.clone())?;
+        (crate::s3_client().await, bucket, key)
+    };
+
+    // The stand-in stops when this goes out of scope
+    #[cfg(feature = "test-utils")]
+    let (_local, client, bucket, key) = match args.local_size {
+        Some(size) => {
+            let network = NetworkConditions {
+                latency: Duration::from_millis(args.latency.unwrap_or(0)),
+                bandwidth: args.bandwidth,
+                connection_bandwidth: args.connection_bandwidth,
+            };
+            let data = (0..size).map(|i| (i % 251) as u8).collect();
+            let local = LocalS3::start_with(vec![(LOCAL_BUCKET, LOCAL_KEY, data)], network).await?;
+            let client = local.client().await;
+            info!(size, ?network, "Serving a synthetic object locally");
+            (Some(local), client, LOCAL_BUCKET.to_string(), LOCAL_KEY.to_string())
+        }
+        None => {
+            let (bucket, key) = crate::require_bucket_and_key(args.bucket.clone(), args.key.clone())?;
+            (None, crate::s3_client().await, bucket, key)
+        }
+    };
+
+    let measurements = measure_grid(&client, &bucket, &key, args).await?;
+    let best = best(&measurements);
+    match args.format {
+        BenchFormat::Json => println!("{:#}", json_report(&bucket, &key, args, &measurements, best)),
+        BenchFormat::Csv => print!("{}", csv(&measurements, best)),
+        BenchFormat::Human if measurements.len() == 1 && args.runs == 1 => {
+            for line in human_summary(&measurements[0].report) {
+                println!("{}", line);
+            }
+        }
+        BenchFormat::Human => print!("{}", table(&measurements, best)),
+    }
+    Ok(())
+}
+
+// Download the object `args.runs` times with every combination of chunk size and concurrency
+async fn measure_grid(client: &Client, bucket: &str, key: &str, args: &BenchArgs) -> Result<Vec<Measurement>> {
+    let planners: Vec<Arc<dyn ChunkPlanner>> = match args.chunk_sizes.as_slice() {
+        [] => vec![args.chunks.planner()?],
+        sizes => sizes.iter().map(|&size| Arc::new(FixedSize(size)) as Arc<dyn ChunkPlanner>).collect(),
+    };
+    let limits = args.chunks.limits()?;
+    let concurrencies: Vec<Option<u64>> = match args.concurrency.as_slice() {
+        [] => vec![None],
+        levels => levels.iter().copied().map(Some).collect(),
+    };
+
+    let mut measurements = Vec::new();
+    for planner in &planners {
+        for &concurrency in &concurrencies {
+            let mut reports = Vec::new();
+            for run in 1..=args.runs {
+                let progress = Arc::new(DownloadProgress::new());
+                let display = args.progress.start(&progress);
+                let mut loader = Loader::new(client.clone())
+                    .source(bucket, key)
+                    .planner(planner.clone())
+                    .limits(limits)
+                    .progress(progress.clone());
+                if let Some(concurrency) = concurrency {
+                    loader = loader.concurrency(concurrency as usize);
+                }
+                // A memory file is dropped as soon as it is complete
+                let downloaded = match args.sink {
+                    Sink::Memfd => loader.load().await.map(drop),
+                    Sink::Discard => loader.discard_all().await.map(drop),
+                };
+                if let Some(display) = display {
+                    display.finish().await;
+                }
+                downloaded?;
+
+                let report = progress.report(bucket);
+                info!(
+                    chunk_size = report["objects"][0]["chunk_size_bytes"].as_u64(),
+                    concurrency = report["concurrency"].as_u64(),
+                    run,
+                    throughput_mbps = report["throughput_mbps"].as_f64(),
+                    "Benchmark run finished"
+                );
+                reports.push(report);
+            }
+            measurements.push(Measurement::new(reports));
+        }
+    }
+    Ok(measurements)
+}
+
+fn json_report(bucket: &str, key: &str, args: &BenchArgs, measurements: &[Measurement], best: Option<usize>) -> Value {
+    let results: Vec<Value> = measurements
+        .iter()
+        .enumerate()
+        .map(|(index, measurement)| measurement.to_json(Some(index) == best))
+        .collect();
+    #[cfg(feature = "test-utils")]
+    let local = args.local_size.map(|_| json!({
+        "latency_ms": args.latency.unwrap_or(0),
+        "bandwidth_bytes_per_sec": args.bandwidth,
+        "connection_bandwidth_bytes_per_sec": args.connection_bandwidth,
+    }));
+    #[cfg(not(feature = "test-utils"))]
+    let local: Option<Value> = None;
+    json!({
+        "bucket": bucket,
+        "key": key,
+        "size_bytes": measurements.first().map(|measurement| &measurement.report["objects"][0]["size_bytes"]),
+        "sink": match args.sink { Sink::Memfd => "memfd", Sink::Discard => "discard" },
+        "local": local,
+        "results": results,
+        "best": best.map(|index| results[index].clone()),
+    })
+}
+
+fn csv(measurements: &[Measurement], best: Option<usize>) -> String {
+    let mut csv = String::from(
+        "chunk_size_bytes,concurrency,chunks,runs,throughput_mbps_median,throughput_mbps_min,throughput_mbps_max,wall_time_ms_median,ttfb_p50_ms_median,retries,best\n",
+    );
+    for (index, measurement) in measurements.iter().enumerate() {
+        csv.push_str(&format!(
+            "{},{},{},{},{:.1},{:.1},{:.1},{:.0},{:.1},{},{}\n",
+            measurement.chunk_size,
+            measurement.concurrency,
+            measurement.chunks,
+            measurement.throughput_mbps.len(),
+            measurement.throughput(),
+            measurement.min_throughput(),
+            measurement.max_throughput(),
+            median(&measurement.wall_time_ms),
+            median(&measurement.ttfb_p50_ms),
+            measurement.retries,
+            Some(index) == best,
+        ));
+    }
+    csv
+}
+
+// One row per combination, the best marked with *
+fn table(measurements: &[Measurement], best: Option<usize>) -> String {
+    let mut table = format!(
+        "  {:<10}  {:>11}  {:>6}  {:>8}  {:>8}  {:>8}  {:>8}  {:>7}  {:>7}\n",
+        "CHUNK SIZE", "CONCURRENCY", "CHUNKS", "MB/S", "MIN MB/S", "MAX MB/S", "WALL MS", "TTFB MS", "RETRIES"
+    );
+    for (index, measurement) in measurements.iter().enumerate() {
+        table.push_str(&format!(
+            "{} {:<10}  {:>11}  {:>6}  {:>8.1}  {:>8.1}  {:>8.1}  {:>8.0}  {:>7.1}  {:>7}\n",
+            if Some(index) == best { "*" } else { " " },
+            format_bytes(measurement.chunk_size),
+            measurement.concurrency,
+            measurement.chunks,
+            measurement.throughput(),
+            measurement.min_throughput(),
+            measurement.max_throughput(),
+            median(&measurement.wall_time_ms),
+            median(&measurement.ttfb_p50_ms),
+            measurement.retries,
+        ));
+    }
+    if let Some(index) = best {
+        let measurement = &measurements[index];
+        let runs = measurement.throughput_mbps.len();
+        table.push_str(&format!(
+            "\nBest: {} chunks, {} at a time, {:.1} MB/s median over {} {}\n",
+            format_bytes(measurement.chunk_size),
+            measurement.concurrency,
+            measurement.throughput(),
+            runs,
+            if runs == 1 { "run" } else { "runs" }
+        ));
+    }
+    table
+}
+
+// The headline numbers of a download timing report
+fn human_summary(report: &Value) -> Vec<String> {
+    let number = |value: &Value| value.as_f64().unwrap_or(0.0);
+    let object = &report["objects"][0];
+    let chunks = &report["chunks"];
+    let retries = chunks["retries"].as_u64().unwrap_or(0);
+    vec![
+        format!(
+            "Object:        s3://{}/{} ({})",
+            report["bucket"].as_str().unwrap_or(""),
+            object["key"].as_str().unwrap_or(""),
+            format_bytes(report["total_bytes"].as_u64().unwrap_or(0))
+        ),
+        format!(
+            "Chunks:        {} of {}, {} at a time, {} {}",
+            chunks["count"],
+            format_bytes(object["chunk_size_bytes"].as_u64().unwrap_or(0)),
+            report["concurrency"],
+            retries,
+            if retries == 1 { "retry" } else { "retries" }
+        ),
+        format!("Wall time:     {:.0} ms", number(&report["wall_time_ms"])),
+        format!(
+            "Throughput:    {:.1} MB/s ({:.2} Gbit/s)",
+            number(&report["throughput_mbps"]),
+            number(&report["effective_gbps"])
+        ),
+        format!(
+            "HEAD latency:  {:.0} ms",
+            number(&report["head_latency_ms"]["max"])
+        ),
+        format!(
+            "TTFB:          p50 {:.0} ms, p99 {:.0} ms",
+            number(&chunks["ttfb_ms"]["p50"]),
+            number(&chunks["ttfb_ms"]["p99"])
+        ),
+        format!(
+            "Chunk rate:    p50 {:.1} MB/s, min {:.1} MB/s",
+            number(&chunks["throughput_mbps"]["p50"]),
+            number(&chunks["throughput_mbps"]["min"])
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use s3mem_run::report::Timings;
+
+    fn bench_args(args: &[&str]) -> BenchArgs {
+        let args = crate::Args::try_parse_from(["s3mem-run", "bench"].iter().chain(args)).unwrap();
+        match args.subcommand {
+            Some(crate::Commands::Bench(bench_args)) => bench_args,
+            other => panic!("expected bench subcommand, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_human_summary() {
+        let mut timings = Timings::default();
+        timings.record_head("model.gguf", 4_000_000, 2_000_000, Duration::from_millis(20));
+        timings.record_chunk(2_000_000, Duration::from_millis(30), Duration::from_millis(500), 1);
+        timings.record_chunk(2_000_000, Duration::from_millis(50), Duration::from_millis(1000), 2);
+        timings.set_concurrency(2);
+        timings.finish(Duration::from_secs(1));
+
+        let lines = human_summary(&timings.to_json("models"));
+        assert_eq!(lines[0], "Object:        s3://models/model.gguf (3.81 MiB)");
+        assert_eq!(lines[1], "Chunks:        2 of 1.91 MiB, 2 at a time, 1 retry");
+        assert_eq!(lines[2], "Wall time:     1000 ms");
+        assert_eq!(lines[3], "Throughput:    4.0 MB/s (0.03 Gbit/s)");
+        assert_eq!(lines[5], "TTFB:          p50 30 ms, p99 50 ms");
+    }
+
+    #[test]
+    fn test_bench_args() {
+        let args = bench_args(&["--local-size", "64M", "--chunk-sizes", "4M,8MiB", "--concurrency", "4,8", "--runs", "3"]);
+        assert_eq!(args.local_size, Some(64 << 20));
+        assert_eq!(args.chunk_sizes, vec![4 << 20, 8 << 20]);
+        assert_eq!(args.concurrency, vec![4, 8]);
+        assert_eq!(args.sink, Sink::Memfd);
+
+        // Network conditions only apply to the stand-in
+        assert!(crate::Args::try_parse_from(["s3mem-run", "bench", "--latency", "20"]).is_err());
+        assert!(crate::Args::try_parse_from(["s3mem-run", "bench", "--concurrency", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[]), 0.0);
+        assert_eq!(median(&[3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&[4.0, 1.0, 2.0, 3.0]), 2.5);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_measure_grid() {
+        let args = bench_args(&[
+            "--local-size", "1", "--chunk-sizes", "64K,320K", "--concurrency", "1,4", "--runs", "2",
+            "--sink", "discard", "--progress", "off",
+        ]);
+        let data: Vec<u8> = (0..640 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let s3 = LocalS3::start(vec![(LOCAL_BUCKET, LOCAL_KEY, data)]).await.unwrap();
+
+        let measurements = measure_grid(&s3.client().await, LOCAL_BUCKET, LOCAL_KEY, &args).await.unwrap();
+        let grid: Vec<(u64, u64, u64)> = measurements
+            .iter()
+            .map(|measurement| (measurement.chunk_size, measurement.concurrency, measurement.chunks))
+            .collect();
+        assert_eq!(grid, vec![(65536, 1, 10), (65536, 4, 10), (327680, 1, 2), (327680, 4, 2)]);
+        assert!(measurements.iter().all(|measurement| measurement.throughput_mbps.len() == 2));
+
+        let best = best(&measurements).unwrap();
+        let csv = csv(&measurements, Some(best));
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 5);
+        assert!(rows[best + 1].ends_with(",true"));
+        assert_eq!(rows.iter().filter(|row| row.ends_with(",true")).count(), 1);
+
+        let table = table(&measurements, Some(best));
+        assert!(table.lines().nth(best + 1).unwrap().starts_with('*'));
+        let report = json_report(LOCAL_BUCKET, LOCAL_KEY, &args, &measurements, Some(best));
+        assert_eq!(report["size_bytes"], 640 * 1024);
+        assert_eq!(report["results"].as_array().unwrap().len(), 4);
+        assert_eq!(report["best"], report["results"][best]);
+    }
+}
@@ -0,0 +1,369 @@
+// Capture of the program's stdout and stderr
+// Without it the program's unstructured output ends up in CloudWatch mixed with our
+// structured logs. When captured, every line is tagged with its stream, the model key
+// and the child's pid, known llama-server messages (model loading, server start, slot
+// events, timings, requests, errors) are parsed into fields, and the line is emitted
+// through the same tracing subscriber as everything else, under the target "child".
+
+use std::io::{BufRead, BufReader, Read};      // Reading lines from the pipes
+use std::sync::mpsc;                          // Reader threads report when they finish
+use std::time::{Duration, Instant};           // Waiting for readers at exit
+use tokio::io::AsyncBufReadExt;               // Reading lines from async pipes
+use tracing::{event, Level};                  // Re-emitting lines
+
+// Which of the program's streams a line came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+// What is attached to every captured line
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub model_key: String,                // S3 key of the model the program serves
+}
+
+// A line of program output with what could be parsed from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildLine<'a> {
+    pub level: Level,
+    pub event: Option<&'static str>,      // Kind of message, such as "slot" or "request"
+    pub slot: Option<u64>,                // Slot id of slot events
+    pub task: Option<u64>,                // Task id of slot events
+    pub progress: Option<f64>,            // Prompt processing progress from 0 to 1
+    pub tokens: Option<u64>,              // Token count of timing lines
+    pub tokens_per_sec: Option<f64>,      // Throughput of timing lines
+    pub method: Option<&'a str>,          // HTTP method of request lines
+    pub path: Option<&'a str>,            // HTTP path of request lines
+    pub status: Option<u16>,              // HTTP status of request lines
+    pub url: Option<&'a str>,             // Address the server listens on
+    pub message: &'a str,                 // The line itself
+}
+
+// Parse a line of llama-server output
+// Lines that match no known pattern are kept as plain messages at info level
+pub fn parse_line(line: &str) -> ChildLine<'_> {
+    let mut parsed = ChildLine {
+        level: Level::INFO,
+        event: None,
+        slot: None,
+        task: None,
+        progress: None,
+        tokens: None,
+        tokens_per_sec: None,
+        method: None,
+        path: None,
+        status: None,
+        url: None,
+        message: line,
+    };
+    let (marker, trimmed) = split_log_prefix(line);
+    let lower = line.to_ascii_lowercase();
+
+    if trimmed.starts_with("slot ") {
+        // "slot update_slots: id  0 | task 3 | prompt processing progress, ..., progress = 0.5"
+        parsed.event = Some("slot");
+        parsed.slot = number_after(line, "id ");
+        parsed.task = number_after(line, "task ");
+        parsed.progress = number_after(line, "progress = ");
+    } else if let Some(rest) = field_after(line, "request: ") {
+        // "srv  log_server_r: request: POST /completion 127.0.0.1 200"
+        let mut words = rest.split_whitespace();
+        parsed.event = Some("request");
+        parsed.method = words.next();
+        parsed.path = words.next();
+        parsed.status = words.last().and_then(|status| status.parse().ok());
+        if parsed.status.is_some_and(|status| status >= 500) {
+            parsed.level = Level::WARN;
+        }
+    } else if trimmed.starts_with("prompt eval time") || trimmed.starts_with("eval time") {
+        // "prompt eval time =  120.00 ms /  10 tokens ( 12.00 ms per token, 83.33 tokens per second)"
+        parsed.event = Some(if trimmed.starts_with("prompt") { "prompt_eval" } else { "eval" });
+        parsed.tokens = number_after(line, "/");
+        parsed.tokens_per_sec = number_after(line, "per token,");
+    } else if lower.contains("server is listening on") {
+        parsed.event = Some("listening");
+        parsed.url = field_after(line, "listening on ").and_then(|rest| rest.split_whitespace().next());
+    } else if trimmed.starts_with("llama_model_loader") || trimmed.starts_with("load_tensors") || trimmed.starts_with("llama_model_load") || trimmed.starts_with("print_info") {
+        parsed.event = Some("model_load");
+    } else if lower.contains("model loaded") {
+        parsed.event = Some("model_loaded");
+    }
+
+    // Errors and warnings are recognized by llama.cpp's log level marker, "error:" at the
+    // start of the line, or how the message after a "function: " prefix starts, so a line
+    // merely mentioning an error, such as a vocabulary entry or a request path, keeps its level
+    let text = trimmed.to_ascii_lowercase();
+    let message = function_message(&text).unwrap_or_default();
+    let starts_with_any = |prefixes: &[&str]| prefixes.iter().any(|prefix| message.starts_with(prefix));
+    if marker == Some('E')
+        || line.contains("GGML_ASSERT")
+        || text.starts_with("error:")
+        || starts_with_any(&["error", "failed to", "out of memory"])
+    {
+        parsed.level = Level::ERROR;
+        parsed.event = parsed.event.or(Some("error"));
+    } else if marker == Some('W') || text.starts_with("warning:") || starts_with_any(&["warning"]) {
+        parsed.level = Level::WARN;
+    }
+    parsed
+}
+
+// Split llama.cpp's optional log prefix off a line
+// With --log-prefix, lines start with a level marker (I, W, E or D), preceded by a
+// timestamp such as "0.01.234.567" with --log-timestamps
+fn split_log_prefix(line: &str) -> (Option<char>, &str) {
+    let mut rest = line.trim_start();
+    if let Some((timestamp, after)) = rest.split_once(' ') {
+        if timestamp.contains('.') && timestamp.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            rest = after;
+        }
+    }
+    match rest.as_bytes() {
+        [marker @ (b'I' | b'W' | b'E' | b'D'), b' ', ..] => (Some(*marker as char), rest[2..].trim_start()),
+        _ => (None, line.trim_start()),
+    }
+}
+
+// The message of a line after its "function: " or "srv  function: " prefix, if it has one
+fn function_message(line: &str) -> Option<&str> {
+    let (prefix, message) = line.split_once(": ")?;
+    prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ' ')
+        .then_some(message)
+}
+
+// The number that follows a marker in a line, skipping spaces
+fn number_after<T: std::str::FromStr>(line: &str, marker: &str) -> Option<T> {
+    let rest = field_after(line, marker)?.trim_start();
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+// The rest of a line after the first occurrence of a marker
+fn field_after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.find(marker).map(|start| &line[start + marker.len()..])
+}
+
+// Emit a line of program output through tracing
+pub fn emit(capture: &Capture, pid: u32, stream: Stream, line: &str) {
+    let line = line.trim_end_matches('\r');
+    if line.trim().is_empty() {
+        return;
+    }
+    let parsed = parse_line(line);
+
+    // The level of an event is fixed at the call site, so each level gets its own
+    macro_rules! emit_at {
+        ($level:expr) => {
+            event!(
+                target: "child",
+                $level,
+                stream = stream.as_str(),
+                model_key = %capture.model_key,
+                pid,
+                event = parsed.event,
+                slot = parsed.slot,
+                task = parsed.task,
+                progress = parsed.progress,
+                tokens = parsed.tokens,
+                tokens_per_sec = parsed.tokens_per_sec,
+                method = parsed.method,
+                path = parsed.path,
+                status = parsed.status,
+                url = parsed.url,
+                "{}",
+                parsed.message
+            )
+        };
+    }
+    match parsed.level {
+        Level::ERROR => emit_at!(Level::ERROR),
+        Level::WARN => emit_at!(Level::WARN),
+        _ => emit_at!(Level::INFO),
+    }
+}
+
+// Readers of a child's pipes running on their own threads
+pub struct Readers {
+    done: mpsc::Receiver<()>,
+    count: usize,
+}
+
+impl Readers {
+    // Wait for the readers to reach the end of their pipes, which happens soon after the
+    // child exits, unless something else still holds the pipes open
+    pub fn wait(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for _ in 0..self.count {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(remaining).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// Start threads that emit the lines of a child started with piped stdout and stderr
+pub fn capture_child(child: &mut std::process::Child, capture: &Capture) -> Readers {
+    let (sender, done) = mpsc::channel();
+    let mut count = 0;
+    let pid = child.id();
+    let pipes: [(Stream, Option<Box<dyn Read + Send>>); 2] = [
+        (Stream::Stdout, child.stdout.take().map(|pipe| Box::new(pipe) as Box<dyn Read + Send>)),
+        (Stream::Stderr, child.stderr.take().map(|pipe| Box::new(pipe) as Box<dyn Read + Send>)),
+    ];
+    for (stream, pipe) in pipes {
+        let Some(pipe) = pipe else { continue };
+        let capture = capture.clone();
+        let sender = sender.clone();
+        count += 1;
+        std::thread::spawn(move || {
+            for line in BufReader::new(pipe).split(b'\n') {
+                let Ok(line) = line else { break };
+                emit(&capture, pid, stream, &String::from_utf8_lossy(&line));
+            }
+            let _ = sender.send(());
+        });
+    }
+    Readers { done, count }
+}
+
+// Start tasks that emit the lines of an async child started with piped stdout and stderr
+pub fn capture_async_child(child: &mut tokio::process::Child, capture: &Capture) {
+    let pid = child.id().unwrap_or_default();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(emit_lines(tokio::io::BufReader::new(stdout), capture.clone(), pid, Stream::Stdout));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(emit_lines(tokio::io::BufReader::new(stderr), capture.clone(), pid, Stream::Stderr));
+    }
+}
+
+async fn emit_lines<R: tokio::io::AsyncBufRead + Unpin>(reader: R, capture: Capture, pid: u32, stream: Stream) {
+    let mut lines = reader.split(b'\n');
+    while let Ok(Some(line)) = lines.next_segment().await {
+        emit(&capture, pid, stream, &String::from_utf8_lossy(&line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_slot_line() {
+        let line = parse_line(
+            "slot update_slots: id  0 | task 3 | prompt processing progress, n_past = 512, n_tokens = 512, progress = 0.25",
+        );
+        assert_eq!(line.event, Some("slot"));
+        assert_eq!(line.slot, Some(0));
+        assert_eq!(line.task, Some(3));
+        assert_eq!(line.progress, Some(0.25));
+        assert_eq!(line.level, Level::INFO);
+    }
+
+    #[test]
+    fn test_parse_request_and_timing_lines() {
+        let line = parse_line("srv  log_server_r: request: POST /completion 127.0.0.1 200");
+        assert_eq!(line.event, Some("request"));
+        assert_eq!((line.method, line.path, line.status), (Some("POST"), Some("/completion"), Some(200)));
+
+        let line = parse_line("prompt eval time =     120.00 ms /    10 tokens (   12.00 ms per token,    83.33 tokens per second)");
+        assert_eq!(line.event, Some("prompt_eval"));
+        assert_eq!(line.tokens, Some(10));
+        assert_eq!(line.tokens_per_sec, Some(83.33));
+
+        let line = parse_line("       eval time =    1000.00 ms /    50 tokens (   20.00 ms per token,    50.00 tokens per second)");
+        assert_eq!(line.event, Some("eval"));
+        assert_eq!(line.tokens_per_sec, Some(50.0));
+    }
+
+    #[test]
+    fn test_parse_server_and_error_lines() {
+        let line = parse_line("main: server is listening on http://127.0.0.1:8080 - starting the main loop");
+        assert_eq!(line.event, Some("listening"));
+        assert_eq!(line.url, Some("http://127.0.0.1:8080"));
+
+        let line = parse_line("llama_model_loader: loaded meta data with 33 key-value pairs and 292 tensors");
+        assert_eq!(line.event, Some("model_load"));
+
+        let line = parse_line("llama_model_load: error loading model: tensor 'output.weight' data is not within the file bounds");
+        assert_eq!(line.event, Some("model_load"));
+        assert_eq!(line.level, Level::ERROR);
+
+        let line = parse_line("/src/ggml.c:1234: GGML_ASSERT(n <= max) failed");
+        assert_eq!(line.event, Some("error"));
+        assert_eq!(line.level, Level::ERROR);
+
+        let line = parse_line("srv    load_model: failed to load model, '/proc/self/fd/3'");
+        assert_eq!(line.level, Level::ERROR);
+
+        let line = parse_line("error: invalid argument: --ctx");
+        assert_eq!(line.level, Level::ERROR);
+
+        let line = parse_line("0.01.234.567 E main: exiting due to model loading error");
+        assert_eq!(line.level, Level::ERROR);
+
+        let line = parse_line("W llama_context: n_ctx_per_seq (4096) < n_ctx_train (32768)");
+        assert_eq!(line.level, Level::WARN);
+
+        let line = parse_line("warning: no usable GPU found, --gpu-layers option will be ignored");
+        assert_eq!(line.level, Level::WARN);
+
+        let line = parse_line("0.00.035.060 I llama_model_loader: loaded meta data with 33 key-value pairs");
+        assert_eq!(line.event, Some("model_load"));
+        assert_eq!(line.level, Level::INFO);
+
+        let line = parse_line("some unrelated output");
+        assert_eq!(line.event, None);
+        assert_eq!(line.level, Level::INFO);
+    }
+
+    #[test]
+    fn test_parse_lines_mentioning_errors() {
+        // Lines that mention errors or failures without being one keep their level
+        for text in [
+            "llama_model_loader: - kv  24: tokenizer.ggml.tokens arr[str,151936] = [\"error\", \"failed\", ...]",
+            "srv  log_server_r: request: POST /v1/errors/failed 127.0.0.1 200",
+            "slot update_slots: id  0 | task 3 | new prompt, n_ctx_slot = 4096, error_count = 0",
+            "print_info: general.name = Warning Error Model",
+            "Explain why the build failed with an error",
+            "Error handling in Rust",
+            "Every line is INFO",
+        ] {
+            let line = parse_line(text);
+            assert_eq!(line.level, Level::INFO, "{}", text);
+            assert_ne!(line.event, Some("error"), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_capture_child_reaches_end_of_pipes() {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "echo out; echo err >&2"])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let readers = capture_child(&mut child, &Capture { model_key: "model.gguf".to_string() });
+        assert_eq!(readers.count, 2);
+        child.wait().unwrap();
+        let started = Instant::now();
+        readers.wait(Duration::from_secs(5));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,111 @@
+// Chunk planning options of every command that downloads
+// They override the compiled-in chunk size and concurrency bounds and pick the planner,
+// and are checked before anything is downloaded.
+
+use anyhow::Result;                           // Error handling
+use s3mem_run::inspect::parse_size;           // Sizes with units
+use s3mem_run::planner::{ChunkLimits, ChunkPlanner, ChunkStrategy};  // Chunk planning
+use std::sync::Arc;                           // Shared planners
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ChunkArgs {
+    /// How objects are split into ranged requests: size-scaled (equal chunks scaled with
+    /// the object size), fixed (--chunk-size bytes), part-aligned (whole parts of a multipart
+    /// upload) or adaptive (small chunks first, doubling up to the size-scaled size)
+    #[arg(long, env = "CHUNK_STRATEGY", value_enum)]
+    pub chunk_strategy: Option<ChunkStrategy>,
+
+    /// Bytes per ranged request, such as 16M; implies the fixed strategy
+    #[arg(long, env = "CHUNK_SIZE", value_parser = parse_size)]
+    pub chunk_size: Option<u64>,
+
+    /// Smallest size-scaled chunk [default: 4M]
+    #[arg(long, env = "MIN_CHUNK_SIZE", value_parser = parse_size)]
+    pub min_chunk_size: Option<u64>,
+
+    /// Largest size-scaled chunk [default: 128M]
+    #[arg(long, env = "MAX_CHUNK_SIZE", value_parser = parse_size)]
+    pub max_chunk_size: Option<u64>,
+
+    /// Chunks per object the size-scaled chunk size aims for [default: 75]
+    #[arg(long, env = "TARGET_CHUNKS_PER_FILE", value_parser = clap::value_parser!(u64).range(1..))]
+    pub target_chunks: Option<u64>,
+
+    /// Parallel requests for objects up to 0.5GB [default: 4]
+    #[arg(long, env = "MIN_CONCURRENT_DOWNLOADS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_concurrency: Option<u64>,
+
+    /// Parallel requests for objects of 10GB and more [default: 16]
+    #[arg(long, env = "MAX_CONCURRENT_DOWNLOADS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrency: Option<u64>,
+}
+
+impl ChunkArgs {
+    // The bounds, with the defaults for any that weren't given
+    pub fn limits(&self) -> Result<ChunkLimits> {
+        let defaults = ChunkLimits::default();
+        let limits = ChunkLimits {
+            min_chunk_size: self.min_chunk_size.unwrap_or(defaults.min_chunk_size),
+            max_chunk_size: self.max_chunk_size.unwrap_or(defaults.max_chunk_size),
+            target_chunks: self.target_chunks.unwrap_or(defaults.target_chunks),
+            min_concurrency: self.min_concurrency.map_or(defaults.min_concurrency, |value| value as usize),
+            max_concurrency: self.max_concurrency.map_or(defaults.max_concurrency, |value| value as usize),
+        };
+        limits.validate()?;
+        Ok(limits)
+    }
+
+    // The chosen planner; a chunk size on its own selects the fixed strategy
+    pub fn planner(&self) -> Result<Arc<dyn ChunkPlanner>> {
+        let strategy = match (self.chunk_strategy, self.chunk_size) {
+            (None, Some(_)) => ChunkStrategy::Fixed,
+            (strategy, _) => strategy.unwrap_or_default(),
+        };
+        strategy.planner(self.chunk_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Command {
+        #[command(flatten)]
+        chunks: ChunkArgs,
+    }
+
+    fn chunk_args(args: &[&str]) -> ChunkArgs {
+        Command::try_parse_from(["s3mem-run"].iter().chain(args)).unwrap().chunks
+    }
+
+    #[test]
+    fn test_chunk_args() {
+        let args = chunk_args(&[]);
+        assert_eq!(args.limits().unwrap(), ChunkLimits::default());
+        assert_eq!(format!("{:?}", args.planner().unwrap()), "SizeScaled");
+
+        let args = chunk_args(&["--min-chunk-size", "8M", "--max-concurrency", "64", "--chunk-strategy", "adaptive"]);
+        let limits = args.limits().unwrap();
+        assert_eq!(limits.min_chunk_size, 8 << 20);
+        assert_eq!(limits.max_concurrency, 64);
+        assert_eq!(format!("{:?}", args.planner().unwrap()), "Adaptive");
+
+        assert_eq!(format!("{:?}", chunk_args(&["--chunk-size", "16M"]).planner().unwrap()), "FixedSize(16777216)");
+        assert!(chunk_args(&["--chunk-strategy", "fixed"]).planner().is_err());
+        assert!(chunk_args(&["--chunk-strategy", "part-aligned", "--chunk-size", "16M"]).planner().is_err());
+
+        // Bounds that contradict each other or the defaults are refused
+        assert!(chunk_args(&["--min-chunk-size", "256M"]).limits().is_err());
+        assert!(chunk_args(&["--min-concurrency", "8", "--max-concurrency", "4"]).limits().is_err());
+
+        // Sizes and counts that would flood S3 with requests are refused up front
+        assert!(chunk_args(&["--chunk-size", "1"]).planner().is_err());
+        assert!(chunk_args(&["--chunk-size", "64K"]).planner().is_ok());
+        assert!(chunk_args(&["--min-chunk-size", "1"]).limits().is_err());
+        assert!(chunk_args(&["--target-chunks", "1000000000"]).limits().is_err());
+        assert!(chunk_args(&["--max-concurrency", "100000"]).limits().is_err());
+        assert!(Command::try_parse_from(["s3mem-run", "--target-chunks", "0"]).is_err());
+    }
+}
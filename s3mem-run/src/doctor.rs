@@ -0,0 +1,496 @@
+// The doctor subcommand: check the environment s3mem-run depends on
+// Each check is independent and reports ok, warn or fail with a one-line detail, so
+// one run shows everything that would get in the way of a download or an exec: who the
+// credentials belong to, whether the bucket and key can be reached and read, whether the
+// object fits in memory, and whether the program can start.
+
+use anyhow::{bail, Context, Result};          // Error handling with context
+use aws_config::{BehaviorVersion, SdkConfig};  // AWS SDK configuration
+use aws_sdk_s3::config::ProvideCredentials;   // Resolving credentials
+use aws_sdk_s3::Client;                       // AWS S3 client
+use clap::ValueEnum;                          // Command-line enum parsing
+use s3mem_run::inspect::{format_bytes, object_info};  // Object metadata
+use s3mem_run::program;                       // Pre-flight checks of the program
+use serde_json::{json, Value};                // The JSON report
+use std::ffi::CString;                        // Paths for libc
+use std::os::unix::ffi::OsStrExt;             // Path bytes
+use std::path::{Path, PathBuf};               // Program and temporary paths
+use std::time::{Instant, SystemTime};         // Request timings and credential expiry
+
+pub const CHECKS_FAILED_EXIT_CODE: i32 = 3;   // Exit code when a check failed, next to the usage code
+const RANGE_PROBE: u64 = 1024 * 1024;         // Bytes of the test GET
+const MEMORY_HEADROOM: u64 = 5;               // Warn when the object takes more than 4/5 of the limit
+const TMP_LOW: u64 = 64 * 1024 * 1024;        // Free bytes below which /tmp is reported as low
+const CGROUP_UNLIMITED: u64 = 1 << 60;        // cgroup v1 reports no limit as a huge number
+
+// Output format of the results
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DoctorFormat {
+    Human,
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DoctorArgs {
+    /// S3 bucket to check access to (defaults to S3_BUCKET env var)
+    #[arg(long, env = "S3_BUCKET")]
+    pub bucket: Option<String>,
+
+    /// S3 key to check access to (defaults to S3_KEY env var)
+    #[arg(long, env = "S3_KEY")]
+    pub key: Option<String>,
+
+    /// Program to check the way exec would before starting it
+    #[arg(long)]
+    pub program: Option<String>,
+
+    /// Output format: a human-readable table or json
+    #[arg(long, value_enum, default_value = "human")]
+    pub format: DoctorFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Warn,                                 // Works, but may not do what was meant
+    Fail,                                 // A download or exec would fail
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Check { name, status, detail: detail.into() }
+    }
+
+    // Ok with the value's description, or failed with the error's
+    fn from_result(name: &'static str, result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => Check::new(name, Status::Ok, detail),
+            Err(err) => Check::new(name, Status::Fail, format!("{:#}", err).replace('\n', " ")),
+        }
+    }
+
+    // Not run, because something it needs is missing
+    fn skipped(name: &'static str, reason: &str) -> Self {
+        Check::new(name, Status::Warn, format!("skipped, {}", reason))
+    }
+}
+
+// Run every check and print the results; false when any of them failed
+pub async fn run_doctor(args: &DoctorArgs) -> Result<bool> {
+    let config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+    let mut checks = Vec::new();
+
+    let credentials = Check::from_result("credentials", check_credentials(&config).await);
+    let identity = match credentials.status {
+        Status::Ok => Check::from_result("identity", check_identity(&config).await),
+        _ => Check::skipped("identity", "no credentials"),
+    };
+    checks.extend([credentials, identity]);
+    let region = config.region().map(|region| region.to_string());
+    checks.push(match &region {
+        Some(region) => Check::new("region", Status::Ok, region.clone()),
+        None => Check::new("region", Status::Fail, "no region configured, set AWS_REGION"),
+    });
+
+    let client = Client::new(&config);
+    let (s3_checks, size) = check_s3(&client, region.as_deref(), args.bucket.as_deref(), args.key.as_deref()).await;
+    checks.extend(s3_checks);
+    checks.push(check_memory(memory_limit(), size));
+    checks.push(Check::from_result("memfd_create", check_memfd()));
+    checks.push(check_tmp(&std::env::temp_dir()));
+    if let Some(name) = &args.program {
+        checks.push(check_program(name).unwrap_or_else(|err| Check::from_result("program", Err(err))));
+    }
+
+    match args.format {
+        DoctorFormat::Human => print!("{}", table(&checks)),
+        DoctorFormat::Json => println!("{:#}", json_report(&checks)),
+    }
+    Ok(checks.iter().all(|check| check.status != Status::Fail))
+}
+
+async fn check_credentials(config: &SdkConfig) -> Result<String> {
+    let provider = config.credentials_provider().context("no credentials provider configured")?;
+    let credentials = provider.provide_credentials().await.context("no credentials found")?;
+    let expiry = credentials
+        .expiry()
+        .and_then(|expiry| expiry.duration_since(SystemTime::now()).ok())
+        .map(|left| format!(", expire in {} min", left.as_secs() / 60))
+        .unwrap_or_default();
+    Ok(format!(
+        "access key {}…{}{}",
+        credentials.access_key_id().chars().take(4).collect::<String>(),
+        if credentials.session_token().is_some() { " (temporary)" } else { "" },
+        expiry
+    ))
+}
+
+// The account and principal the credentials map to
+async fn check_identity(config: &SdkConfig) -> Result<String> {
+    let identity = aws_sdk_sts::Client::new(config)
+        .get_caller_identity()
+        .send()
+        .await
+        .context("Failed to get the caller identity from STS")?;
+    Ok(format!(
+        "{} (account {})",
+        identity.arn().unwrap_or("unknown principal"),
+        identity.account().unwrap_or("unknown")
+    ))
+}
+
+// The bucket, a HEAD and a ranged GET of the key, and the object's size when the HEAD worked
+async fn check_s3(client: &Client, region: Option<&str>, bucket: Option<&str>, key: Option<&str>) -> (Vec<Check>, Option<u64>) {
+    let Some(bucket) = bucket else {
+        let checks = ["bucket", "head", "range_get"].map(|name| Check::skipped(name, "--bucket is not set"));
+        return (checks.into(), None);
+    };
+    let mut checks = vec![check_bucket(client, bucket, region).await];
+    let Some(key) = key else {
+        checks.extend(["head", "range_get"].map(|name| Check::skipped(name, "--key is not set")));
+        return (checks, None);
+    };
+
+    let size = match object_info(client, bucket, key).await {
+        Ok(object) => {
+            checks.push(Check::new(
+                "head",
+                Status::Ok,
+                format!("s3://{}/{} is {}", bucket, key, format_bytes(object.size)),
+            ));
+            Some(object.size)
+        }
+        Err(err) => {
+            checks.push(Check::from_result("head", Err(err)));
+            None
+        }
+    };
+    checks.push(match size {
+        Some(0) => Check::new("range_get", Status::Ok, "skipped, the object is empty"),
+        Some(size) => Check::from_result("range_get", check_range_get(client, bucket, key, size).await),
+        None => Check::skipped("range_get", "the HEAD failed"),
+    });
+    (checks, size)
+}
+
+// Whether the bucket answers, and whether it is in the configured region
+// HEAD on a bucket needs s3:ListBucket, which downloads don't, so a denial only warns;
+// S3 names the bucket's region even then.
+async fn check_bucket(client: &Client, bucket: &str, region: Option<&str>) -> Check {
+    let result = client.head_bucket().bucket(bucket).send().await;
+    let (status, actual) = match &result {
+        Ok(output) => (Some(200), output.bucket_region().map(str::to_string)),
+        Err(err) => (
+            err.raw_response().map(|response| response.status().as_u16()),
+            err.raw_response()
+                .and_then(|response| response.headers().get("x-amz-bucket-region"))
+                .map(str::to_string),
+        ),
+    };
+    let location = actual.as_ref().map(|actual| format!(" in {}", actual)).unwrap_or_default();
+    match (status, actual.as_deref(), region) {
+        (_, Some(actual), Some(region)) if actual != region => Check::new(
+            "bucket",
+            Status::Fail,
+            format!("s3://{} is in {}, not in {}; set AWS_REGION={}", bucket, actual, region, actual),
+        ),
+        (Some(200), _, _) => Check::new("bucket", Status::Ok, format!("s3://{}{} is reachable", bucket, location)),
+        (Some(403), _, _) => Check::new(
+            "bucket",
+            Status::Warn,
+            format!("s3://{}{} is reachable, but not listable (s3:ListBucket), which downloads don't need", bucket, location),
+        ),
+        (Some(404), _, _) => Check::new("bucket", Status::Fail, format!("s3://{} doesn't exist", bucket)),
+        _ => Check::from_result("bucket", result.map(|_| String::new()).context("Failed to reach the bucket")),
+    }
+}
+
+// A GET of the first chunk, as the loader's first request would be
+async fn check_range_get(client: &Client, bucket: &str, key: &str, size: u64) -> Result<String> {
+    let length = size.min(RANGE_PROBE);
+    let started = Instant::now();
+    let object = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .range(format!("bytes=0-{}", length - 1))
+        .send()
+        .await
+        .context("Failed to get a range of the object")?;
+    let first_byte = started.elapsed();
+    let body = object.body.collect().await.context("Failed to read the range")?.into_bytes();
+    if body.len() as u64 != length {
+        bail!("asked for {} bytes, got {}", length, body.len());
+    }
+    Ok(format!(
+        "{} in {} ms, first byte after {} ms",
+        format_bytes(length),
+        started.elapsed().as_millis(),
+        first_byte.as_millis()
+    ))
+}
+
+// The memory available to this process and where the figure comes from
+fn memory_limit() -> Option<(u64, String)> {
+    if let Some(megabytes) = std::env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE").ok().and_then(|size| size.parse::<u64>().ok()) {
+        return Some((megabytes * 1024 * 1024, "Lambda memory size".to_string()));
+    }
+    if let Some(limit) = cgroup_memory_limit() {
+        return Some(limit);
+    }
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let total = meminfo.lines().find_map(|line| line.strip_prefix("MemTotal:"))?;
+    let kilobytes: u64 = total.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some((kilobytes * 1024, "total memory".to_string()))
+}
+
+// The memory limit of this process's cgroup, v2 or v1, if it has one
+// Memory files are charged to the cgroup like any other memory of the process.
+fn cgroup_memory_limit() -> Option<(u64, String)> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let mut candidates = Vec::new();
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let path = path.trim_end_matches('/');
+        if controllers.is_empty() {
+            candidates.push(format!("/sys/fs/cgroup{}/memory.max", path));
+            candidates.push("/sys/fs/cgroup/memory.max".to_string());
+        } else if controllers.split(',').any(|controller| controller == "memory") {
+            candidates.push(format!("/sys/fs/cgroup/memory{}/memory.limit_in_bytes", path));
+            candidates.push("/sys/fs/cgroup/memory/memory.limit_in_bytes".to_string());
+        }
+    }
+    candidates.into_iter().find_map(|file| {
+        let limit = parse_cgroup_limit(&std::fs::read_to_string(&file).ok()?)?;
+        Some((limit, format!("cgroup limit in {}", file)))
+    })
+}
+
+// A limit from memory.max or memory.limit_in_bytes, or None when there is none
+fn parse_cgroup_limit(contents: &str) -> Option<u64> {
+    contents.trim().parse::<u64>().ok().filter(|&limit| limit < CGROUP_UNLIMITED)
+}
+
+// Whether the object fits in memory with room left for the program
+fn check_memory(limit: Option<(u64, String)>, size: Option<u64>) -> Check {
+    let Some((limit, source)) = limit else {
+        return Check::new("memory", Status::Warn, "the memory limit is unknown");
+    };
+    let Some(size) = size else {
+        return Check::new("memory", Status::Ok, format!("{} ({})", format_bytes(limit), source));
+    };
+    if size > limit {
+        return Check::new(
+            "memory",
+            Status::Fail,
+            format!("the object's {} doesn't fit in {} ({})", format_bytes(size), format_bytes(limit), source),
+        );
+    }
+    let detail = format!(
+        "{} of {} ({}), {} left for the program",
+        format_bytes(size),
+        format_bytes(limit),
+        source,
+        format_bytes(limit - size)
+    );
+    match size > limit / MEMORY_HEADROOM * (MEMORY_HEADROOM - 1) {
+        true => Check::new("memory", Status::Warn, detail),
+        false => Check::new("memory", Status::Ok, detail),
+    }
+}
+
+// Memory files are where every download goes
+fn check_memfd() -> Result<String> {
+    let fd = unsafe { libc::memfd_create(c"s3mem-run-doctor".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("memfd_create failed");
+    }
+    unsafe { libc::close(fd) };
+    Ok("memory files can be created".to_string())
+}
+
+// Free space in the temporary directory, which programs such as llama-server write to
+fn check_tmp(dir: &Path) -> Check {
+    let space = tmp_space(dir).with_context(|| format!("{} is not usable", dir.display()));
+    match space {
+        Ok((free, total)) => {
+            let detail = format!("{} free of {} in {}", format_bytes(free), format_bytes(total), dir.display());
+            Check::new("tmp", if free < TMP_LOW { Status::Warn } else { Status::Ok }, detail)
+        }
+        Err(err) => Check::from_result("tmp", Err(err)),
+    }
+}
+
+// Free and total bytes of a writable directory's filesystem
+fn tmp_space(dir: &Path) -> Result<(u64, u64)> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("statvfs failed");
+    }
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("not writable");
+    }
+    let block = stats.f_frsize as u64;
+    Ok((stats.f_bavail as u64 * block, stats.f_blocks as u64 * block))
+}
+
+// The checks exec makes; libraries that may be missing only warn, as they do there
+fn check_program(name: &str) -> Result<Check> {
+    let path = program::resolve(name)?;
+    let origin = path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", path.display()))?
+        .parent()
+        .map(PathBuf::from);
+    let report = program::check(&path, origin.as_deref())?;
+    report.ensure_runnable()?;
+    let found = report.libraries.iter().filter(|library| library.path.is_some()).count();
+    let detail = format!("{}, {} shared libraries found", path.display(), found);
+    Ok(match report.warnings.is_empty() {
+        true => Check::new("program", Status::Ok, detail),
+        false => Check::new("program", Status::Warn, format!("{}; {}", detail, report.warnings.join("; "))),
+    })
+}
+
+// One aligned row per check, under a header
+fn table(checks: &[Check]) -> String {
+    let width = checks.iter().map(|check| check.name.len()).max().unwrap_or(0).max("CHECK".len());
+    let mut table = format!("{:width$}  {:6}  {}\n", "CHECK", "STATUS", "DETAIL", width = width);
+    for check in checks {
+        table.push_str(&format!(
+            "{:width$}  {:6}  {}\n",
+            check.name,
+            check.status.as_str(),
+            check.detail,
+            width = width
+        ));
+    }
+    table
+}
+
+fn json_report(checks: &[Check]) -> Value {
+    json!({
+        "ok": checks.iter().all(|check| check.status != Status::Fail),
+        "checks": checks
+            .iter()
+            .map(|check| json!({"name": check.name, "status": check.status.as_str(), "detail": check.detail}))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s3mem_run::local_s3::LocalS3;
+
+    #[test]
+    fn test_checks() {
+        assert_eq!(check_memfd().unwrap(), "memory files can be created");
+        let check = check_program("/nonexistent/llama-server").unwrap_or_else(|err| Check::from_result("program", Err(err)));
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check_program("sh").unwrap().status, Status::Ok);
+        assert!(!check.detail.contains('\n'));
+
+        assert_ne!(check_tmp(&std::env::temp_dir()).status, Status::Fail);
+        let check = check_tmp(Path::new("/nonexistent"));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.starts_with("/nonexistent is not usable"), "{}", check.detail);
+    }
+
+    #[test]
+    fn test_memory() {
+        assert_eq!(parse_cgroup_limit("max\n"), None);
+        assert_eq!(parse_cgroup_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_cgroup_limit("2147483648\n"), Some(2 << 30));
+
+        let limit = || Some((10 << 30, "cgroup limit".to_string()));
+        assert_eq!(check_memory(limit(), None).detail, "10.00 GiB (cgroup limit)");
+        let check = check_memory(limit(), Some(4 << 30));
+        assert_eq!(check.status, Status::Ok);
+        assert_eq!(check.detail, "4.00 GiB of 10.00 GiB (cgroup limit), 6.00 GiB left for the program");
+        assert_eq!(check_memory(limit(), Some(9 << 30)).status, Status::Warn);
+        assert_eq!(check_memory(limit(), Some(11 << 30)).status, Status::Fail);
+        assert_eq!(check_memory(None, Some(1)).status, Status::Warn);
+    }
+
+    #[tokio::test]
+    async fn test_s3_checks() {
+        let data = vec![1u8; 3 * 1024 * 1024];
+        let s3 = LocalS3::start(vec![("models", "model.gguf", data)]).await.unwrap();
+        let client = s3.client().await;
+        let statuses = |checks: &[Check]| checks.iter().map(|check| (check.name, check.status)).collect::<Vec<_>>();
+
+        let (checks, size) = check_s3(&client, Some("us-east-1"), Some("models"), Some("model.gguf")).await;
+        assert_eq!(size, Some(3 * 1024 * 1024));
+        assert_eq!(
+            statuses(&checks),
+            [("bucket", Status::Ok), ("head", Status::Ok), ("range_get", Status::Ok)]
+        );
+        assert_eq!(checks[0].detail, "s3://models in us-east-1 is reachable");
+        assert!(checks[2].detail.starts_with("1.00 MiB in "), "{}", checks[2].detail);
+
+        // A missing key fails the HEAD, and the GET isn't tried
+        let (checks, size) = check_s3(&client, Some("us-east-1"), Some("models"), Some("missing")).await;
+        assert_eq!(size, None);
+        assert_eq!(
+            statuses(&checks),
+            [("bucket", Status::Ok), ("head", Status::Fail), ("range_get", Status::Warn)]
+        );
+
+        let (checks, _) = check_s3(&client, Some("us-east-1"), Some("other"), None).await;
+        assert_eq!(statuses(&checks)[0], ("bucket", Status::Fail));
+        assert_eq!(checks[0].detail, "s3://other doesn't exist");
+
+        // The bucket's region must be the one requests are signed for
+        let check = check_bucket(&client, "models", Some("eu-west-1")).await;
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.detail, "s3://models is in us-east-1, not in eu-west-1; set AWS_REGION=us-east-1");
+
+        let (checks, _) = check_s3(&client, None, None, None).await;
+        assert!(checks.iter().all(|check| check.detail == "skipped, --bucket is not set"));
+    }
+
+    #[test]
+    fn test_table() {
+        let checks = vec![
+            Check::new("memfd_create", Status::Ok, "memory files can be created"),
+            Check::new("object", Status::Warn, "skipped"),
+        ];
+        assert_eq!(
+            table(&checks),
+            "CHECK         STATUS  DETAIL\n\
+             memfd_create  ok      memory files can be created\n\
+             object        warn    skipped\n"
+        );
+    }
+
+    #[test]
+    fn test_json_report() {
+        let checks = vec![
+            Check::new("memfd_create", Status::Ok, "memory files can be created"),
+            Check::new("head", Status::Fail, "access denied"),
+        ];
+        let report = json_report(&checks);
+        assert_eq!(report["ok"], false);
+        assert_eq!(report["checks"][1], json!({"name": "head", "status": "fail", "detail": "access denied"}));
+    }
+}
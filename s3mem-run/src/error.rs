@@ -0,0 +1,308 @@
+// Typed errors and exit codes
+// Errors are still anyhow errors with their context chain, but failures that wrappers
+// need to tell apart carry an Error with its ErrorKind, either as the root error or as
+// a context on the way up. Errors from S3 and the OS are classified by their own types,
+// so they don't have to be wrapped. Each kind has an exit code that doesn't change
+// between releases, and the same name is used as the Lambda init error type.
+
+use aws_sdk_s3::config::http::HttpResponse;   // Raw responses of S3 errors
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};  // S3 error codes
+use aws_sdk_s3::operation::{get_object::GetObjectError, head_object::HeadObjectError};
+use serde_json::json;                         // JSON errors
+use std::fmt;                                 // Display of errors
+
+// What went wrong, grouped by category
+// The exit codes are part of the interface: never reuse or renumber them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // Config
+    MissingConfiguration,                 // No bucket or key was given
+    InvalidConfiguration,                 // Options or placeholders that can't work
+    // Auth
+    AccessDenied,                         // S3 answered 403 or rejected the credentials
+    // Not found
+    NotFound,                             // The bucket or key doesn't exist
+    // Throttling and other S3 failures
+    Throttled,                            // S3 answered SlowDown or 503
+    S3Error,                              // Any other S3 error response
+    NetworkError,                         // S3 couldn't be reached or timed out
+    // Integrity
+    ChecksumMismatch,                     // The program didn't match --program-sha256
+    InvalidModel,                         // The model failed GGUF validation
+    IncompleteDownload,                   // Responses didn't match the requested ranges
+    // Resource
+    OutOfMemory,                          // The memory files didn't fit
+    TooManyOpenFiles,                     // The descriptor limit was reached
+    // Exec
+    ProgramNotRunnable,                   // The program is missing or fails the pre-flight checks
+    ExecFailed,                           // exec or spawn of the program failed
+    // Anything else
+    Internal,
+}
+
+impl ErrorKind {
+    // The process exit code; 2 is left to command line usage errors and 3 to failed doctor checks
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::MissingConfiguration => 10,
+            ErrorKind::InvalidConfiguration => 11,
+            ErrorKind::AccessDenied => 20,
+            ErrorKind::NotFound => 30,
+            ErrorKind::Throttled => 40,
+            ErrorKind::S3Error => 41,
+            ErrorKind::NetworkError => 42,
+            ErrorKind::ChecksumMismatch => 50,
+            ErrorKind::InvalidModel => 51,
+            ErrorKind::IncompleteDownload => 52,
+            ErrorKind::OutOfMemory => 60,
+            ErrorKind::TooManyOpenFiles => 61,
+            ErrorKind::ProgramNotRunnable => 70,
+            ErrorKind::ExecFailed => 71,
+        }
+    }
+
+    // Stable name, also used as the Lambda error type after "S3MemRun."
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::MissingConfiguration => "MissingConfiguration",
+            ErrorKind::InvalidConfiguration => "InvalidConfiguration",
+            ErrorKind::AccessDenied => "AccessDenied",
+            ErrorKind::NotFound => "NotFound",
+            ErrorKind::Throttled => "Throttled",
+            ErrorKind::S3Error => "S3Error",
+            ErrorKind::NetworkError => "NetworkError",
+            ErrorKind::ChecksumMismatch => "ChecksumMismatch",
+            ErrorKind::InvalidModel => "InvalidModel",
+            ErrorKind::IncompleteDownload => "IncompleteDownload",
+            ErrorKind::OutOfMemory => "OutOfMemory",
+            ErrorKind::TooManyOpenFiles => "TooManyOpenFiles",
+            ErrorKind::ProgramNotRunnable => "ProgramNotRunnable",
+            ErrorKind::ExecFailed => "ExecFailed",
+            ErrorKind::Internal => "Internal",
+        }
+    }
+
+    pub fn category(self) -> &'static str {
+        match self {
+            ErrorKind::MissingConfiguration | ErrorKind::InvalidConfiguration => "config",
+            ErrorKind::AccessDenied => "auth",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Throttled | ErrorKind::S3Error | ErrorKind::NetworkError => "s3",
+            ErrorKind::ChecksumMismatch | ErrorKind::InvalidModel | ErrorKind::IncompleteDownload => "integrity",
+            ErrorKind::OutOfMemory | ErrorKind::TooManyOpenFiles => "resource",
+            ErrorKind::ProgramNotRunnable | ErrorKind::ExecFailed => "exec",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    // The kind of an error: the outermost typed Error in its chain, otherwise the first
+    // S3 or OS error that says more than "something failed"
+    pub fn of(err: &anyhow::Error) -> ErrorKind {
+        if let Some(typed) = err.downcast_ref::<Error>() {
+            return typed.kind;
+        }
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<SdkError<HeadObjectError, HttpResponse>>() {
+                return s3_error_kind(err);
+            }
+            if let Some(err) = cause.downcast_ref::<SdkError<GetObjectError, HttpResponse>>() {
+                return s3_error_kind(err);
+            }
+            if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                if let Some(kind) = io_error_kind(err) {
+                    return kind;
+                }
+            }
+        }
+        ErrorKind::Internal
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn s3_error_kind<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> ErrorKind {
+    let status = err.raw_response().map(|response| response.status().as_u16());
+    match (err.code(), status) {
+        (Some("AccessDenied" | "InvalidAccessKeyId" | "SignatureDoesNotMatch" | "ExpiredToken"), _) | (_, Some(403)) => {
+            ErrorKind::AccessDenied
+        }
+        (Some("NoSuchKey" | "NoSuchBucket" | "NotFound"), _) | (_, Some(404)) => ErrorKind::NotFound,
+        (Some("SlowDown"), _) | (_, Some(503)) => ErrorKind::Throttled,
+        (_, Some(_)) => ErrorKind::S3Error,
+        (_, None) => ErrorKind::NetworkError,
+    }
+}
+
+fn io_error_kind(err: &std::io::Error) -> Option<ErrorKind> {
+    if err.kind() == std::io::ErrorKind::OutOfMemory {
+        return Some(ErrorKind::OutOfMemory);
+    }
+    match err.raw_os_error()? {
+        // A memfd larger than the memory left fails with ENOSPC or ENOMEM
+        libc::ENOMEM | libc::ENOSPC | libc::EFBIG => Some(ErrorKind::OutOfMemory),
+        libc::EMFILE | libc::ENFILE => Some(ErrorKind::TooManyOpenFiles),
+        _ => None,
+    }
+}
+
+// An error of a known kind, raised with fail! or attached as a context
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Error { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Return early with a typed error, like anyhow::bail!
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::error::Error::new($crate::error::ErrorKind::$kind, format!($($arg)+)).into())
+    };
+}
+pub(crate) use fail;
+
+// How errors are written to stderr before exiting
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    Text,                                 // "Error: ..." with the "Caused by:" chain
+    Json,                                 // One JSON object per error
+}
+
+// The machine-readable form of an error
+pub fn to_json(err: &anyhow::Error) -> serde_json::Value {
+    let kind = ErrorKind::of(err);
+    json!({
+        "error": {
+            "type": kind.as_str(),
+            "category": kind.category(),
+            "exit_code": kind.exit_code(),
+            "message": format!("{:#}", err),
+            "chain": err.chain().map(|cause| cause.to_string()).collect::<Vec<_>>(),
+        }
+    })
+}
+
+// Write an error to stderr
+pub fn report(err: &anyhow::Error, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {:?}", err),
+        ErrorFormat::Json => eprintln!("{}", to_json(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use aws_sdk_s3::error::ErrorMetadata;
+    use aws_sdk_s3::primitives::SdkBody;
+
+    fn head_error(code: Option<&str>, status: u16) -> anyhow::Error {
+        let mut meta = ErrorMetadata::builder();
+        if let Some(code) = code {
+            meta = meta.code(code);
+        }
+        let response = HttpResponse::new(status.try_into().unwrap(), SdkBody::empty());
+        let err = SdkError::service_error(HeadObjectError::generic(meta.build()), response);
+        anyhow::Error::new(err).context("Failed to get object metadata from S3")
+    }
+
+    const ALL: [ErrorKind; 15] = [
+        ErrorKind::MissingConfiguration,
+        ErrorKind::InvalidConfiguration,
+        ErrorKind::AccessDenied,
+        ErrorKind::NotFound,
+        ErrorKind::Throttled,
+        ErrorKind::S3Error,
+        ErrorKind::NetworkError,
+        ErrorKind::ChecksumMismatch,
+        ErrorKind::InvalidModel,
+        ErrorKind::IncompleteDownload,
+        ErrorKind::OutOfMemory,
+        ErrorKind::TooManyOpenFiles,
+        ErrorKind::ProgramNotRunnable,
+        ErrorKind::ExecFailed,
+        ErrorKind::Internal,
+    ];
+
+    fn checksum(expected: &str) -> anyhow::Result<()> {
+        fail!(ChecksumMismatch, "Checksum mismatch: expected SHA-256 {}", expected)
+    }
+
+    #[test]
+    fn test_exit_codes_are_unique() {
+        let mut codes: Vec<i32> = ALL.iter().map(|kind| kind.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), ALL.len());
+        assert!(!codes.contains(&0) && !codes.contains(&2));
+        assert!(codes.iter().all(|&code| code < 126));
+    }
+
+    #[test]
+    fn test_s3_error_kinds() {
+        assert_eq!(ErrorKind::of(&head_error(None, 403)), ErrorKind::AccessDenied);
+        assert_eq!(ErrorKind::of(&head_error(Some("AccessDenied"), 400)), ErrorKind::AccessDenied);
+        assert_eq!(ErrorKind::of(&head_error(None, 404)), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::of(&head_error(Some("NoSuchBucket"), 404)), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::of(&head_error(Some("SlowDown"), 503)), ErrorKind::Throttled);
+        assert_eq!(ErrorKind::of(&head_error(Some("InternalError"), 500)), ErrorKind::S3Error);
+
+        let err = SdkError::<GetObjectError, HttpResponse>::timeout_error("timed out");
+        let err = anyhow::Error::new(err).context("Failed to get object from S3");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::NetworkError);
+    }
+
+    #[test]
+    fn test_typed_and_os_error_kinds() {
+        // Typed errors are found as the root error and as contexts at any depth
+        let err = checksum("00").context("Program check failed").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ChecksumMismatch);
+        let err = anyhow::anyhow!("bad magic")
+            .context(Error::new(ErrorKind::InvalidModel, "s3://models/a.gguf is not a valid GGUF file"))
+            .context("Download failed");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidModel);
+
+        // The outermost typed error wins over what it wraps
+        let err = head_error(None, 403).context(Error::new(ErrorKind::ProgramNotRunnable, "no program"));
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ProgramNotRunnable);
+
+        let err = anyhow::Error::new(std::io::Error::from_raw_os_error(libc::ENOMEM)).context("Failed to write to memfd");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::OutOfMemory);
+        let err = anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EMFILE)).context("Failed to create memfd");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::TooManyOpenFiles);
+        let err = anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EINVAL)).context("Failed to create memfd");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn test_json_error() {
+        let err = head_error(None, 403).context("Failed to download s3://models/a.gguf");
+        let json = to_json(&err);
+        assert_eq!(json["error"]["type"], "AccessDenied");
+        assert_eq!(json["error"]["category"], "auth");
+        assert_eq!(json["error"]["exit_code"], 20);
+        let chain = json["error"]["chain"].as_array().unwrap();
+        assert_eq!(chain[0], "Failed to download s3://models/a.gguf");
+        assert_eq!(chain[1], "Failed to get object metadata from S3");
+        assert!(json["error"]["message"].as_str().unwrap().starts_with("Failed to download s3://models/a.gguf: "));
+    }
+}
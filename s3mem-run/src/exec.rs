@@ -0,0 +1,486 @@
+// Handing memory files to a program
+// Downloads the model (and the program itself when it comes from S3), expands the memory
+// file placeholders in the program's arguments and builds the command to exec or supervise.
+
+use crate::error::{self, ErrorKind};          // Typed errors
+use crate::hotswap;                           // Model sources for hot swap mode
+use crate::loader::{object_version, verify_gguf_file, Loader, S3Object};  // Parallel S3 downloads
+use crate::memfile::MemFile;                  // Memory-backed files
+use crate::planner::{ChunkLimits, ChunkPlanner};  // Chunk planning
+use crate::program;                           // Checks of a downloaded program
+use crate::report::REPORT_PLACEHOLDER;        // The download report in program arguments
+use crate::progress::DownloadProgress;        // Shared download progress
+use crate::runtime_dir::RuntimeDir;           // Named symlinks to memory files
+use crate::{shards, template};                // Split models and argument placeholders
+use anyhow::{Context, Result};                // Error handling with context
+use aws_sdk_s3::Client;                       // AWS S3 client
+use std::os::unix::process::CommandExt;       // Unix-specific process extensions
+use std::path::PathBuf;                       // Path manipulation
+use std::process::Command;                    // Process execution
+use std::sync::Arc;                           // Thread-safe reference counting
+use tracing::{debug, info, instrument, warn}; // Structured logging
+
+// Move memory files to fixed descriptor numbers and mark every other descriptor close-on-exec
+// `moves` holds (current fd, target fd) pairs. This runs in the child between fork and exec,
+// so it only touches the child's descriptors, but it must be async-signal-safe: hot swap
+// and supervisor restarts spawn while other threads of the parent are running.
+pub fn place_fds_for_exec(moves: &[(i32, i32)]) -> std::io::Result<()> {
+    // The hook runs between fork and exec, where another thread may have held the malloc
+    // lock at fork, so it allocates nothing; each move is staged at a descriptor derived
+    // from its position instead of a list built here
+    let first_free = moves.iter().map(|&(source, target)| source.max(target)).max().unwrap_or(2) + 1;
+
+    // Copy every source above all sources and targets first, so a target that happens to be
+    // another source isn't overwritten before it has been moved
+    for (index, &(source, _)) in moves.iter().enumerate() {
+        if unsafe { libc::dup3(source, first_free + index as i32, libc::O_CLOEXEC) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    // dup2 leaves the target without close-on-exec, so it is inherited by the program
+    for (index, &(_, target)) in moves.iter().enumerate() {
+        if unsafe { libc::dup2(first_free + index as i32, target) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    // Everything except stdio and the targets is closed on exec, including the SDK's sockets
+    // and the staged copies, one gap between targets at a time
+    let mut next = 3;
+    loop {
+        let kept = moves.iter().map(|&(_, target)| target).filter(|&target| target >= next).min();
+        if kept != Some(next) {
+            set_cloexec_range(next as u32, kept.map_or(u32::MAX, |target| (target - 1) as u32));
+        }
+        match kept {
+            Some(target) => next = target + 1,
+            None => return Ok(()),
+        }
+    }
+}
+
+// Mark descriptors first..=last close-on-exec
+fn set_cloexec_range(first: u32, last: u32) {
+    if unsafe { libc::syscall(libc::SYS_close_range, first, last, libc::CLOSE_RANGE_CLOEXEC) } == 0 {
+        return;
+    }
+    // CLOSE_RANGE_CLOEXEC needs Linux 5.11, fall back to marking descriptors one by one
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    let max_fd = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+        limit.rlim_cur.min(1 << 20) as u32
+    } else {
+        4096
+    };
+    for fd in first..=last.min(max_fd.saturating_sub(1)) {
+        unsafe { libc::fcntl(fd as i32, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+}
+
+// Options controlling how downloaded files are handed to the program
+#[derive(Debug, Clone)]
+pub struct ExecOptions {
+    pub memfd_placeholder: String,        // Placeholder replaced with the memory file path
+    pub verify_gguf: bool,                // Validate the files as GGUF models before exec
+    pub fd: Option<i32>,                  // Fixed descriptor number for the first memory file
+    pub program_object: Option<S3Object>, // Download the program itself from S3 and run it from memory
+    pub program_path: Option<PathBuf>,    // Where a local program was found on PATH
+    pub supervise: bool,                  // The program runs as a child rather than by exec
+    pub capture_output: bool,             // The program's stdout and stderr are piped to s3mem-run
+    pub progress: Arc<DownloadProgress>,  // Bytes downloaded so far, shared with the progress endpoint
+    pub planner: Arc<dyn ChunkPlanner>,   // How the files are split into ranged requests
+    pub limits: ChunkLimits,              // Bounds of the size-scaled chunk sizes and concurrency
+}
+
+// A program ready to start, with the memory files and symlinks its command refers to
+pub struct PreparedProgram {
+    pub command: Command,                 // The program and its expanded arguments
+    pub memfiles: Vec<MemFile>,           // Memory files, including a program downloaded from S3
+    pub(crate) runtime_dir: Option<RuntimeDir>,  // Named symlinks to the memory files
+}
+
+impl PreparedProgram {
+    // Hand the memory files and symlinks over to the program for good
+    // The memory files stay open for the program to inherit, and the symlinks must
+    // outlive this process when it is replaced by the program, so a watcher removes
+    // them once this process and the program are gone
+    pub fn into_command(self) -> Result<Command> {
+        std::mem::forget(self.memfiles);
+        if let Some(dir) = self.runtime_dir {
+            dir.remove_on_exit()?;
+        }
+        Ok(self.command)
+    }
+}
+
+#[instrument(skip(client))]
+// Create a memory file descriptor, download the file, and prepare to execute the specified program
+// This is the main function that ties everything together. The returned program is executed
+// by main once the async runtime has shut down, or run as a child in hot swap mode.
+pub async fn create_memfd_and_exec(
+    bucket: &str,
+    key: &str,
+    client: &Client,
+    program: &str,
+    args: &[String],
+    options: &ExecOptions,
+) -> Result<PreparedProgram> {
+    info!(bucket, key, program, "Starting download and execution process");
+    
+    // Split models are downloaded shard by shard, anything else as a single file
+    let keys = shards::find_shard_keys(client, bucket, key).await?;
+    if keys.len() > 1 {
+        info!(shards = keys.len(), first_shard = %keys[0], "Split GGUF model detected");
+    }
+
+    // Download the files from S3 into memory, along with the program when it comes from S3
+    // A downloaded program is checked against its expected SHA-256 by the loader
+    let mut loader = Loader::new(client.clone())
+        .progress(options.progress.clone())
+        .planner(options.planner.clone())
+        .limits(options.limits);
+    for key in &keys {
+        loader = loader.source(bucket, key);
+    }
+    if let Some(program_object) = &options.program_object {
+        loader = loader.object(program_object.clone());
+    }
+    let mut memfiles = loader.load_all().await?;
+    let program_memfile = match &options.program_object {
+        Some(_) => memfiles.pop(),
+        None => None,
+    };
+
+    // The program couldn't be checked before the download, so check it now
+    // A program in a memory file has no directory, so $ORIGIN run paths don't apply
+    if let (Some(program_memfile), Some(program_object)) = (&program_memfile, &options.program_object) {
+        let report = program::check(&program_memfile.path(), None)?;
+        report.ensure_runnable().with_context(|| {
+            error::Error::new(
+                ErrorKind::ProgramNotRunnable,
+                format!("s3://{}/{} is not a runnable program", program_object.bucket, program_object.key),
+            )
+        })?;
+        for warning in &report.warnings {
+            warn!(key = %program_object.key, "{}", warning);
+        }
+    }
+
+    // Optionally make sure the files are well-formed GGUF models before handing them over
+    // A bad key or corrupted download is much easier to diagnose here than after exec
+    // The first shard's header is also needed when arguments reference GGUF metadata
+    let mut gguf = None;
+    if options.verify_gguf || template::has_gguf_placeholders(args) {
+        let checked = if options.verify_gguf { memfiles.len() } else { 1 };
+        for (index, (memfile, shard_key)) in memfiles.iter().zip(&keys).enumerate().take(checked) {
+            let header = verify_gguf_file(memfile)
+                .and_then(|header| {
+                    shards::check_split_metadata(&header, shard_key, index, keys.len())?;
+                    Ok(header)
+                })
+                .with_context(|| {
+                    error::Error::new(ErrorKind::InvalidModel, format!("s3://{}/{} is not a valid GGUF file", bucket, shard_key))
+                })?;
+            if index == 0 {
+                gguf = Some(header);
+            }
+        }
+    }
+    
+    // Decide which descriptor numbers the program will see the memory files at
+    // With --fd they are moved there right before exec, otherwise they stay where they are
+    let child_fds: Vec<i32> = match options.fd {
+        Some(first) => (first..).take(memfiles.len()).collect(),
+        None => memfiles.iter().map(MemFile::fd).collect(),
+    };
+    let file_names: Vec<&str> = keys
+        .iter()
+        .map(|key| key.rsplit('/').next().unwrap_or(key))
+        .collect();
+
+    // Get the path to the memory file descriptor
+    // This is a special path in /proc that points to the memory file
+    // Shards get named symlinks in a private directory instead, since llama.cpp
+    // finds the other shards from the first shard's file name
+    let mut runtime_dir = None;
+    let memfd_path = if memfiles.len() > 1 {
+        let dir = RuntimeDir::new()?;
+        let mut links = Vec::new();
+        for (&fd, name) in child_fds.iter().zip(&file_names) {
+            links.push(dir.link_fd(name, fd)?);
+        }
+        debug!(dir = %dir.path().display(), shards = links.len(), "Created shard symlinks");
+        runtime_dir = Some(dir);
+        links[0].display().to_string()
+    } else {
+        format!("/proc/self/fd/{}", child_fds[0])
+    };
+
+    // Replace placeholder with actual memfd path in all command arguments
+    // This allows the target program to access the memory file
+    let mut final_args: Vec<String> = args
+        .iter()
+        .map(|arg| arg.replace(&options.memfd_placeholder, &memfd_path))
+        .collect();
+
+    // Expand {{gguf:...}} placeholders from the model's metadata
+    if let Some(gguf) = &gguf {
+        final_args = final_args
+            .iter()
+            .map(|arg| template::expand_gguf_placeholders(arg, gguf))
+            .collect::<Result<_>>()
+            .context(error::Error::new(ErrorKind::InvalidConfiguration, "Failed to expand {{gguf:...}} placeholders"))?;
+    }
+
+    // Expand {{memfd_link:NAME}} placeholders into named symlinks for programs that
+    // need a real file name or extension, or that reject paths under /proc
+    if template::has_memfd_link_placeholders(&final_args) {
+        if runtime_dir.is_none() {
+            runtime_dir = Some(RuntimeDir::new()?);
+        }
+        let dir = runtime_dir.as_ref().expect("runtime directory was just created");
+        final_args = final_args
+            .iter()
+            .map(|arg| {
+                template::expand_memfd_links(arg, |number, name| {
+                    let fd = number
+                        .checked_sub(1)
+                        .and_then(|index| child_fds.get(index))
+                        .with_context(|| {
+                            format!("There is no memfd {}, only {} were downloaded", number, memfiles.len())
+                        })?;
+                    Ok(dir.link_fd(name, *fd)?.display().to_string())
+                })
+            })
+            .collect::<Result<_>>()
+            .context(error::Error::new(ErrorKind::InvalidConfiguration, "Failed to expand {{memfd_link:...}} placeholders"))?;
+    }
+
+    // Replace {{report}} with this download's timing report, as one line of JSON
+    // Every hot swap child is prepared here, so each gets the report of its own download
+    if final_args.iter().any(|arg| arg.contains(REPORT_PLACEHOLDER)) {
+        let report = options.progress.report(bucket).to_string();
+        final_args = final_args.iter().map(|arg| arg.replace(REPORT_PLACEHOLDER, &report)).collect();
+    }
+    
+    debug!(
+        program,
+        args = ?final_args,
+        "Preparing to execute program with memory file descriptor"
+    );
+
+    // A program downloaded from S3 is executed from its memory file, which is what
+    // fexecve does; with --fd it is moved to the descriptor after the memory files
+    let program_fd = program_memfile.as_ref().map(|memfile| match options.fd {
+        Some(first) => first + memfiles.len() as i32,
+        None => memfile.fd(),
+    });
+
+    // Create a new command to execute the target program
+    let mut cmd = match program_fd {
+        Some(fd) => {
+            let mut cmd = Command::new(format!("/proc/self/fd/{}", fd));
+            cmd.arg0(program);
+            cmd
+        }
+        None => {
+            // Run the program found on PATH, with argv[0] as the user wrote it
+            let mut cmd = Command::new(options.program_path.as_deref().unwrap_or(program.as_ref()));
+            cmd.arg0(program);
+            cmd
+        }
+    };
+    cmd.args(final_args);
+
+    // Set the environment variable with memfd_path for programs that might use it
+    // Only the program's environment changes, since hot swap prepares versions while
+    // other threads are running and each child needs its own path
+    cmd.env("MEMFD_PATH", &memfd_path);
+    debug!(memfd_path, "Set MEMFD_PATH environment variable");
+    if options.capture_output {
+        cmd.stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::piped());
+    }
+
+    // Place the memory files at the descriptors the program sees them at and stop every
+    // other descriptor, such as the SDK's sockets or the memory files of another model
+    // version, from leaking into the program; without --fd they stay where they are
+    let mut moves: Vec<(i32, i32)> = memfiles
+        .iter()
+        .map(MemFile::fd)
+        .zip(child_fds.iter().copied())
+        .collect();
+    // The program's memory file stays open so interpreters can read scripts from it
+    if let (Some(memfile), Some(fd)) = (&program_memfile, program_fd) {
+        moves.push((memfile.fd(), fd));
+    }
+    unsafe {
+        cmd.pre_exec(move || place_fds_for_exec(&moves));
+    }
+
+    if let Some(first) = options.fd {
+        cmd.env("MEMFD_FD", first.to_string());
+        // systemd-style socket activation only describes descriptors starting at 3
+        // A supervised child's pid isn't known until it is spawned, so LISTEN_PID can't be set
+        if first == 3 && options.supervise {
+            warn!("LISTEN_FDS is not exported when supervising, the child's pid isn't known in advance");
+        } else if first == 3 {
+            cmd.env("LISTEN_FDS", child_fds.len().to_string())
+                .env("LISTEN_PID", std::process::id().to_string())
+                .env("LISTEN_FDNAMES", file_names.join(":"));
+        }
+        debug!(fds = ?child_fds, "Memory files will be placed at fixed descriptors");
+    }
+
+    // The memory files must stay open for as long as the program may use them
+    memfiles.extend(program_memfile);
+    Ok(PreparedProgram { command: cmd, memfiles, runtime_dir })
+}
+
+// Models in S3 for hot swap mode, with everything needed to start a child for them
+pub struct S3ModelSource {
+    pub client: Client,
+    pub bucket: String,
+    pub key: String,
+    pub keys: Vec<String>,                // Every shard of the model, whose ETags make up its version
+    pub program: String,
+    pub args: Vec<String>,                // Arguments with {{port}} still in them
+    pub options: ExecOptions,
+}
+
+impl S3ModelSource {
+    // Download the model and build the command for a child listening on `port`
+    pub async fn prepare_with(&self, port: u16, progress: Arc<DownloadProgress>) -> Result<PreparedProgram> {
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| arg.replace(hotswap::PORT_PLACEHOLDER, &port.to_string()))
+            .collect();
+        let options = ExecOptions { progress, ..self.options.clone() };
+        create_memfd_and_exec(&self.bucket, &self.key, &self.client, &self.program, &args, &options).await
+    }
+}
+
+impl hotswap::ModelSource for S3ModelSource {
+    fn version(&self) -> futures::future::BoxFuture<'_, Result<String>> {
+        Box::pin(object_version(&self.client, &self.bucket, &self.keys))
+    }
+
+    fn prepare(&self, port: u16) -> futures::future::BoxFuture<'_, Result<PreparedProgram>> {
+        Box::pin(self.prepare_with(port, Arc::new(DownloadProgress::new())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_s3::LocalS3;
+    use crate::planner::SizeScaled;
+
+    #[test]
+    fn test_place_fds_for_exec() {
+        use std::os::unix::io::AsRawFd;
+
+        // A memory file with known content, and a descriptor that would normally leak
+        let mut memfile = MemFile::new("test_file").unwrap();
+        memfile.write_at(b"model bytes", 0).unwrap();
+        let leaked = unsafe { libc::dup(memfile.as_raw_fd()) };
+        assert!(leaked > 0);
+
+        let moves = vec![(memfile.fd(), 9)];
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "cat /proc/self/fd/9; test -e /proc/self/fd/{} && echo leaked",
+            leaked
+        ));
+        unsafe {
+            cmd.pre_exec(move || place_fds_for_exec(&moves));
+        }
+        let output = cmd.output().unwrap();
+        unsafe { libc::close(leaked) };
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "model bytes");
+    }
+
+    #[test]
+    fn test_place_fds_in_place() {
+        // Without --fd the memory files keep their numbers, and descriptors both between
+        // and above them are still closed
+        let mut first = MemFile::new("first").unwrap();
+        first.write_at(b"first", 0).unwrap();
+        let between = unsafe { libc::dup(first.fd()) };
+        let mut second = MemFile::new("second").unwrap();
+        second.write_at(b" second", 0).unwrap();
+        let above = unsafe { libc::fcntl(first.fd(), libc::F_DUPFD, 500) };
+        assert!(between > first.fd() && second.fd() > between && above >= 500);
+
+        let moves = vec![(first.fd(), first.fd()), (second.fd(), second.fd())];
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "cat /proc/self/fd/{} /proc/self/fd/{}; for fd in {} {}; do test -e /proc/self/fd/$fd && echo \" leaked $fd\"; done",
+            first.fd(),
+            second.fd(),
+            between,
+            above
+        ));
+        unsafe {
+            cmd.pre_exec(move || place_fds_for_exec(&moves));
+        }
+        let output = cmd.output().unwrap();
+        unsafe {
+            libc::close(between);
+            libc::close(above);
+        }
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "first second");
+    }
+
+    #[test]
+    fn test_place_fds_swapped() {
+        // Each memory file moves to the other's descriptor, so both must be staged first
+        let mut first = MemFile::new("first").unwrap();
+        first.write_at(b"first", 0).unwrap();
+        let mut second = MemFile::new("second").unwrap();
+        second.write_at(b" second", 0).unwrap();
+
+        let moves = vec![(first.fd(), second.fd()), (second.fd(), first.fd())];
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!("cat /proc/self/fd/{} /proc/self/fd/{}", second.fd(), first.fd()));
+        unsafe {
+            cmd.pre_exec(move || place_fds_for_exec(&moves));
+        }
+        let output = cmd.output().unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "first second");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_report_placeholder() {
+        let s3 = LocalS3::start(vec![("models", "model.bin", vec![7; 4096])]).await.unwrap();
+        let options = ExecOptions {
+            memfd_placeholder: "{{memfd}}".to_string(),
+            verify_gguf: false,
+            fd: None,
+            program_object: None,
+            program_path: None,
+            supervise: true,
+            capture_output: false,
+            progress: Arc::new(DownloadProgress::new()),
+            planner: Arc::new(SizeScaled),
+            limits: ChunkLimits::default(),
+        };
+        let args = ["{{memfd}}".to_string(), "--report={{report}}".to_string()];
+        let prepared = create_memfd_and_exec("models", "model.bin", &s3.client().await, "true", &args, &options)
+            .await
+            .unwrap();
+
+        let args: Vec<_> = prepared.command.get_args().map(|arg| arg.to_str().unwrap()).collect();
+        let report: serde_json::Value = serde_json::from_str(args[1].strip_prefix("--report=").unwrap()).unwrap();
+        assert_eq!(report["objects"][0]["key"], "model.bin");
+        assert_eq!(report["total_bytes"], 4096);
+
+        // MEMFD_PATH is set for the program only, not in this process
+        let memfd_path = prepared.command.get_envs().find(|(name, _)| *name == "MEMFD_PATH").and_then(|(_, value)| value);
+        assert_eq!(memfd_path.and_then(|path| path.to_str()), Some(args[0]));
+        assert!(std::env::var_os("MEMFD_PATH").is_none());
+    }
+}
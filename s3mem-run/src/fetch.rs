@@ -0,0 +1,91 @@
+// The fetch subcommand: download an object with the parallel loader and save it
+// The object goes through the same chunked, verified download as exec, into a sealed
+// memory file, and is written out in one piece once it is complete.
+
+use crate::chunk_args::ChunkArgs;             // Chunk planning options
+use crate::progress_display::ProgressArgs;    // Progress display options
+use anyhow::{Context, Result};                // Error handling with context
+use aws_sdk_s3::Client;                       // AWS S3 client
+use s3mem_run::inspect::format_bytes;         // Human-readable sizes
+use s3mem_run::progress::DownloadProgress;    // Progress of the download
+use s3mem_run::Loader;                        // The parallel download
+use std::io::Write;                           // Writing to stdout
+use std::path::PathBuf;                       // Output paths
+use std::sync::Arc;                           // Progress shared with the display
+use tracing::info;                            // Structured logging
+
+const STDOUT: &str = "-";                     // Output path that means stdout
+
+#[derive(clap::Args, Debug)]
+pub struct FetchArgs {
+    /// S3 bucket containing the file (defaults to S3_BUCKET env var)
+    #[arg(long, env = "S3_BUCKET")]
+    pub bucket: Option<String>,
+
+    /// S3 key of the file (defaults to S3_KEY env var)
+    #[arg(long, env = "S3_KEY")]
+    pub key: Option<String>,
+
+    /// File to write, replaced if it exists, or - for stdout
+    #[arg(short, long, default_value = STDOUT)]
+    pub output: PathBuf,
+
+    /// Expected SHA-256 of the file, as hex; nothing is written if it doesn't match
+    #[arg(long)]
+    pub sha256: Option<String>,
+
+    /// Validate the file as a GGUF model before writing it
+    #[arg(long)]
+    pub verify_gguf: bool,
+
+    #[command(flatten)]
+    pub progress: ProgressArgs,
+
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+}
+
+pub async fn run_fetch(client: &Client, bucket: &str, key: &str, args: &FetchArgs) -> Result<()> {
+    info!(bucket, key, output = %args.output.display(), "Fetching object");
+    let (planner, limits) = (args.chunks.planner()?, args.chunks.limits()?);
+    let progress = Arc::new(DownloadProgress::new());
+    let display = args.progress.start(&progress);
+
+    let mut loader = Loader::new(client.clone())
+        .source(bucket, key)
+        .verify_gguf(args.verify_gguf)
+        .planner(planner)
+        .limits(limits)
+        .progress(progress.clone());
+    if let Some(sha256) = &args.sha256 {
+        loader = loader.sha256(sha256);
+    }
+    let loaded = loader.load().await;
+    if let Some(display) = display {
+        display.finish().await;
+    }
+    let memfile = loaded?;
+
+    let contents = memfile.mmap()?;
+    if args.output.as_os_str() == STDOUT {
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&contents)
+            .and_then(|_| stdout.flush())
+            .context("Failed to write the file to stdout")?;
+    } else {
+        std::fs::write(&args.output, &*contents)
+            .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    }
+
+    let report = progress.report(bucket);
+    info!(
+        bytes = contents.len(),
+        size = %format_bytes(contents.len() as u64),
+        wall_time_ms = report["wall_time_ms"].as_f64(),
+        throughput_mbps = report["throughput_mbps"].as_f64(),
+        output = %args.output.display(),
+        "Object fetched"
+    );
+    Ok(())
+}
@@ -0,0 +1,670 @@
+// GGUF header and metadata parsing
+// GGUF is the model format used by llama.cpp. A file starts with a fixed header,
+// followed by metadata key/value pairs, tensor descriptions and finally the
+// aligned tensor data. Only the header, metadata and tensor descriptions are
+// parsed here; the tensor data itself is never read.
+
+use anyhow::{Context, Result};                // Error handling with context
+use std::fmt;                                 // Display implementations
+use std::fs::File;                            // File handle for reading the memfd
+use std::os::unix::fs::FileExt;               // Positional reads that don't move the file offset
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";               // Every GGUF file starts with these bytes
+const DEFAULT_ALIGNMENT: u64 = 32;                  // Tensor data alignment when general.alignment is absent
+const MAX_DIMS: u32 = 4;                            // llama.cpp tensors have at most 4 dimensions
+const MAX_STRING_LEN: u64 = 64 * 1024 * 1024;       // Upper bound for a single string, guards against garbage lengths
+pub const INITIAL_READ_SIZE: u64 = 1024 * 1024;     // First read when looking for the end of the metadata
+
+// ParseError separates "not enough bytes yet" from "these bytes are wrong"
+// Callers reading the file incrementally grow their buffer on Truncated and
+// report Invalid errors as-is
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Truncated { needed: u64 },  // The buffer must hold at least `needed` bytes to continue
+    Invalid(String),            // The file is not a valid GGUF file
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { needed } => {
+                write!(f, "GGUF header is truncated, at least {} bytes are needed", needed)
+            }
+            ParseError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// A metadata value as stored in the GGUF key/value section
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl Value {
+    // Return the value as an unsigned integer if it is a non-negative integer type
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::U8(v) => Some(v as u64),
+            Value::U16(v) => Some(v as u64),
+            Value::U32(v) => Some(v as u64),
+            Value::U64(v) => Some(v),
+            Value::I8(v) => u64::try_from(v).ok(),
+            Value::I16(v) => u64::try_from(v).ok(),
+            Value::I32(v) => u64::try_from(v).ok(),
+            Value::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    // Return the value as a string slice if it is a string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::U8(v) => write!(f, "{}", v),
+            Value::I8(v) => write!(f, "{}", v),
+            Value::U16(v) => write!(f, "{}", v),
+            Value::I16(v) => write!(f, "{}", v),
+            Value::U32(v) => write!(f, "{}", v),
+            Value::I32(v) => write!(f, "{}", v),
+            Value::F32(v) => write!(f, "{}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::String(v) => write!(f, "{}", v),
+            Value::Array(values) => write!(f, "[{} values]", values.len()),
+            Value::U64(v) => write!(f, "{}", v),
+            Value::I64(v) => write!(f, "{}", v),
+            Value::F64(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+// Description of a single tensor from the tensor info section
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorInfo {
+    pub name: String,        // Tensor name, e.g. "blk.0.attn_q.weight"
+    pub dims: Vec<u64>,      // Number of elements along each dimension
+    pub ggml_type: u32,      // ggml element type id
+    pub offset: u64,         // Offset of the tensor data relative to the data section
+}
+
+impl TensorInfo {
+    // Total number of elements in the tensor
+    pub fn element_count(&self) -> u64 {
+        self.dims.iter().product()
+    }
+
+    // Size of the tensor data in bytes, or None for element types we don't know
+    pub fn byte_size(&self) -> Option<u64> {
+        let (block_size, type_size) = ggml_type_size(self.ggml_type)?;
+        Some(self.element_count().div_ceil(block_size) * type_size)
+    }
+}
+
+// Parsed GGUF header, metadata and tensor descriptions
+#[derive(Debug, Clone)]
+pub struct GgufFile {
+    pub version: u32,                     // GGUF format version
+    pub metadata: Vec<(String, Value)>,   // Metadata key/value pairs in file order
+    pub tensors: Vec<TensorInfo>,         // Tensor descriptions in file order
+    pub alignment: u64,                   // Alignment of the tensor data section and of each tensor
+    pub data_offset: u64,                 // Absolute offset where tensor data starts
+}
+
+impl GgufFile {
+    // Look up a metadata value by key
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.metadata
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    // The model architecture from general.architecture, e.g. "llama"
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture").and_then(Value::as_str)
+    }
+
+    // Check that the tensor data described by the header fits in a file of `file_size` bytes
+    // Every tensor must start at an aligned offset and end before the end of the file
+    pub fn validate(&self, file_size: u64) -> Result<(), ParseError> {
+        if self.data_offset > file_size {
+            return Err(ParseError::Invalid(format!(
+                "Tensor data starts at offset {} but the file is only {} bytes",
+                self.data_offset, file_size
+            )));
+        }
+
+        for tensor in &self.tensors {
+            if tensor.offset % self.alignment != 0 {
+                return Err(ParseError::Invalid(format!(
+                    "Tensor '{}' has offset {} which is not a multiple of the alignment {}",
+                    tensor.name, tensor.offset, self.alignment
+                )));
+            }
+
+            let start = self.data_offset.saturating_add(tensor.offset);
+            let (end, size) = match tensor.byte_size() {
+                Some(size) => (start.saturating_add(size), size.to_string()),
+                // Tensors of unknown type can at least be checked to start inside the file
+                None => (start.saturating_add(1), "unknown".to_string()),
+            };
+            if end > file_size {
+                return Err(ParseError::Invalid(format!(
+                    "Tensor '{}' ({} bytes at offset {}) extends past the end of the {} byte file",
+                    tensor.name, size, start, file_size
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Block size (elements) and block byte size for a ggml type id
+// Returns None for type ids that were removed from ggml or are not known yet
+pub fn ggml_type_size(ggml_type: u32) -> Option<(u64, u64)> {
+    let size = match ggml_type {
+        0 => (1, 4),       // F32
+        1 => (1, 2),       // F16
+        2 => (32, 18),     // Q4_0
+        3 => (32, 20),     // Q4_1
+        6 => (32, 22),     // Q5_0
+        7 => (32, 24),     // Q5_1
+        8 => (32, 34),     // Q8_0
+        9 => (32, 36),     // Q8_1
+        10 => (256, 84),   // Q2_K
+        11 => (256, 110),  // Q3_K
+        12 => (256, 144),  // Q4_K
+        13 => (256, 176),  // Q5_K
+        14 => (256, 210),  // Q6_K
+        15 => (256, 292),  // Q8_K
+        16 => (256, 66),   // IQ2_XXS
+        17 => (256, 74),   // IQ2_XS
+        18 => (256, 98),   // IQ3_XXS
+        19 => (256, 50),   // IQ1_S
+        20 => (32, 18),    // IQ4_NL
+        21 => (256, 110),  // IQ3_S
+        22 => (256, 82),   // IQ2_S
+        23 => (256, 136),  // IQ4_XS
+        24 => (1, 1),      // I8
+        25 => (1, 2),      // I16
+        26 => (1, 4),      // I32
+        27 => (1, 8),      // I64
+        28 => (1, 8),      // F64
+        29 => (256, 56),   // IQ1_M
+        30 => (1, 2),      // BF16
+        34 => (256, 54),   // TQ1_0
+        35 => (256, 66),   // TQ2_0
+        39 => (32, 17),    // MXFP4
+        _ => return None,
+    };
+    Some(size)
+}
+
+// Human-readable name for a ggml type id
+pub fn ggml_type_name(ggml_type: u32) -> String {
+    let name = match ggml_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        6 => "Q5_0",
+        7 => "Q5_1",
+        8 => "Q8_0",
+        9 => "Q8_1",
+        10 => "Q2_K",
+        11 => "Q3_K",
+        12 => "Q4_K",
+        13 => "Q5_K",
+        14 => "Q6_K",
+        15 => "Q8_K",
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        24 => "I8",
+        25 => "I16",
+        26 => "I32",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        30 => "BF16",
+        34 => "TQ1_0",
+        35 => "TQ2_0",
+        39 => "MXFP4",
+        other => return format!("TYPE_{}", other),
+    };
+    name.to_string()
+}
+
+// Look up a ggml type id by its name, ignoring case (e.g. "q8_0" or "F16")
+pub fn ggml_type_by_name(name: &str) -> Option<u32> {
+    (0..64).find(|&ggml_type| {
+        ggml_type_size(ggml_type).is_some() && ggml_type_name(ggml_type).eq_ignore_ascii_case(name)
+    })
+}
+
+// Human-readable name for the general.file_type metadata value (llama_ftype)
+pub fn file_type_name(file_type: u64) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        38 => "MXFP4_MOE",
+        other => return format!("FTYPE_{}", other),
+    };
+    name.to_string()
+}
+
+// Cursor over a byte buffer that reports how many bytes it would need when it runs out
+struct Reader<'a> {
+    buf: &'a [u8],           // Bytes available so far, starting at file offset 0
+    pos: u64,                // Current read position
+    file_size: Option<u64>,  // Size of the whole file when known, used to reject impossible lengths
+}
+
+impl<'a> Reader<'a> {
+    // Take the next `len` bytes, or report how large the buffer must be
+    fn take(&mut self, len: u64, what: &str) -> Result<&'a [u8], ParseError> {
+        let end = self.pos.checked_add(len).ok_or_else(|| {
+            ParseError::Invalid(format!("{} at offset {} has an impossible length {}", what, self.pos, len))
+        })?;
+        if let Some(file_size) = self.file_size {
+            if end > file_size {
+                return Err(ParseError::Invalid(format!(
+                    "{} at offset {} ({} bytes) extends past the end of the {} byte file",
+                    what, self.pos, len, file_size
+                )));
+            }
+        }
+        if end > self.buf.len() as u64 {
+            return Err(ParseError::Truncated { needed: end });
+        }
+        let bytes = &self.buf[self.pos as usize..end as usize];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self, what: &str) -> Result<[u8; N], ParseError> {
+        let bytes = self.take(N as u64, what)?;
+        Ok(bytes.try_into().expect("take returned the requested length"))
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, ParseError> {
+        Ok(self.array::<1>(what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, ParseError> {
+        Ok(u16::from_le_bytes(self.array(what)?))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, ParseError> {
+        Ok(u32::from_le_bytes(self.array(what)?))
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64, ParseError> {
+        Ok(u64::from_le_bytes(self.array(what)?))
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, ParseError> {
+        let offset = self.pos;
+        let len = self.u64(what)?;
+        if len > MAX_STRING_LEN {
+            return Err(ParseError::Invalid(format!(
+                "{} at offset {} has an implausible length of {} bytes",
+                what, offset, len
+            )));
+        }
+        let bytes = self.take(len, what)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| ParseError::Invalid(format!("{} at offset {} is not valid UTF-8", what, offset)))
+    }
+
+    // Read a metadata value of the given GGUF value type
+    fn value(&mut self, value_type: u32, key: &str) -> Result<Value, ParseError> {
+        let value = match value_type {
+            0 => Value::U8(self.u8(key)?),
+            1 => Value::I8(self.u8(key)? as i8),
+            2 => Value::U16(self.u16(key)?),
+            3 => Value::I16(self.u16(key)? as i16),
+            4 => Value::U32(self.u32(key)?),
+            5 => Value::I32(self.u32(key)? as i32),
+            6 => Value::F32(f32::from_bits(self.u32(key)?)),
+            7 => match self.u8(key)? {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                other => {
+                    return Err(ParseError::Invalid(format!(
+                        "Metadata key '{}' has invalid boolean value {}",
+                        key, other
+                    )))
+                }
+            },
+            8 => Value::String(self.string(key)?),
+            9 => {
+                let offset = self.pos;
+                let element_type = self.u32(key)?;
+                if element_type == 9 {
+                    return Err(ParseError::Invalid(format!(
+                        "Metadata key '{}' at offset {} is a nested array, which GGUF doesn't allow",
+                        key, offset
+                    )));
+                }
+                let len = self.u64(key)?;
+                // Every element takes at least one byte, so a count larger than the
+                // rest of the file can only come from a corrupted header
+                if let Some(file_size) = self.file_size {
+                    if len > file_size.saturating_sub(self.pos) {
+                        return Err(ParseError::Invalid(format!(
+                            "Metadata key '{}' at offset {} claims {} array elements, more than the file can hold",
+                            key, offset, len
+                        )));
+                    }
+                }
+                let mut values = Vec::with_capacity(len.min(1 << 20) as usize);
+                for _ in 0..len {
+                    values.push(self.value(element_type, key)?);
+                }
+                Value::Array(values)
+            }
+            10 => Value::U64(self.u64(key)?),
+            11 => Value::I64(self.u64(key)? as i64),
+            12 => Value::F64(f64::from_bits(self.u64(key)?)),
+            other => {
+                return Err(ParseError::Invalid(format!(
+                    "Metadata key '{}' at offset {} has unknown value type {}",
+                    key,
+                    self.pos - 4,
+                    other
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+// Parse the GGUF header, metadata and tensor descriptions from the start of a file
+// `buf` may hold only a prefix of the file; when it is too short the error says how
+// many bytes are needed. `file_size` is used to reject lengths that can't fit the file.
+pub fn parse(buf: &[u8], file_size: Option<u64>) -> Result<GgufFile, ParseError> {
+    let mut reader = Reader { buf, pos: 0, file_size };
+
+    // Header: magic, version, tensor count and metadata count
+    let magic = reader.array::<4>("GGUF magic")?;
+    if &magic != GGUF_MAGIC {
+        return Err(ParseError::Invalid(format!(
+            "Not a GGUF file: expected magic {:?} but found {:?}",
+            String::from_utf8_lossy(GGUF_MAGIC),
+            String::from_utf8_lossy(&magic)
+        )));
+    }
+    let version = reader.u32("GGUF version")?;
+    if !(2..=3).contains(&version) {
+        return Err(ParseError::Invalid(format!(
+            "Unsupported GGUF version {}, only versions 2 and 3 are supported",
+            version
+        )));
+    }
+    let tensor_count = reader.u64("tensor count")?;
+    let metadata_count = reader.u64("metadata count")?;
+
+    // Each metadata entry and tensor description takes well over 8 bytes, so counts
+    // beyond that are certainly corrupt and would otherwise cause huge allocations
+    if let Some(file_size) = file_size {
+        for (count, what) in [(tensor_count, "tensor"), (metadata_count, "metadata")] {
+            if count > file_size / 8 {
+                return Err(ParseError::Invalid(format!(
+                    "Header claims {} {} entries, more than a {} byte file can hold",
+                    count, what, file_size
+                )));
+            }
+        }
+    }
+
+    // Metadata key/value section
+    let mut metadata = Vec::with_capacity(metadata_count.min(1 << 16) as usize);
+    for index in 0..metadata_count {
+        let key = reader.string(&format!("metadata key #{}", index))?;
+        let value_type = reader.u32(&key)?;
+        let value = reader.value(value_type, &key)?;
+        metadata.push((key, value));
+    }
+
+    // Tensor info section
+    let mut tensors = Vec::with_capacity(tensor_count.min(1 << 16) as usize);
+    for index in 0..tensor_count {
+        let name = reader.string(&format!("tensor name #{}", index))?;
+        let n_dims = reader.u32(&name)?;
+        if n_dims == 0 || n_dims > MAX_DIMS {
+            return Err(ParseError::Invalid(format!(
+                "Tensor '{}' has {} dimensions, expected 1 to {}",
+                name, n_dims, MAX_DIMS
+            )));
+        }
+        let mut dims = Vec::with_capacity(n_dims as usize);
+        for _ in 0..n_dims {
+            dims.push(reader.u64(&name)?);
+        }
+        if dims.iter().try_fold(1u64, |acc, &dim| acc.checked_mul(dim)).is_none() {
+            return Err(ParseError::Invalid(format!(
+                "Tensor '{}' has dimensions {:?} whose element count overflows",
+                name, dims
+            )));
+        }
+        let ggml_type = reader.u32(&name)?;
+        let offset = reader.u64(&name)?;
+        tensors.push(TensorInfo { name, dims, ggml_type, offset });
+    }
+
+    // The tensor data section starts at the next multiple of the alignment
+    let alignment = match metadata.iter().find(|(key, _)| key == "general.alignment") {
+        Some((_, value)) => match value.as_u64() {
+            Some(alignment) if alignment > 0 && alignment.is_power_of_two() => alignment,
+            _ => {
+                return Err(ParseError::Invalid(format!(
+                    "general.alignment must be a power of two, found {}",
+                    value
+                )))
+            }
+        },
+        None => DEFAULT_ALIGNMENT,
+    };
+    let data_offset = reader.pos.div_ceil(alignment) * alignment;
+
+    Ok(GgufFile { version, metadata, tensors, alignment, data_offset })
+}
+
+// How many bytes to hold after a parse reported it needs `needed` bytes
+// Grows at least geometrically so large vocabularies don't take many passes
+pub fn next_read_len(current: u64, needed: u64, file_size: u64) -> u64 {
+    needed.max(current * 2).min(file_size)
+}
+
+// Parse and validate the GGUF header of a file that has been fully written
+// Reads only as much of the file as the header and metadata need
+pub fn read_from_file(file: &File, file_size: u64) -> Result<GgufFile> {
+    let mut len = INITIAL_READ_SIZE.min(file_size);
+    loop {
+        let mut buf = vec![0u8; len as usize];
+        file.read_exact_at(&mut buf, 0)
+            .context("Failed to read GGUF header")?;
+
+        match parse(&buf, Some(file_size)) {
+            Ok(gguf) => {
+                gguf.validate(file_size)?;
+                return Ok(gguf);
+            }
+            Err(ParseError::Truncated { needed }) if needed <= file_size => {
+                len = next_read_len(len, needed, file_size);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Build a small GGUF file in memory for tests
+    // Metadata values are (key, type, encoded value) and tensors are (name, dims, type, offset)
+    pub(crate) fn build_gguf(
+        metadata: &[(&str, u32, Vec<u8>)],
+        tensors: &[(&str, &[u64], u32, u64)],
+        data_len: usize,
+    ) -> Vec<u8> {
+        fn string(out: &mut Vec<u8>, value: &str) {
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            out.extend_from_slice(value.as_bytes());
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(GGUF_MAGIC);
+        out.extend_from_slice(&3u32.to_le_bytes());
+        out.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+        out.extend_from_slice(&(metadata.len() as u64).to_le_bytes());
+        for (key, value_type, value) in metadata {
+            string(&mut out, key);
+            out.extend_from_slice(&value_type.to_le_bytes());
+            out.extend_from_slice(value);
+        }
+        for (name, dims, ggml_type, offset) in tensors {
+            string(&mut out, name);
+            out.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+            for dim in *dims {
+                out.extend_from_slice(&dim.to_le_bytes());
+            }
+            out.extend_from_slice(&ggml_type.to_le_bytes());
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+        out.resize(out.len().div_ceil(32) * 32 + data_len, 0);
+        out
+    }
+
+    // Encode a GGUF string value
+    pub(crate) fn string_value(value: &str) -> Vec<u8> {
+        let mut out = (value.len() as u64).to_le_bytes().to_vec();
+        out.extend_from_slice(value.as_bytes());
+        out
+    }
+
+    fn sample() -> Vec<u8> {
+        build_gguf(
+            &[
+                ("general.architecture", 8, string_value("llama")),
+                ("llama.context_length", 4, 4096u32.to_le_bytes().to_vec()),
+            ],
+            &[("token_embd.weight", &[8, 4], 0, 0), ("output.weight", &[32], 8, 128)],
+            128 + 34,
+        )
+    }
+
+    #[test]
+    fn test_parse_valid_file() {
+        let bytes = sample();
+        let gguf = parse(&bytes, Some(bytes.len() as u64)).unwrap();
+        assert_eq!(gguf.version, 3);
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.get("llama.context_length").and_then(Value::as_u64), Some(4096));
+        assert_eq!(gguf.tensors.len(), 2);
+        assert_eq!(gguf.tensors[0].byte_size(), Some(128));
+        assert_eq!(gguf.tensors[1].byte_size(), Some(34));
+        assert_eq!(gguf.data_offset % 32, 0);
+        gguf.validate(bytes.len() as u64).unwrap();
+    }
+
+    #[test]
+    fn test_parse_reports_truncation() {
+        let bytes = sample();
+        match parse(&bytes[..40], None) {
+            Err(ParseError::Truncated { needed }) => assert!(needed > 40),
+            other => panic!("expected truncation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_magic_and_version() {
+        let mut bytes = sample();
+        bytes[0] = b'X';
+        assert!(matches!(parse(&bytes, None), Err(ParseError::Invalid(_))));
+
+        let mut bytes = sample();
+        bytes[4..8].copy_from_slice(&7u32.to_le_bytes());
+        assert!(matches!(parse(&bytes, None), Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_validate_rejects_tensor_past_end() {
+        // Drop the last bytes so the Q8_0 tensor no longer fits
+        let mut bytes = sample();
+        bytes.truncate(bytes.len() - 10);
+        let gguf = parse(&bytes, Some(bytes.len() as u64)).unwrap();
+        let err = gguf.validate(bytes.len() as u64).unwrap_err();
+        assert!(err.to_string().contains("output.weight"));
+    }
+
+    #[test]
+    fn test_read_from_file() {
+        let bytes = sample();
+        let path = std::env::temp_dir().join(format!("s3mem-run-gguf-{}", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let file = File::open(&path).unwrap();
+        let gguf = read_from_file(&file, bytes.len() as u64).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(gguf.tensors.len(), 2);
+    }
+}
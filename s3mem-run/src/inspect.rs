@@ -0,0 +1,558 @@
+// Remote inspection of GGUF models in S3
+// The object's own metadata comes from a HEAD request. Of its contents only the header
+// and metadata region is fetched, using the same validated range GETs as the parallel
+// download. The read starts small and grows
+// until the GGUF parser has everything it needs.
+
+use crate::loader::download_chunk_with_retry;  // Validated, retried S3 requests
+use crate::gguf::{self, GgufFile, ParseError, Value};  // GGUF parsing
+use anyhow::{Context, Result};                // Error handling with context
+use aws_sdk_s3::Client;                       // AWS S3 client
+use clap::ValueEnum;                          // Command-line enum parsing
+use serde_json::json;                         // JSON report output
+use tracing::{debug, info, instrument};       // Structured logging
+
+// Output format of the inspection report
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    Human,
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InspectArgs {
+    /// S3 bucket containing the model (defaults to S3_BUCKET env var)
+    #[arg(long, env = "S3_BUCKET")]
+    pub bucket: Option<String>,
+
+    /// S3 key of the model (defaults to S3_KEY env var)
+    #[arg(long, env = "S3_KEY")]
+    pub key: Option<String>,
+
+    /// Report format
+    #[arg(long, value_enum, default_value = "human")]
+    pub format: ReportFormat,
+
+    /// Context size for the memory estimate (defaults to the model's context length)
+    #[arg(long)]
+    pub ctx_size: Option<u64>,
+
+    /// KV cache type for keys used in the memory estimate (e.g. f16, q8_0)
+    #[arg(long, default_value = "f16")]
+    pub cache_type_k: String,
+
+    /// KV cache type for values used in the memory estimate (e.g. f16, q8_0)
+    #[arg(long, default_value = "f16")]
+    pub cache_type_v: String,
+}
+
+// What S3 knows about the object
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,     // RFC 3339
+    pub content_type: Option<String>,
+    pub storage_class: String,             // STANDARD unless S3 says otherwise
+}
+
+impl ObjectInfo {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "etag": self.etag,
+            "last_modified": self.last_modified,
+            "content_type": self.content_type,
+            "storage_class": self.storage_class,
+        })
+    }
+}
+
+#[instrument(skip(client))]
+// Get an object's metadata with a HEAD request
+pub async fn object_info(client: &Client, bucket: &str, key: &str) -> Result<ObjectInfo> {
+    let head = client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context("Failed to get object metadata from S3")?;
+    Ok(ObjectInfo {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        size: head.content_length().context("Content length not available")? as u64,
+        etag: head.e_tag().map(str::to_string),
+        last_modified: head
+            .last_modified()
+            .and_then(|time| time.fmt(aws_sdk_s3::primitives::DateTimeFormat::DateTime).ok()),
+        content_type: head.content_type().map(str::to_string),
+        storage_class: head
+            .storage_class()
+            .map_or("STANDARD", |class| class.as_str())
+            .to_string(),
+    })
+}
+
+// Estimated memory needed to serve a model with llama.cpp
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryEstimate {
+    pub ctx_size: u64,         // Context size the estimate is for
+    pub weights_bytes: u64,    // Tensor data, which stays in the memfd
+    pub kv_cache_bytes: u64,   // K and V cache for all layers
+}
+
+impl MemoryEstimate {
+    pub fn total_bytes(&self) -> u64 {
+        self.weights_bytes + self.kv_cache_bytes
+    }
+}
+
+// Format a byte count with a binary unit
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+// Parse a byte count such as 1048576, 512K, 16MiB or 1.5G; units are binary
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("{:?} is not a size", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("{:?} has an unknown unit, use K, M, G or T", value)),
+    };
+    match (number * multiplier as f64).round() as u64 {
+        0 => Err(format!("{:?} must be at least one byte", value)),
+        bytes => Ok(bytes),
+    }
+}
+
+// Read a per-model hyperparameter "{arch}.{name}"
+fn arch_value<'a>(gguf: &'a GgufFile, name: &str) -> Option<&'a Value> {
+    let arch = gguf.architecture()?;
+    gguf.get(&format!("{}.{}", arch, name))
+}
+
+// A hyperparameter that is either one value for all layers or an array per layer
+// A single value is never expanded, since block_count comes from untrusted metadata
+#[derive(Debug, Clone, PartialEq)]
+enum PerLayer {
+    All(u64),
+    Each(Vec<u64>),
+}
+
+impl PerLayer {
+    fn read(value: Option<&Value>) -> Option<Self> {
+        match value? {
+            Value::Array(values) => values.iter().map(Value::as_u64).collect::<Option<_>>().map(PerLayer::Each),
+            value => value.as_u64().map(PerLayer::All),
+        }
+    }
+
+    fn layer(&self, layer: usize) -> Option<u64> {
+        match self {
+            PerLayer::All(value) => Some(*value),
+            PerLayer::Each(values) => values.get(layer).copied(),
+        }
+    }
+}
+
+// Bytes per element of a KV cache type such as "f16" or "q8_0"
+fn cache_type_bytes(name: &str) -> Result<f64> {
+    let (block_size, type_size) = gguf::ggml_type_by_name(name)
+        .and_then(gguf::ggml_type_size)
+        .with_context(|| format!("Unknown KV cache type '{}'", name))?;
+    Ok(type_size as f64 / block_size as f64)
+}
+
+// Estimate weights and KV cache memory for a context size and cache types
+// Compute buffers are not included since they depend on batch sizes and the backend
+pub fn estimate_memory(
+    gguf: &GgufFile,
+    ctx_size: u64,
+    cache_type_k: &str,
+    cache_type_v: &str,
+) -> Result<MemoryEstimate> {
+    let k_bytes = cache_type_bytes(cache_type_k)?;
+    let v_bytes = cache_type_bytes(cache_type_v)?;
+    let weights_bytes = gguf.tensors.iter().filter_map(|t| t.byte_size()).sum();
+
+    let n_layer = arch_value(gguf, "block_count")
+        .and_then(Value::as_u64)
+        .context("Model has no block_count, can't estimate the KV cache")?;
+    let n_embd = arch_value(gguf, "embedding_length")
+        .and_then(Value::as_u64)
+        .context("Model has no embedding_length, can't estimate the KV cache")?;
+    let n_head = PerLayer::read(arch_value(gguf, "attention.head_count"))
+        .context("Model has no attention.head_count, can't estimate the KV cache")?;
+    let n_head_kv = PerLayer::read(arch_value(gguf, "attention.head_count_kv"))
+        .unwrap_or_else(|| n_head.clone());
+
+    // KV cache bytes of one layer for the whole context
+    let layer_bytes = |heads: u64, kv_heads: u64| {
+        if heads == 0 || kv_heads == 0 {
+            return 0f64;
+        }
+        let key_length = arch_value(gguf, "attention.key_length")
+            .and_then(Value::as_u64)
+            .unwrap_or(n_embd / heads);
+        let value_length = arch_value(gguf, "attention.value_length")
+            .and_then(Value::as_u64)
+            .unwrap_or(n_embd / heads);
+        let per_token = kv_heads as f64 * (key_length as f64 * k_bytes + value_length as f64 * v_bytes);
+        per_token * ctx_size as f64
+    };
+
+    // Per-layer arrays are walked as far as both reach, like llama.cpp's own loop,
+    // and identical layers are multiplied out instead
+    let kv_cache_bytes = match (&n_head, &n_head_kv) {
+        (PerLayer::All(heads), PerLayer::All(kv_heads)) => layer_bytes(*heads, *kv_heads) * n_layer as f64,
+        _ => (0..n_layer as usize)
+            .map_while(|layer| Some(layer_bytes(n_head.layer(layer)?, n_head_kv.layer(layer)?)))
+            .sum(),
+    };
+
+    Ok(MemoryEstimate {
+        ctx_size,
+        weights_bytes,
+        kv_cache_bytes: kv_cache_bytes.ceil() as u64,
+    })
+}
+
+#[instrument(skip(client))]
+// Fetch just enough of the object to parse its GGUF header and metadata
+// Returns the parsed header and how many bytes were read
+pub async fn fetch_gguf_header(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    total_size: i64,
+) -> Result<(GgufFile, u64)> {
+    let file_size = total_size as u64;
+    let mut buf: Vec<u8> = Vec::new();
+    let mut want = gguf::INITIAL_READ_SIZE.min(file_size);
+
+    loop {
+        // Fetch only the bytes beyond what we already have
+        if want > buf.len() as u64 {
+            let start = buf.len() as i64;
+            let end = want as i64 - 1;
+            debug!(start, end, "Fetching GGUF header range");
+            let data = download_chunk_with_retry(client, bucket, key, start, end, total_size, &|_, _, _| {}).await?.data;
+            buf.extend_from_slice(&data);
+        }
+
+        match gguf::parse(&buf, Some(file_size)) {
+            Ok(header) => {
+                header.validate(file_size)?;
+                return Ok((header, buf.len() as u64));
+            }
+            Err(ParseError::Truncated { needed }) if needed <= file_size => {
+                want = gguf::next_read_len(buf.len() as u64, needed, file_size);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Build the inspection report as JSON
+fn json_report(
+    object: &ObjectInfo,
+    header_bytes: u64,
+    gguf: &GgufFile,
+    estimate: Option<&MemoryEstimate>,
+    cache_types: (&str, &str),
+) -> serde_json::Value {
+    let metadata: serde_json::Map<String, serde_json::Value> = gguf
+        .metadata
+        .iter()
+        .map(|(key, value)| (key.clone(), json_value(value)))
+        .collect();
+    let tensors: Vec<serde_json::Value> = gguf
+        .tensors
+        .iter()
+        .map(|tensor| {
+            json!({
+                "name": tensor.name,
+                "type": gguf::ggml_type_name(tensor.ggml_type),
+                "dims": tensor.dims,
+                "offset": gguf.data_offset + tensor.offset,
+                "bytes": tensor.byte_size(),
+            })
+        })
+        .collect();
+
+    json!({
+        "bucket": object.bucket,
+        "key": object.key,
+        "size_bytes": object.size,
+        "object": object.to_json(),
+        "header_bytes_read": header_bytes,
+        "gguf_version": gguf.version,
+        "architecture": gguf.architecture(),
+        "name": gguf.get("general.name").and_then(Value::as_str),
+        "quantization": quantization(gguf),
+        "context_length": arch_value(gguf, "context_length").and_then(Value::as_u64),
+        "parameters": parameter_count(gguf),
+        "data_offset": gguf.data_offset,
+        "alignment": gguf.alignment,
+        "memory_estimate": estimate.map(|estimate| json!({
+            "ctx_size": estimate.ctx_size,
+            "cache_type_k": cache_types.0,
+            "cache_type_v": cache_types.1,
+            "weights_bytes": estimate.weights_bytes,
+            "kv_cache_bytes": estimate.kv_cache_bytes,
+            "total_bytes": estimate.total_bytes(),
+        })),
+        "metadata": metadata,
+        "tensors": tensors,
+    })
+}
+
+// Convert a metadata value to JSON, summarizing large arrays such as vocabularies
+fn json_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Array(values) if values.len() > 16 => json!({ "array_len": values.len() }),
+        Value::Array(values) => values.iter().map(json_value).collect(),
+        Value::String(v) => json!(v),
+        Value::Bool(v) => json!(v),
+        Value::F32(v) => json!(v),
+        Value::F64(v) => json!(v),
+        Value::I8(v) => json!(v),
+        Value::I16(v) => json!(v),
+        Value::I32(v) => json!(v),
+        Value::I64(v) => json!(v),
+        other => json!(other.as_u64()),
+    }
+}
+
+// Quantization name from general.file_type, or the most common tensor type
+fn quantization(gguf: &GgufFile) -> Option<String> {
+    if let Some(file_type) = gguf.get("general.file_type").and_then(Value::as_u64) {
+        return Some(gguf::file_type_name(file_type));
+    }
+    let mut counts = std::collections::BTreeMap::new();
+    for tensor in &gguf.tensors {
+        *counts.entry(tensor.ggml_type).or_insert(0u64) += tensor.element_count();
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+        .map(|(ggml_type, _)| gguf::ggml_type_name(ggml_type))
+}
+
+// Total number of weights across all tensors
+fn parameter_count(gguf: &GgufFile) -> u64 {
+    gguf.tensors.iter().map(|t| t.element_count()).sum()
+}
+
+// Print the inspection report for people
+fn print_human_report(
+    object: &ObjectInfo,
+    header_bytes: u64,
+    gguf: &GgufFile,
+    estimate: Option<&MemoryEstimate>,
+    cache_types: (&str, &str),
+) {
+    let unknown = || "unknown".to_string();
+    println!("Object:        s3://{}/{} ({})", object.bucket, object.key, format_bytes(object.size));
+    println!("ETag:          {}", object.etag.clone().unwrap_or_else(unknown));
+    println!("Modified:      {}", object.last_modified.clone().unwrap_or_else(unknown));
+    println!("Content type:  {}", object.content_type.clone().unwrap_or_else(unknown));
+    println!("Storage class: {}", object.storage_class);
+    println!(
+        "Format:        GGUF v{}, {} tensors, {} metadata entries, read {} of header",
+        gguf.version,
+        gguf.tensors.len(),
+        gguf.metadata.len(),
+        format_bytes(header_bytes)
+    );
+    println!("Architecture:  {}", gguf.architecture().unwrap_or("unknown"));
+    println!(
+        "Name:          {}",
+        gguf.get("general.name").and_then(Value::as_str).unwrap_or("unknown")
+    );
+    println!("Quantization:  {}", quantization(gguf).unwrap_or_else(unknown));
+    println!(
+        "Context:       {}",
+        arch_value(gguf, "context_length").map_or_else(unknown, |v| v.to_string())
+    );
+    println!(
+        "Layers:        {}",
+        arch_value(gguf, "block_count").map_or_else(unknown, |v| v.to_string())
+    );
+    println!("Parameters:    {:.2} B", parameter_count(gguf) as f64 / 1e9);
+
+    println!();
+    println!("Metadata:");
+    for (key, value) in &gguf.metadata {
+        println!("  {} = {}", key, value);
+    }
+
+    if let Some(estimate) = estimate {
+        println!();
+        println!(
+            "Memory estimate (ctx {}, K {}, V {}):",
+            estimate.ctx_size, cache_types.0, cache_types.1
+        );
+        println!("  Weights:   {}", format_bytes(estimate.weights_bytes));
+        println!("  KV cache:  {}", format_bytes(estimate.kv_cache_bytes));
+        println!("  Total:     {} (excludes compute buffers)", format_bytes(estimate.total_bytes()));
+    }
+
+    println!();
+    println!("Tensors:");
+    for tensor in &gguf.tensors {
+        println!(
+            "  {:<40} {:<8} {:<24} {}",
+            tensor.name,
+            gguf::ggml_type_name(tensor.ggml_type),
+            format!("{:?}", tensor.dims),
+            tensor.byte_size().map_or_else(unknown, format_bytes)
+        );
+    }
+}
+
+#[instrument(skip(client, args))]
+// Inspect a GGUF model in S3 without downloading its tensor data
+pub async fn run_inspect(client: &Client, bucket: &str, key: &str, args: &InspectArgs) -> Result<()> {
+    info!(bucket, key, "Inspecting object");
+    let object = object_info(client, bucket, key).await?;
+    let total_size = object.size as i64;
+
+    let (gguf, header_bytes) = fetch_gguf_header(client, bucket, key, total_size)
+        .await
+        .with_context(|| format!("s3://{}/{} is not a valid GGUF file", bucket, key))?;
+    info!(header_bytes, tensors = gguf.tensors.len(), "GGUF header fetched");
+
+    // The estimate needs a context size; fall back to the model's trained context
+    let ctx_size = args
+        .ctx_size
+        .or_else(|| arch_value(&gguf, "context_length").and_then(Value::as_u64));
+    let estimate = match ctx_size {
+        Some(ctx_size) => Some(estimate_memory(&gguf, ctx_size, &args.cache_type_k, &args.cache_type_v)?),
+        None => None,
+    };
+
+    let cache_types = (args.cache_type_k.as_str(), args.cache_type_v.as_str());
+    match args.format {
+        ReportFormat::Human => print_human_report(&object, header_bytes, &gguf, estimate.as_ref(), cache_types),
+        ReportFormat::Json => {
+            let report = json_report(&object, header_bytes, &gguf, estimate.as_ref(), cache_types);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::tests::{build_gguf, string_value};
+
+    #[test]
+    fn test_estimate_memory() {
+        let bytes = build_gguf(
+            &[
+                ("general.architecture", 8, string_value("llama")),
+                ("llama.block_count", 4, 2u32.to_le_bytes().to_vec()),
+                ("llama.embedding_length", 4, 64u32.to_le_bytes().to_vec()),
+                ("llama.attention.head_count", 4, 8u32.to_le_bytes().to_vec()),
+                ("llama.attention.head_count_kv", 4, 2u32.to_le_bytes().to_vec()),
+            ],
+            &[("token_embd.weight", &[64, 4], 0, 0)],
+            1024,
+        );
+        let gguf = gguf::parse(&bytes, None).unwrap();
+
+        // 2 layers * 2 KV heads * (8 + 8) head dims * 2 bytes per f16 element per token
+        let estimate = estimate_memory(&gguf, 100, "f16", "f16").unwrap();
+        assert_eq!(estimate.weights_bytes, 64 * 4 * 4);
+        assert_eq!(estimate.kv_cache_bytes, 2 * 2 * 16 * 2 * 100);
+
+        // q8_0 stores 32 elements in 34 bytes
+        let estimate = estimate_memory(&gguf, 32, "q8_0", "q8_0").unwrap();
+        assert_eq!(estimate.kv_cache_bytes, 2 * 2 * 16 * 34);
+
+        assert!(estimate_memory(&gguf, 32, "q9_9", "f16").is_err());
+
+        // A huge block_count is multiplied out rather than expanded into a vector per layer
+        let bytes = build_gguf(
+            &[
+                ("general.architecture", 8, string_value("llama")),
+                ("llama.block_count", 10, u64::MAX.to_le_bytes().to_vec()),
+                ("llama.embedding_length", 4, 64u32.to_le_bytes().to_vec()),
+                ("llama.attention.head_count", 4, 8u32.to_le_bytes().to_vec()),
+            ],
+            &[],
+            0,
+        );
+        let gguf = gguf::parse(&bytes, None).unwrap();
+        let estimate = estimate_memory(&gguf, 1, "f16", "f16").unwrap();
+        assert!(estimate.kv_cache_bytes > 0);
+    }
+
+    #[test]
+    fn test_per_layer() {
+        let each = PerLayer::read(Some(&Value::Array(vec![Value::U32(8), Value::U32(0)]))).unwrap();
+        assert_eq!(each, PerLayer::Each(vec![8, 0]));
+        assert_eq!((each.layer(1), each.layer(2)), (Some(0), None));
+        assert_eq!(PerLayer::read(Some(&Value::U32(4))).unwrap().layer(1000), Some(4));
+        assert!(PerLayer::read(Some(&Value::String("8".into()))).is_none());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.00 GiB");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1048576"), Ok(1 << 20));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("16MiB"), Ok(16 << 20));
+        assert_eq!(parse_size("1.5g"), Ok(3 << 29));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("16 parsecs").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_inspect_local_object() {
+        let bytes = build_gguf(&[("general.architecture", 8, string_value("llama"))], &[], 0);
+        let s3 = crate::local_s3::LocalS3::start(vec![("models", "model.gguf", bytes.clone())]).await.unwrap();
+        let client = s3.client().await;
+
+        let object = object_info(&client, "models", "model.gguf").await.unwrap();
+        assert_eq!(object.size, bytes.len() as u64);
+        assert_eq!(object.last_modified.as_deref(), Some("2025-01-01T00:00:00Z"));
+        assert_eq!(object.content_type.as_deref(), Some("application/octet-stream"));
+        assert_eq!(object.storage_class, "STANDARD");
+
+        let (gguf, header_bytes) = fetch_gguf_header(&client, "models", "model.gguf", object.size as i64).await.unwrap();
+        let report = json_report(&object, header_bytes, &gguf, None, ("f16", "f16"));
+        assert_eq!(report["architecture"], "llama");
+        assert_eq!(report["object"]["etag"], object.etag.unwrap());
+        assert_eq!(report["object"]["storage_class"], "STANDARD");
+    }
+}
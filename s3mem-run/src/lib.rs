@@ -0,0 +1,29 @@
+// s3mem-run as a library: download S3 objects in parallel into memory files
+// Loader is the entry point for other programs; the s3mem-run binary is a command-line
+// front end that adds program execution, supervision and hot swapping on top of it.
+pub mod child_output;                         // Structured capture of the program's output
+pub mod error;                                // Typed errors and exit codes
+pub mod exec;                                 // Building the program's command around the memory files
+pub mod gguf;                                 // GGUF header and metadata parsing
+pub mod hotswap;                              // Swap in new model versions behind a proxy
+pub mod inspect;                              // Remote GGUF inspection with range reads
+pub mod loader;                               // Parallel chunked download into memory files
+#[cfg(any(test, feature = "test-utils"))]
+pub mod local_s3;                             // Stand-in S3 endpoint for tests, with the test-utils feature
+pub mod logging;                              // Text, compact and JSON log output
+pub mod memfile;                              // Sealed memfd files and read-only mappings
+pub mod metrics;                              // CloudWatch Embedded Metric Format output
+pub mod planner;                              // How objects are split into ranged requests
+pub mod program;                              // PATH lookup and pre-flight checks of the program
+pub mod progress;                             // Download progress and its HTTP endpoint
+pub mod report;                               // JSON download timing report
+pub mod runtime_api;                          // Init error reporting to the Lambda Runtime API
+mod runtime_dir;                              // Private directory of named symlinks to memfds
+pub mod shards;                               // Split GGUF shard discovery
+pub mod supervisor;                           // Restart the program on crash instead of exec
+pub mod telemetry;                            // OTLP trace export and X-Ray propagation
+mod template;                                 // {{gguf:...}} placeholder expansion
+
+pub use loader::{ChunkSize, Loader, S3Object};  // The download builder and its options
+pub use memfile::{MemFile, Mmap};             // What a download produces
+pub use planner::{ChunkLimits, ChunkPlanner, ChunkStrategy};  // Chunk planning and its bounds
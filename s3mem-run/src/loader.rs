@@ -0,0 +1,1060 @@
+// Parallel download of S3 objects into memory files
+// Each object is split into byte ranges that are fetched concurrently with retries, checked
+// against the requested range and written straight to their offset in a memory file. The
+// Loader builder is the entry point for other programs; the CLI is built on it too.
+
+use crate::error::{fail, Error, ErrorKind};   // Typed errors
+use crate::gguf;                              // GGUF header validation
+use crate::memfile::MemFile;                  // Memory-backed files
+use crate::planner::{check_concurrency, ChunkLimits, ChunkPlanner, FixedSize, ObjectLayout, SizeScaled};  // Chunk plans
+use crate::progress::{DownloadProgress, EventCallback, ProgressEvent};  // Progress and its events
+use crate::telemetry;                         // X-Ray trace header on S3 requests
+use anyhow::{Context, Result};                // Error handling with context
+use aws_sdk_s3::Client;                       // AWS S3 client
+use libc::ftruncate;                          // Sizing memory files up front
+use std::ops::Range;                          // Planned byte ranges
+use std::sync::Arc;                           // Thread-safe reference counting
+use tokio::sync::Semaphore;                   // Async concurrency limiting
+use tracing::{debug, info, instrument, warn}; // Structured logging
+
+const MAX_CHUNK_ATTEMPTS: u32 = 4;                 // Attempts per chunk before giving up on the download
+const RETRY_BASE_DELAY_MS: u64 = 250;              // Initial retry delay, doubled after every failed attempt
+
+// ChunkCoverage tracks which planned chunks have been written to the memory file
+// The plan itself is checked to tile [0, total_size) with no gaps or overlaps, so
+// a full bitmap at the end proves every byte of the file was written exactly once
+struct ChunkCoverage {
+    ranges: Vec<(i64, i64)>,  // Planned (start, end) byte ranges, both inclusive
+    written: Vec<u64>,        // One bit per planned chunk, set once the chunk is written
+}
+
+impl ChunkCoverage {
+    // Build a coverage tracker for a chunk plan, rejecting plans that don't tile the file
+    fn new(ranges: Vec<(i64, i64)>, total_size: i64) -> Result<Self> {
+        let mut expected_start = 0i64;
+        for (index, &(start, end)) in ranges.iter().enumerate() {
+            if start != expected_start || end < start {
+                anyhow::bail!(
+                    "Chunk {} covers bytes {}-{} but bytes must continue from offset {}",
+                    index, start, end, expected_start
+                );
+            }
+            expected_start = end + 1;
+        }
+        if expected_start != total_size {
+            anyhow::bail!(
+                "Chunk plan covers {} bytes but the object is {} bytes",
+                expected_start, total_size
+            );
+        }
+
+        let words = ranges.len().div_ceil(64);
+        Ok(ChunkCoverage { ranges, written: vec![0; words] })
+    }
+
+    // Record that a chunk was written, failing if it was already written
+    // or if the written range doesn't match what was planned for it
+    fn mark_written(&mut self, index: usize, start: i64, len: usize) -> Result<()> {
+        let (planned_start, planned_end) = *self
+            .ranges
+            .get(index)
+            .with_context(|| format!("Chunk {} is not part of the download plan", index))?;
+        if start != planned_start || len as i64 != planned_end - planned_start + 1 {
+            fail!(
+                IncompleteDownload,
+                "Chunk {} wrote {} bytes at offset {} but bytes {}-{} were planned",
+                index, len, start, planned_start, planned_end
+            );
+        }
+
+        let (word, bit) = (index / 64, 1u64 << (index % 64));
+        if self.written[word] & bit != 0 {
+            fail!(IncompleteDownload, "Chunk {} (bytes {}-{}) was written twice", index, planned_start, planned_end);
+        }
+        self.written[word] |= bit;
+        Ok(())
+    }
+
+    // Verify that every planned chunk was written
+    fn verify_complete(&self) -> Result<()> {
+        let missing: Vec<usize> = (0..self.ranges.len())
+            .filter(|index| self.written[index / 64] & (1u64 << (index % 64)) == 0)
+            .collect();
+        if let Some(&first) = missing.first() {
+            let (start, end) = self.ranges[first];
+            fail!(
+                IncompleteDownload,
+                "{} of {} chunks were never written, first missing chunk {} covers bytes {}-{}",
+                missing.len(), self.ranges.len(), first, start, end
+            );
+        }
+        Ok(())
+    }
+}
+
+// Parse a Content-Range header of the form "bytes START-END/TOTAL" (TOTAL may be "*")
+// Returns the inclusive byte range and the total size when the server reported it
+fn parse_content_range(header: &str) -> Result<(i64, i64, Option<i64>)> {
+    let parse = || -> Option<(i64, i64, Option<i64>)> {
+        let spec = header.trim().strip_prefix("bytes ")?;
+        let (range, total) = spec.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        Some((start.trim().parse().ok()?, end.trim().parse().ok()?, total))
+    };
+    parse().with_context(|| format!("Malformed Content-Range header '{}'", header))
+}
+
+// Check a ranged GET response against the range that was requested
+// A response for the wrong range, for a different object size, or with a short body
+// must never be written into the memory file
+fn validate_chunk_response(
+    start: i64,
+    end: i64,
+    total_size: i64,
+    content_range: Option<&str>,
+    content_length: Option<i64>,
+    body_len: usize,
+) -> Result<()> {
+    let expected_len = end - start + 1;
+
+    let content_range = content_range.context("Response is missing the Content-Range header")?;
+    let (range_start, range_end, range_total) = parse_content_range(content_range)?;
+    if (range_start, range_end) != (start, end) {
+        fail!(
+            IncompleteDownload,
+            "Requested bytes {}-{} but the response covers bytes {}-{}",
+            start, end, range_start, range_end
+        );
+    }
+    if let Some(range_total) = range_total {
+        if range_total != total_size {
+            fail!(
+                IncompleteDownload,
+                "Object size changed during download: expected {} bytes, response reports {}",
+                total_size, range_total
+            );
+        }
+    }
+
+    if let Some(content_length) = content_length {
+        if content_length != expected_len {
+            fail!(
+                IncompleteDownload,
+                "Content-Length is {} but bytes {}-{} are {} bytes long",
+                content_length, start, end, expected_len
+            );
+        }
+    }
+
+    if body_len as i64 != expected_len {
+        fail!(
+            IncompleteDownload,
+            "Received {} bytes for bytes {}-{}, expected {}",
+            body_len, start, end, expected_len
+        );
+    }
+    Ok(())
+}
+
+#[instrument(skip(client))]
+// Download a single chunk of the file from S3
+// This function is called in parallel for different chunks of the file
+// The response is validated against the requested range before it is returned
+async fn download_chunk(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    start: i64,
+    end: i64,
+    total_size: i64,
+) -> Result<Chunk> {
+    // Format the byte range header for the S3 request
+    let range = format!("bytes={}-{}", start, end);
+    debug!(range, "Downloading chunk");
+
+    // Make the S3 GetObject request with the byte range
+    let started = std::time::Instant::now();
+    let trace_header = telemetry::current_trace_header();
+    let resp = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .range(range)
+        .customize()
+        .mutate_request(move |request| {
+            // S3 requests appear in the X-Ray trace under this chunk's span
+            if let Some(header) = &trace_header {
+                request.headers_mut().insert(telemetry::TRACE_HEADER, header.clone());
+            }
+        })
+        .send()
+        .await
+        .context("Failed to get object from S3")?;
+    let ttfb = started.elapsed();
+
+    // Keep the range headers so the body can be checked against them
+    let content_range = resp.content_range().map(str::to_string);
+    let content_length = resp.content_length();
+
+    // Collect the streaming response body into a byte vector
+    let data = resp
+        .body
+        .collect()
+        .await
+        .context("Failed to collect response body")?;
+    
+    // Convert to a standard Vec<u8> and make sure it is exactly the requested range
+    let bytes = data.to_vec();
+    let chunk_size = bytes.len();
+    validate_chunk_response(
+        start,
+        end,
+        total_size,
+        content_range.as_deref(),
+        content_length,
+        chunk_size,
+    )?;
+    debug!(bytes = chunk_size, offset = start, "Chunk downloaded successfully");
+    
+    // Return both the data and the offset where it should be written
+    Ok(Chunk { data: bytes, offset: start as u64, ttfb, attempts: 1 })
+}
+
+// A downloaded chunk, where it belongs and how long its response took to start
+pub struct Chunk {
+    pub data: Vec<u8>,                // The chunk's bytes
+    pub offset: u64,                  // Offset of the chunk in the file
+    pub ttfb: std::time::Duration,    // Time to the response headers of the successful attempt
+    pub attempts: u32,                // Requests made, including retries
+}
+
+// A chunk that failed, with the number of attempts made before giving up
+#[derive(Debug)]
+pub struct ChunkError {
+    pub error: anyhow::Error,         // The error of the last attempt
+    pub attempts: u32,                // Requests made, including retries
+}
+
+impl From<ChunkError> for anyhow::Error {
+    fn from(err: ChunkError) -> Self {
+        err.error
+    }
+}
+
+// Whether another attempt can succeed; a missing object or denied access won't change
+fn is_retryable(err: &anyhow::Error) -> bool {
+    !matches!(ErrorKind::of(err), ErrorKind::AccessDenied | ErrorKind::NotFound)
+}
+
+#[instrument(skip(client, retried))]
+// Download a chunk, retrying with exponential backoff on failures and invalid responses
+// Short bodies and mismatched ranges are usually transient, so they get the same retries
+// as network errors before the whole download is failed
+// `retried` is called with the failed attempt, the delay before the next one and the error
+pub async fn download_chunk_with_retry(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    start: i64,
+    end: i64,
+    total_size: i64,
+    retried: &(dyn Fn(u32, std::time::Duration, &anyhow::Error) + Sync),
+) -> Result<Chunk, ChunkError> {
+    let mut attempt = 1;
+    loop {
+        match download_chunk(client, bucket, key, start, end, total_size).await {
+            Ok(chunk) => return Ok(Chunk { attempts: attempt, ..chunk }),
+            Err(err) if attempt < MAX_CHUNK_ATTEMPTS && is_retryable(&err) => {
+                let delay_ms = RETRY_BASE_DELAY_MS << (attempt - 1);
+                warn!(
+                    attempt,
+                    max_attempts = MAX_CHUNK_ATTEMPTS,
+                    delay_ms,
+                    error = format!("{:#}", err),
+                    "Chunk download failed, retrying"
+                );
+                let delay = std::time::Duration::from_millis(delay_ms);
+                retried(attempt, delay, &err);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                let error = err.context(format!("Bytes {}-{} failed after {} attempts", start, end, attempt));
+                return Err(ChunkError { error, attempts: attempt });
+            }
+        }
+    }
+}
+
+#[instrument(skip(client))]
+// Get the ETags of S3 objects, joined into one version string
+pub async fn object_version(client: &Client, bucket: &str, keys: &[String]) -> Result<String> {
+    let etags = futures::future::try_join_all(keys.iter().map(|key| async move {
+        let head_object = client
+            .head_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .with_context(|| format!("Failed to get metadata of s3://{}/{}", bucket, key))?;
+        head_object.e_tag.context("ETag not available")
+    }))
+    .await?;
+    Ok(etags.join(","))
+}
+
+#[instrument(skip(client))]
+// Get the size of an S3 object from its metadata
+pub async fn object_size(client: &Client, bucket: &str, key: &str) -> Result<i64> {
+    info!("Getting object metadata from S3");
+    let head_object = client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context("Failed to get object metadata from S3")?;
+
+    // Extract the total file size from the metadata
+    head_object
+        .content_length
+        .context("Content length not available")
+}
+
+#[instrument(skip(client))]
+// Get the part size of an object uploaded in several parts, from a HEAD of its first part
+pub async fn object_part_size(client: &Client, bucket: &str, key: &str) -> Result<Option<u64>> {
+    let head_part = client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .part_number(1)
+        .send()
+        .await
+        .context("Failed to get the first part's metadata from S3")?;
+    Ok(match head_part.parts_count {
+        Some(parts) if parts > 1 => head_part.content_length.map(|length| length as u64),
+        _ => None,
+    })
+}
+
+#[instrument(skip_all, fields(bucket = %object.bucket, key = %object.key, total_size, executable = object.executable))]
+// Download a file from S3 in parallel chunks directly into memory
+// This is the main function that orchestrates the parallel download process
+// Chunk downloads take permits from `semaphore`, which may be shared by several files
+// Unless `keep` is set, the chunks are checked and counted but not written anywhere
+async fn parallel_download_to_memfd(
+    object: &S3Object,
+    client: &Client,
+    total_size: i64,
+    plan: Vec<Range<u64>>,
+    semaphore: Arc<Semaphore>,
+    progress: Arc<DownloadProgress>,
+    keep: bool,
+) -> Result<Option<MemFile>> {
+    // Log the download parameters for monitoring and debugging
+    let chunk_size = largest_chunk(&plan) as i64;
+    info!(
+        file_size_bytes = total_size,
+        file_size_mb = total_size / (1024 * 1024),
+        chunk_size_bytes = chunk_size,
+        chunk_size_mb = chunk_size / (1024 * 1024),
+        "Download parameters calculated"
+    );
+
+    // Create a memory file to hold the downloaded data
+    let mut memfile = if keep {
+        debug!("Creating memory file");
+        let memfile = if object.executable {
+            MemFile::new_executable("s3_program")?
+        } else {
+            MemFile::new("s3_file")?
+        };
+
+        // Pre-allocate the full file size in memory to avoid resizing during writes
+        if unsafe { ftruncate(memfile.fd(), total_size) } == -1 {
+            return Err(std::io::Error::last_os_error()).context("Failed to set file size");
+        }
+        Some(memfile)
+    } else {
+        None
+    };
+
+    let mut tasks = Vec::new();
+
+    // Chunk boundaries are planned up front so coverage can be verified at the end
+    // The planner's half-open ranges become the inclusive ones of HTTP Range headers
+    let ranges: Vec<(i64, i64)> = plan.iter().map(|range| (range.start as i64, range.end as i64 - 1)).collect();
+    let total_chunks = ranges.len();
+    let mut coverage = ChunkCoverage::new(ranges.clone(), total_size)?;
+    progress.emit(ProgressEvent::Planned {
+        key: object.key.clone(),
+        size: total_size as u64,
+        chunk_size: chunk_size as u64,
+        chunks: total_chunks,
+    });
+    
+    info!(total_chunks, "Starting parallel download");
+    
+    // Spawn tasks for each chunk
+    for (chunk_index, &(start, end)) in ranges.iter().enumerate() {
+        let chunk_count = chunk_index + 1;
+        
+        // Clone references for the async task
+        let client = client.clone();
+        let bucket = object.bucket.clone();
+        let key = object.key.clone();
+        let progress = progress.clone();
+        
+        // Acquire a permit from the semaphore to limit concurrency
+        let permit = semaphore.clone().acquire_owned().await?;
+        
+        debug!(
+            chunk_number = chunk_count,
+            total_chunks = total_chunks,
+            start_byte = start,
+            end_byte = end,
+            "Scheduling chunk download"
+        );
+
+        // Spawn an async task to download this chunk
+        let task = tokio::spawn(async move {
+            // Download the chunk and release the semaphore permit when done
+            let started = std::time::Instant::now();
+            progress.emit(ProgressEvent::ChunkStarted {
+                key: key.clone(),
+                chunk: chunk_index,
+                start: start as u64,
+                end: end as u64,
+            });
+            let retried = |attempt, delay, err: &anyhow::Error| {
+                progress.emit(ProgressEvent::ChunkRetried {
+                    key: key.clone(),
+                    chunk: chunk_index,
+                    attempt,
+                    delay,
+                    error: format!("{:#}", err),
+                });
+            };
+            let result =
+                download_chunk_with_retry(&client, &bucket, &key, start, end, total_size, &retried).await;
+            drop(permit);
+            match &result {
+                Ok(chunk) => {
+                    progress.record_chunk(chunk.data.len() as u64, chunk.ttfb, started.elapsed(), chunk.attempts);
+                    progress.emit(ProgressEvent::ChunkCompleted {
+                        key,
+                        chunk: chunk_index,
+                        bytes: chunk.data.len() as u64,
+                        attempts: chunk.attempts,
+                        progress: progress.snapshot(),
+                    });
+                }
+                Err(err) => progress.emit(ProgressEvent::ChunkFailed {
+                    key,
+                    chunk: chunk_index,
+                    attempts: err.attempts,
+                    error: format!("{:#}", err.error),
+                }),
+            }
+            result.map_err(anyhow::Error::from)
+        });
+
+        tasks.push(task);
+    }
+
+    info!(total_chunks = tasks.len(), "All chunks scheduled, waiting for completion");
+    
+    // Wait for all download tasks to complete and write their data to the memory file
+    let mut completed_chunks = 0;
+    for (chunk_index, task) in tasks.into_iter().enumerate() {
+        completed_chunks += 1;
+        // Await the task completion and extract the data and offset
+        let Chunk { data, offset, .. } = task
+            .await
+            .context("Task join failed")?
+            .context("Chunk download failed")?;
+            
+        debug!(
+            completed = completed_chunks,
+            total = total_chunks,
+            progress_percent = (completed_chunks as f64 / total_chunks as f64 * 100.0) as u32,
+            "Writing chunk to memory file"
+        );
+        
+        // Record the chunk in the coverage bitmap, then write it at the correct offset
+        coverage.mark_written(chunk_index, offset as i64, data.len())?;
+        if let Some(memfile) = &mut memfile {
+            memfile.write_at(&data, offset)?;
+        }
+        
+        // Chunks are written in order, so this trails the download; progress events don't
+        if completed_chunks % 10 == 0 || completed_chunks == total_chunks {
+            debug!(
+                completed_chunks,
+                total_chunks,
+                progress_percent = (completed_chunks as f64 / total_chunks as f64 * 100.0) as u32,
+                "Download progress"
+            );
+        }
+    }
+
+    // Prove that every byte of the file was written exactly once before handing it out
+    coverage
+        .verify_complete()
+        .context("Downloaded file is incomplete")?;
+
+    info!("Download completed successfully");
+    Ok(memfile)
+}
+
+// An S3 object to download into its own memory file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Object {
+    pub bucket: String,          // Bucket containing the object
+    pub key: String,             // Key of the object
+    pub executable: bool,        // The memory file will be executed, so it must allow exec
+    pub sha256: Option<String>,  // Expected SHA-256 of the object, as hex
+}
+
+impl S3Object {
+    pub fn new(bucket: impl Into<String>, key: impl Into<String>) -> Self {
+        S3Object { bucket: bucket.into(), key: key.into(), executable: false, sha256: None }
+    }
+
+    // Download into a memory file that can be executed
+    pub fn executable(mut self) -> Self {
+        self.executable = true;
+        self
+    }
+
+    // Fail the download unless the object has this SHA-256
+    pub fn sha256(mut self, hex: impl Into<String>) -> Self {
+        self.sha256 = Some(hex.into());
+        self
+    }
+}
+
+// How objects are split into ranged GET requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkSize {
+    #[default]
+    Auto,        // Scaled with the object size, aiming for ~75 chunks of 4MB to 128MB
+    Fixed(u64),  // The same number of bytes per request for every object
+}
+
+impl ChunkSize {
+    fn planner(self) -> Arc<dyn ChunkPlanner> {
+        match self {
+            ChunkSize::Auto => Arc::new(SizeScaled),
+            ChunkSize::Fixed(bytes) => Arc::new(FixedSize(bytes)),
+        }
+    }
+}
+
+// The longest range of a plan, which is the chunk size of all but the variable plans
+fn largest_chunk(plan: &[Range<u64>]) -> u64 {
+    plan.iter().map(|range| range.end - range.start).max().unwrap_or(0)
+}
+
+// Builder for downloading S3 objects into sealed memory files
+//
+//     let model = Loader::new(client)
+//         .source("models", "llama.gguf")
+//         .sha256("9f86d08...")
+//         .concurrency(8)
+//         .on_progress(|event| eprintln!("{:?}", event))
+//         .load()
+//         .await?;
+//     let bytes = model.mmap()?;
+pub struct Loader {
+    client: Client,
+    objects: Vec<S3Object>,               // Objects to download, in the order of the result
+    planner: Arc<dyn ChunkPlanner>,       // Splits each object into ranged requests
+    limits: ChunkLimits,                  // Bounds of the size-scaled chunk sizes and concurrency
+    concurrency: Option<usize>,           // Parallel requests, scaled with the combined size if unset
+    verify_gguf: bool,                    // Validate every non-executable object as a GGUF model
+    progress: Arc<DownloadProgress>,      // Bytes downloaded and request timings
+    callbacks: Vec<EventCallback>,        // Registered with the progress when the download starts
+    sha256_without_source: bool,          // sha256 was called before any source, failing the download
+}
+
+impl Loader {
+    pub fn new(client: Client) -> Self {
+        Loader {
+            client,
+            objects: Vec::new(),
+            planner: Arc::new(SizeScaled),
+            limits: ChunkLimits::default(),
+            concurrency: None,
+            verify_gguf: false,
+            progress: Arc::new(DownloadProgress::new()),
+            callbacks: Vec::new(),
+            sha256_without_source: false,
+        }
+    }
+
+    // Add an object to download
+    pub fn source(self, bucket: impl Into<String>, key: impl Into<String>) -> Self {
+        self.object(S3Object::new(bucket, key))
+    }
+
+    // Add an object to download, with its options
+    pub fn object(mut self, object: S3Object) -> Self {
+        self.objects.push(object);
+        self
+    }
+
+    // Expect this SHA-256 for the object added last
+    // Without one, the download fails with a configuration error
+    pub fn sha256(mut self, hex: impl Into<String>) -> Self {
+        match self.objects.pop() {
+            Some(object) => self.object(object.sha256(hex)),
+            None => {
+                self.sha256_without_source = true;
+                self
+            }
+        }
+    }
+
+    pub fn chunk_size(mut self, chunk_size: ChunkSize) -> Self {
+        self.planner = chunk_size.planner();
+        self
+    }
+
+    // Split objects into ranged requests with this planner, such as planner::Adaptive
+    pub fn planner(mut self, planner: Arc<dyn ChunkPlanner>) -> Self {
+        self.planner = planner;
+        self
+    }
+
+    // Bounds of the chunk sizes and concurrency that are scaled with the object size
+    pub fn limits(mut self, limits: ChunkLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Parallel requests shared by all objects
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    // Check that every non-executable object is a well-formed GGUF model
+    pub fn verify_gguf(mut self, verify: bool) -> Self {
+        self.verify_gguf = verify;
+        self
+    }
+
+    // Record progress and request timings in a tracker shared with the caller
+    pub fn progress(mut self, progress: Arc<DownloadProgress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    // Call `callback` with every progress event of the download
+    pub fn on_progress(mut self, callback: impl Fn(&ProgressEvent) + Send + Sync + 'static) -> Self {
+        self.callbacks.push(Arc::new(callback));
+        self
+    }
+
+    // Receive the progress events of the download on a channel
+    // Like `on_progress`, the channel is attached to whichever tracker the download uses
+    pub fn events(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<ProgressEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.callbacks.push(Arc::new(move |event: &ProgressEvent| {
+            let _ = sender.send(event.clone());
+        }));
+        receiver
+    }
+
+    // Download the single object that was added
+    pub async fn load(self) -> Result<MemFile> {
+        if self.objects.len() != 1 {
+            fail!(InvalidConfiguration, "Loader::load needs exactly one source, {} were added", self.objects.len());
+        }
+        Ok(self.load_all().await?.remove(0))
+    }
+
+    #[instrument(skip_all, fields(files = self.objects.len()))]
+    // Download every object into its own memory file under a single concurrency budget
+    // Concurrency is sized from the combined size, so shards of a split model share the
+    // same number of parallel requests a single file of that size would get
+    pub async fn load_all(self) -> Result<Vec<MemFile>> {
+        let (objects, verify_gguf) = (self.objects.clone(), self.verify_gguf);
+        let memfiles: Vec<MemFile> = self.download(true).await?.into_iter().flatten().collect();
+
+        // The files never change from here on, which also makes mapping them safe
+        for (memfile, object) in memfiles.iter().zip(&objects) {
+            memfile.seal()?;
+            if let Some(expected) = &object.sha256 {
+                verify_sha256(memfile, expected).with_context(|| {
+                    format!("s3://{}/{} failed checksum verification", object.bucket, object.key)
+                })?;
+                info!(key = object.key, "Checksum verified");
+            }
+            if verify_gguf && !object.executable {
+                verify_gguf_file(memfile).with_context(|| {
+                    Error::new(
+                        ErrorKind::InvalidModel,
+                        format!("s3://{}/{} is not a valid GGUF file", object.bucket, object.key),
+                    )
+                })?;
+            }
+        }
+        Ok(memfiles)
+    }
+
+    #[instrument(skip_all, fields(files = self.objects.len()))]
+    // Download every object the way load_all does, but throw the bytes away as they arrive
+    // Every chunk is still checked, so this measures the download without needing the
+    // memory to hold it; returns the number of bytes downloaded
+    pub async fn discard_all(self) -> Result<u64> {
+        if self.verify_gguf || self.objects.iter().any(|object| object.sha256.is_some()) {
+            fail!(InvalidConfiguration, "Downloads that are discarded can't be verified");
+        }
+        let progress = self.progress.clone();
+        self.download(false).await?;
+        Ok(progress.snapshot().bytes_done)
+    }
+
+    // Download every object, into memory files if `keep` is set
+    async fn download(self, keep: bool) -> Result<Vec<Option<MemFile>>> {
+        if self.sha256_without_source {
+            fail!(InvalidConfiguration, "Loader::sha256 needs a source added before it");
+        }
+        if let Some(concurrency) = self.concurrency {
+            check_concurrency(concurrency)?;
+        }
+        self.planner.validate()?;
+        self.limits.validate()?;
+        let Loader { client, objects, planner, limits, concurrency, progress, callbacks, .. } = self;
+        for callback in callbacks {
+            progress.on_event(callback);
+        }
+
+        // Plan each object's chunks as soon as its size, and part size if needed, are known
+        let plans = futures::future::try_join_all(objects.iter().map(|object| {
+            let (client, progress, planner) = (&client, progress.clone(), &planner);
+            async move {
+                let started = std::time::Instant::now();
+                let size = object_size(client, &object.bucket, &object.key).await?;
+                let head_latency = started.elapsed();
+                let part_size = match planner.needs_part_size() {
+                    true => object_part_size(client, &object.bucket, &object.key).await?,
+                    false => None,
+                };
+                let plan = planner.checked_plan(&ObjectLayout { size: size as u64, part_size }, &limits)?;
+                progress.record_head(&object.key, size as u64, largest_chunk(&plan), head_latency);
+                Ok::<_, anyhow::Error>((size, plan))
+            }
+        }))
+        .await?;
+        let total_size: i64 = plans.iter().map(|(size, _)| size).sum();
+        progress.set_total(total_size as u64);
+
+        // Calculate optimal concurrency based on the combined size, unless it was given
+        let concurrent_downloads = concurrency.unwrap_or_else(|| limits.concurrency(total_size as u64));
+        progress.record_concurrency(concurrent_downloads);
+        info!(
+            files = objects.len(),
+            total_size_bytes = total_size,
+            concurrent_downloads,
+            "Concurrency calculated"
+        );
+
+        // Create a semaphore to limit concurrent downloads across all files
+        let semaphore = Arc::new(Semaphore::new(concurrent_downloads));
+        let memfiles = futures::future::try_join_all(objects.iter().zip(plans).map(|(object, (size, plan))| {
+            parallel_download_to_memfd(
+                object,
+                &client,
+                size,
+                plan,
+                semaphore.clone(),
+                progress.clone(),
+                keep,
+            )
+        }))
+        .await?;
+        progress.finish();
+        Ok(memfiles)
+    }
+}
+
+// Compute the SHA-256 of a memory file and compare it to the expected hex digest
+pub fn verify_sha256(memfile: &MemFile, expected: &str) -> Result<()> {
+    use sha2::{Digest, Sha256};
+    use std::os::unix::fs::FileExt;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 8 * 1024 * 1024];
+    let mut offset = 0u64;
+    loop {
+        let read = memfile
+            .file()
+            .read_at(&mut buf, offset)
+            .context("Failed to read memfd for checksum")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        offset += read as u64;
+    }
+
+    let actual = format!("{:x}", hasher.finalize());
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        fail!(ChecksumMismatch, "Checksum mismatch: expected SHA-256 {} but got {}", expected.trim(), actual);
+    }
+    Ok(())
+}
+
+#[instrument(skip(memfile))]
+// Parse the GGUF header of a downloaded file and check that it describes the file correctly
+pub fn verify_gguf_file(memfile: &MemFile) -> Result<gguf::GgufFile> {
+    let file_size = memfile.size()?;
+    let gguf = gguf::read_from_file(memfile.file(), file_size)?;
+
+    info!(
+        version = gguf.version,
+        architecture = gguf.architecture().unwrap_or("unknown"),
+        metadata_entries = gguf.metadata.len(),
+        tensors = gguf.tensors.len(),
+        data_offset = gguf.data_offset,
+        "GGUF file verified"
+    );
+    Ok(gguf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::tests::{build_gguf, string_value};
+    use crate::local_s3::LocalS3;
+
+    #[test]
+    fn test_validate_chunk_response() {
+        // A response matching the requested range is accepted
+        assert!(validate_chunk_response(0, 99, 1000, Some("bytes 0-99/1000"), Some(100), 100).is_ok());
+        assert!(validate_chunk_response(900, 999, 1000, Some("bytes 900-999/*"), None, 100).is_ok());
+
+        // Short bodies, misrouted ranges and size changes are all rejected
+        assert!(validate_chunk_response(0, 99, 1000, Some("bytes 0-99/1000"), Some(100), 60).is_err());
+        assert!(validate_chunk_response(0, 99, 1000, Some("bytes 100-199/1000"), Some(100), 100).is_err());
+        assert!(validate_chunk_response(0, 99, 1000, Some("bytes 0-99/2000"), Some(100), 100).is_err());
+        assert!(validate_chunk_response(0, 99, 1000, Some("bytes 0-99/1000"), Some(60), 100).is_err());
+        assert!(validate_chunk_response(0, 99, 1000, None, Some(100), 100).is_err());
+        assert!(validate_chunk_response(0, 99, 1000, Some("0-99/1000"), Some(100), 100).is_err());
+    }
+
+    #[test]
+    fn test_chunk_coverage() {
+        // Plans with gaps, overlaps or the wrong total are rejected
+        assert!(ChunkCoverage::new(vec![(0, 49), (51, 99)], 100).is_err());
+        assert!(ChunkCoverage::new(vec![(0, 49), (40, 99)], 100).is_err());
+        assert!(ChunkCoverage::new(vec![(0, 49)], 100).is_err());
+
+        let mut coverage = ChunkCoverage::new(vec![(0, 49), (50, 99)], 100).unwrap();
+        coverage.mark_written(0, 0, 50).unwrap();
+        assert!(coverage.verify_complete().is_err());
+
+        // Writing a chunk twice, or with the wrong length, is an error
+        assert!(coverage.mark_written(0, 0, 50).is_err());
+        assert!(coverage.mark_written(1, 50, 10).is_err());
+
+        coverage.mark_written(1, 50, 50).unwrap();
+        coverage.verify_complete().unwrap();
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let mut memfile = MemFile::new_executable("test_program").unwrap();
+        memfile.write_at(b"abc", 0).unwrap();
+
+        // SHA-256 of "abc"
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        verify_sha256(&memfile, expected).unwrap();
+        verify_sha256(&memfile, &expected.to_uppercase()).unwrap();
+
+        let err = verify_sha256(&memfile, &"0".repeat(64)).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+    }
+
+    const CHUNK: usize = 64 * 1024;           // The smallest chunk the planners allow
+
+    // Every byte of the object, in an order where a misplaced chunk would show
+    fn test_object(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loader_downloads_into_sealed_memfile() {
+        let data = test_object(10 * CHUNK);
+        let s3 = LocalS3::start(vec![("models", "model.bin", data.clone())]).await.unwrap();
+        let completed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let progress = Arc::new(DownloadProgress::new());
+
+        let mut loader = Loader::new(s3.client().await)
+            .source("models", "model.bin")
+            .chunk_size(ChunkSize::Fixed(CHUNK as u64))
+            .concurrency(3);
+
+        // The channel follows the loader to a tracker given after it
+        let mut events = loader.events();
+        let loader = loader
+            .progress(progress.clone())
+            .on_progress({
+                let completed = completed.clone();
+                move |event| {
+                    if let ProgressEvent::ChunkCompleted { .. } = event {
+                        completed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+            });
+        let memfile = loader.load().await.unwrap();
+        assert_eq!(memfile.size().unwrap(), 10 * CHUNK as u64);
+        assert!(memfile.is_sealed());
+        assert_eq!(&memfile.mmap().unwrap()[..], &data[..]);
+        assert_eq!(std::fs::read(memfile.path()).unwrap(), data);
+
+        // The callback and the channel both saw every chunk, and the report did too
+        assert_eq!(completed.load(std::sync::atomic::Ordering::Relaxed), 10);
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received[0],
+            ProgressEvent::Planned { key: "model.bin".to_string(), size: 10 * CHUNK as u64, chunk_size: CHUNK as u64, chunks: 10 }
+        );
+        let started = received.iter().filter(|event| matches!(event, ProgressEvent::ChunkStarted { .. })).count();
+        assert_eq!(started, 10);
+        let Some(ProgressEvent::ChunkCompleted { progress: last, .. }) = received.last() else {
+            panic!("the last event is not a completed chunk: {:?}", received.last());
+        };
+        assert_eq!((last.bytes_done, last.bytes_total), (10 * CHUNK as u64, 10 * CHUNK as u64));
+        let snapshot = progress.snapshot();
+        assert!(snapshot.downloaded);
+        assert_eq!((snapshot.bytes_done, snapshot.bytes_total), (10 * CHUNK as u64, 10 * CHUNK as u64));
+        assert_eq!(progress.report("models")["chunks"]["count"], 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loader_discards() {
+        let s3 = LocalS3::start(vec![("models", "model.bin", test_object(10 * CHUNK))]).await.unwrap();
+        let client = s3.client().await;
+        let progress = Arc::new(DownloadProgress::new());
+
+        let bytes = Loader::new(client.clone())
+            .source("models", "model.bin")
+            .chunk_size(ChunkSize::Fixed(3 * CHUNK as u64))
+            .progress(progress.clone())
+            .discard_all()
+            .await
+            .unwrap();
+        assert_eq!(bytes, 10 * CHUNK as u64);
+        assert_eq!(progress.report("models")["chunks"]["count"], 4);
+
+        // There is nothing left to check a checksum against
+        let err = Loader::new(client).source("models", "model.bin").sha256("00").discard_all().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidConfiguration);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loader_integrity_options() {
+        let gguf = build_gguf(&[("general.architecture", 8, string_value("llama"))], &[], 0);
+        let s3 = LocalS3::start(vec![
+            ("models", "abc", b"abc".to_vec()),
+            ("models", "model.gguf", gguf),
+            ("models", "not-gguf", test_object(100)),
+        ])
+        .await
+        .unwrap();
+        let client = s3.client().await;
+
+        // SHA-256 of "abc"; an executable object is not a model, so it isn't checked as GGUF
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let memfiles = Loader::new(client.clone())
+            .source("models", "model.gguf")
+            .object(S3Object::new("models", "abc").executable().sha256(sha256))
+            .verify_gguf(true)
+            .load_all()
+            .await
+            .unwrap();
+        assert_eq!(memfiles.len(), 2);
+
+        let err = Loader::new(client.clone()).source("models", "abc").sha256("0".repeat(64)).load().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ChecksumMismatch);
+        assert!(err.to_string().contains("s3://models/abc failed checksum verification"));
+
+        let err = Loader::new(client.clone()).source("models", "not-gguf").verify_gguf(true).load().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidModel);
+
+        let err = Loader::new(client.clone()).source("models", "missing").load().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::NotFound);
+
+        let err = Loader::new(client.clone()).load().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidConfiguration);
+
+        // A checksum with no object to apply to is reported by the download, not a panic
+        let err = Loader::new(client).sha256(sha256).source("models", "abc").load().await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidConfiguration);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loader_reports_retries() {
+        let data = test_object(3 * CHUNK);
+        let s3 = LocalS3::start(vec![("models", "model.bin", data.clone())]).await.unwrap();
+        s3.inject_faults(1);
+
+        let mut loader = Loader::new(s3.client().await)
+            .source("models", "model.bin")
+            .chunk_size(ChunkSize::Fixed(CHUNK as u64))
+            .concurrency(1);
+        let mut events = loader.events();
+        let memfile = loader.load().await.unwrap();
+        assert_eq!(&memfile.mmap().unwrap()[..], &data[..]);
+
+        let mut retried = Vec::new();
+        let mut attempts = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                ProgressEvent::ChunkRetried { chunk, attempt, error, .. } => retried.push((chunk, attempt, error)),
+                ProgressEvent::ChunkCompleted { attempts: count, .. } => attempts.push(count),
+                _ => {}
+            }
+        }
+        assert_eq!(retried.len(), 1);
+        assert_eq!((retried[0].0, retried[0].1), (0, 1));
+        assert!(retried[0].2.contains("Requested bytes 0-65535"), "{}", retried[0].2);
+        attempts.sort();
+        assert_eq!(attempts, [1, 1, 2]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_error_attempts() {
+        let s3 = LocalS3::start(vec![("models", "model.bin", test_object(CHUNK))]).await.unwrap();
+        let client = s3.client().await;
+        let end = CHUNK as i64 - 1;
+
+        // A missing object fails on the first attempt, without retries
+        let err = download_chunk_with_retry(&client, "models", "missing", 0, end, CHUNK as i64, &|_, _, _| {})
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.attempts, 1);
+        assert_eq!(ErrorKind::of(&err.error), ErrorKind::NotFound);
+
+        // Invalid responses are retried until the attempts run out
+        s3.inject_faults(MAX_CHUNK_ATTEMPTS as usize);
+        let err = download_chunk_with_retry(&client, "models", "model.bin", 0, end, CHUNK as i64, &|_, _, _| {})
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.attempts, MAX_CHUNK_ATTEMPTS);
+    }
+}
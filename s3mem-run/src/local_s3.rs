@@ -0,0 +1,277 @@
+// A stand-in S3 endpoint on localhost that serves objects from memory
+// It answers the path-style HEAD and ranged GET requests the loader makes, which is enough
+// to run whole downloads through the SDK without AWS, plus HEAD on a bucket. Latency and
+// bandwidth limits can be injected to approximate a real network, for benchmarks that
+// must be reproducible.
+
+use anyhow::{Context, Result};                // Error handling with context
+use aws_config::BehaviorVersion;              // SDK configuration
+use aws_sdk_s3::Client;                       // Client pointed at the stand-in
+use std::collections::HashMap;                // Objects by bucket and key
+use std::sync::atomic::{AtomicUsize, Ordering};  // Injected faults
+use std::sync::{Arc, Mutex};                  // Objects and pacing shared with connection tasks
+use std::time::Duration;                      // Latency and pacing
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};  // HTTP/1.1 over TCP
+use tokio::net::{TcpListener, TcpStream};     // The endpoint's sockets
+use tokio::time::Instant;                     // Pacing deadlines
+
+type Objects = Arc<HashMap<(String, String), Vec<u8>>>;
+
+const LAST_MODIFIED: &str = "Wed, 01 Jan 2025 00:00:00 GMT";  // Of every object
+const PACED_WRITE: usize = 64 * 1024;         // Bytes written at a time under a bandwidth limit
+const REGION: &str = "us-east-1";             // Of every bucket, and of the client
+
+// The network the stand-in pretends to be behind; the default is as fast as localhost
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    pub latency: Duration,                    // Added before every response
+    pub bandwidth: Option<u64>,               // Bytes per second shared by all connections
+    pub connection_bandwidth: Option<u64>,    // Bytes per second of each connection
+}
+
+// A running stand-in endpoint, stopped when dropped
+pub struct LocalS3 {
+    endpoint: String,
+    faults: Arc<AtomicUsize>,             // Ranged GETs still to answer with the wrong range
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl LocalS3 {
+    // Serve `objects`, given as (bucket, key, contents)
+    pub async fn start(objects: Vec<(&str, &str, Vec<u8>)>) -> Result<Self> {
+        Self::start_with(objects, NetworkConditions::default()).await
+    }
+
+    // Serve `objects` as if over a network with these conditions
+    pub async fn start_with(objects: Vec<(&str, &str, Vec<u8>)>, network: NetworkConditions) -> Result<Self> {
+        let objects: Objects = Arc::new(
+            objects
+                .into_iter()
+                .map(|(bucket, key, data)| ((bucket.to_string(), key.to_string()), data))
+                .collect(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.context("Failed to start the local S3 endpoint")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let faults = Arc::new(AtomicUsize::new(0));
+        let link = network.bandwidth.map(|rate| Arc::new(Pacer::new(rate)));
+        let server = tokio::spawn({
+            let faults = faults.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let pacers = Pacers {
+                        link: link.clone(),
+                        connection: network.connection_bandwidth.map(Pacer::new),
+                    };
+                    tokio::spawn(serve_connection(stream, objects.clone(), faults.clone(), network.latency, pacers));
+                }
+            }
+        });
+        Ok(LocalS3 { endpoint, faults, server })
+    }
+
+    // Answer the next `count` ranged GETs with the first byte of the object instead
+    pub fn inject_faults(&self, count: usize) {
+        self.faults.store(count, Ordering::SeqCst);
+    }
+
+    // A client with static credentials that sends every request here
+    pub async fn client(&self) -> Client {
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .region(aws_config::Region::new(REGION))
+            .credentials_provider(aws_sdk_s3::config::Credentials::new("test", "test", None, None, "local"))
+            .endpoint_url(&self.endpoint)
+            .load()
+            .await;
+        Client::from_conf(aws_sdk_s3::config::Builder::from(&config).force_path_style(true).build())
+    }
+}
+
+impl Drop for LocalS3 {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+// Spaces out writes to stay under a rate, in bytes per second
+// Each write reserves the next slot on the timeline, so writers that share a pacer split
+// its rate between them
+struct Pacer {
+    rate: f64,
+    next: Mutex<Instant>,                 // When the bytes reserved so far have gone out
+}
+
+impl Pacer {
+    fn new(rate: u64) -> Self {
+        Pacer { rate: rate.max(1) as f64, next: Mutex::new(Instant::now()) }
+    }
+
+    // Reserve time for `bytes` and return when they are done
+    fn reserve(&self, bytes: usize) -> Instant {
+        let mut next = self.next.lock().unwrap();
+        *next = (*next).max(Instant::now()) + Duration::from_secs_f64(bytes as f64 / self.rate);
+        *next
+    }
+}
+
+// The limits a connection's writes are held to
+struct Pacers {
+    link: Option<Arc<Pacer>>,             // Shared by every connection
+    connection: Option<Pacer>,            // This connection's own
+}
+
+impl Pacers {
+    async fn write(&self, writer: &mut (impl AsyncWriteExt + Unpin), body: &[u8]) -> std::io::Result<()> {
+        if self.link.is_none() && self.connection.is_none() {
+            return writer.write_all(body).await;
+        }
+        for block in body.chunks(PACED_WRITE) {
+            let link = self.link.as_ref().map(|pacer| pacer.reserve(block.len()));
+            let connection = self.connection.as_ref().map(|pacer| pacer.reserve(block.len()));
+            if let Some(deadline) = link.into_iter().chain(connection).max() {
+                tokio::time::sleep_until(deadline).await;
+            }
+            writer.write_all(block).await?;
+        }
+        Ok(())
+    }
+}
+
+// Answer requests on one keep-alive connection until the client closes it
+async fn serve_connection(stream: TcpStream, objects: Objects, faults: Arc<AtomicUsize>, latency: Duration, pacers: Pacers) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut range = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("range") {
+                    range = value.trim().strip_prefix("bytes=").map(str::to_string);
+                }
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let path = target.split('?').next().unwrap_or("").trim_start_matches('/');
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        let fault = method == "GET"
+            && range.is_some()
+            && faults.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1)).is_ok();
+        let range = if fault { Some("0-0".to_string()) } else { range };
+        let (head, body) = if key.is_empty() && method == "HEAD" {
+            respond_bucket(objects.keys().any(|(name, _)| name == bucket))
+        } else {
+            respond(method, objects.get(&(bucket.to_string(), key.to_string())), range.as_deref())
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        if writer.write_all(head.as_bytes()).await.is_err() || pacers.write(&mut writer, body).await.is_err() {
+            return;
+        }
+    }
+}
+
+// The response to a HEAD on a bucket, which exists when it holds an object
+fn respond_bucket(exists: bool) -> (String, &'static [u8]) {
+    let head = match exists {
+        true => format!("HTTP/1.1 200 OK\r\nx-amz-bucket-region: {}\r\nContent-Length: 0\r\n\r\n", REGION),
+        false => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+    (head, &[])
+}
+
+// Build the status line and headers, and pick the body, of the response to a HEAD or GET
+fn respond<'a>(method: &str, object: Option<&'a Vec<u8>>, range: Option<&str>) -> (String, &'a [u8]) {
+    let Some(data) = object else {
+        let body: &[u8] = b"<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>";
+        let body = if method == "HEAD" { &[] } else { body };
+        let head = format!(
+            "HTTP/1.1 404 Not Found\r\nContent-Type: application/xml\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        return (head, body);
+    };
+    let etag = format!("\"{:x}\"", data.len());
+    if method == "HEAD" {
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nETag: {}\r\nLast-Modified: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+            etag,
+            LAST_MODIFIED,
+            data.len()
+        );
+        return (head, &[]);
+    }
+
+    let bounds = range.and_then(|range| {
+        let (start, end) = range.split_once('-')?;
+        let start: usize = start.parse().ok()?;
+        let end = end.parse::<usize>().ok()?.min(data.len().checked_sub(1)?);
+        (start <= end).then_some((start, end))
+    });
+    match bounds {
+        Some((start, end)) => (
+            format!(
+                "HTTP/1.1 206 Partial Content\r\nETag: {}\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                etag,
+                start,
+                end,
+                data.len(),
+                end - start + 1
+            ),
+            &data[start..=end],
+        ),
+        None => (
+            format!("HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\n\r\n", etag, data.len()),
+            data,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_network_conditions() {
+        let data = vec![7u8; 256 * 1024];
+        let network = NetworkConditions {
+            latency: Duration::from_millis(50),
+            bandwidth: Some(1024 * 1024),
+            connection_bandwidth: None,
+        };
+        let s3 = LocalS3::start_with(vec![("bench", "object", data.clone())], network).await.unwrap();
+        let client = s3.client().await;
+
+        let started = std::time::Instant::now();
+        let object = client.get_object().bucket("bench").key("object").range("bytes=0-262143").send().await.unwrap();
+        let body = object.body.collect().await.unwrap().into_bytes();
+        assert_eq!(body.len(), data.len());
+
+        // 50ms of latency, then a quarter of a second at 1 MiB/s
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(280), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_pacer_shares_its_rate() {
+        let pacer = Pacer::new(1000);
+        let start = Instant::now();
+        pacer.reserve(500);
+        let done = pacer.reserve(500);
+        assert!(done - start >= Duration::from_secs(1));
+    }
+}
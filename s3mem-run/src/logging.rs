@@ -0,0 +1,241 @@
+// Log output setup
+// Logs go to stderr so that reports printed on stdout stay machine-readable. The JSON
+// format writes one object per line with the fields of every enclosing span, such as
+// those recorded by #[instrument], flattened into the event's own fields, so each line
+// carries the bucket and key it belongs to without nesting.
+// While a status line such as the download progress bar is shown on stderr, each log
+// line clears it first and draws it again below, so the two don't mix.
+
+use serde_json::{Map, Value};                 // JSON log lines
+use std::fmt;                                 // Formatter plumbing
+use std::io::Write;                           // Writing log lines and the status line
+use std::sync::Mutex;                         // The status line shared with log writers
+use tracing::field::{Field, Visit};           // Reading event fields
+use tracing::{Event, Level, Subscriber};      // Events being formatted
+use tracing_subscriber::filter::LevelFilter;  // --log-level as a filter directive
+use tracing_subscriber::fmt::format::{JsonFields, Writer};  // Span fields stored as JSON
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};  // Timestamps
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use crate::telemetry::OtlpLayer;              // Optional trace export
+use tracing_subscriber::layer::SubscriberExt; // Adding the trace export layer
+use tracing_subscriber::registry::LookupSpan; // Walking the span scope
+use tracing_subscriber::{EnvFilter, FmtSubscriber};  // Filtering and formatting
+
+// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,                                 // Full human-readable lines
+    Json,                                 // One JSON object per line
+    Compact,                              // Shorter human-readable lines
+}
+
+// Build the filter from --log-level and RUST_LOG
+// --log-level is the default for everything RUST_LOG doesn't mention, so RUST_LOG can
+// raise or lower the level of individual targets in either direction
+pub fn env_filter(level: Level, rust_log: Option<&str>) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::from_level(level).into())
+        .parse_lossy(rust_log.unwrap_or_default())
+}
+
+// Install the global subscriber, exporting spans through the OTLP layer when given
+pub fn init(format: LogFormat, level: Level, otlp: Option<OtlpLayer>) {
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let filter = env_filter(level, rust_log.as_deref());
+    let builder = FmtSubscriber::builder()
+        .with_env_filter(filter)
+        .with_writer(Stderr);
+
+    let result = match format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish().with(otlp)),
+        LogFormat::Compact => tracing::subscriber::set_global_default(builder.compact().finish().with(otlp)),
+        LogFormat::Json => tracing::subscriber::set_global_default(
+            builder
+                .fmt_fields(JsonFields::new())
+                .event_format(FlatJson)
+                .finish()
+                .with(otlp),
+        ),
+    };
+    result.expect("Failed to set tracing subscriber");
+}
+
+// The line kept at the bottom of stderr, below the log lines
+static STATUS_LINE: Mutex<Option<String>> = Mutex::new(None);
+const CLEAR_LINE: &str = "\r\x1b[2K";        // Back to column 0 and erase the line
+
+// Show `line` at the bottom of stderr in place of the previous status line, or remove it
+// The line is only redrawn, never ended, so it must fit on one row of the terminal
+pub fn set_status_line(line: Option<String>) {
+    let mut status = STATUS_LINE.lock().unwrap();
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(stderr, "{}{}", CLEAR_LINE, line.as_deref().unwrap_or_default());
+    let _ = stderr.flush();
+    *status = line;
+}
+
+// Writes log lines to stderr around the status line
+struct Stderr;
+
+impl<'a> MakeWriter<'a> for Stderr {
+    type Writer = Stderr;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Stderr
+    }
+}
+
+// The formatter hands over each event as a single write of whole lines
+impl std::io::Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let status = STATUS_LINE.lock().unwrap();
+        let mut stderr = std::io::stderr().lock();
+        match status.as_deref() {
+            Some(line) => {
+                write!(stderr, "{}", CLEAR_LINE)?;
+                stderr.write_all(buf)?;
+                write!(stderr, "{}", line)?;
+            }
+            None => stderr.write_all(buf)?,
+        }
+        stderr.flush()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+// Formats each event as a single JSON object with span fields flattened into it
+// Fields of inner spans override those of outer spans, and the event's own fields
+// override both. Span fields must be recorded with JsonFields to be read back.
+pub struct FlatJson;
+
+impl<S, N> FormatEvent<S, N> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut object = Map::new();
+
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        object.insert("timestamp".to_string(), Value::String(timestamp));
+        object.insert("level".to_string(), Value::String(event.metadata().level().to_string()));
+        object.insert("target".to_string(), Value::String(event.metadata().target().to_string()));
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = Vec::new();
+            for span in scope.from_root() {
+                spans.push(Value::String(span.name().to_string()));
+                let extensions = span.extensions();
+                let Some(fields) = extensions.get::<FormattedFields<N>>() else { continue };
+                if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(fields) {
+                    object.extend(fields);
+                }
+            }
+            object.insert("spans".to_string(), Value::Array(spans));
+        }
+
+        event.record(&mut JsonVisitor(&mut object));
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+// Records event fields into a JSON object, keeping numbers and booleans as such
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        let value = serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number);
+        self.0.insert(field.name().to_string(), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::String(format!("{:?}", value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Collects everything written by a subscriber
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_flattens_span_fields() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = FmtSubscriber::builder()
+            .with_env_filter(env_filter(Level::INFO, None))
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("download", bucket = "models", key = "a.gguf");
+            let _entered = span.enter();
+            let inner = tracing::info_span!("chunk", key = "b.gguf", chunk = 3u64);
+            let _inner = inner.enter();
+            tracing::info!(bytes = 42u64, done = true, "Chunk written");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["message"], "Chunk written");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["bucket"], "models");
+        assert_eq!(line["key"], "b.gguf");
+        assert_eq!(line["chunk"], 3);
+        assert_eq!(line["bytes"], 42);
+        assert_eq!(line["done"], true);
+        assert_eq!(line["spans"], serde_json::json!(["download", "chunk"]));
+    }
+
+    #[test]
+    fn test_log_level_combines_with_rust_log() {
+        // RUST_LOG can raise a target above --log-level, which with_max_level used to cap
+        let filter = env_filter(Level::INFO, Some("s3mem_run=debug"));
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::DEBUG));
+
+        let filter = env_filter(Level::WARN, None);
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::WARN));
+
+        // Invalid directives are skipped rather than dropping the whole filter
+        let filter = env_filter(Level::INFO, Some("not a directive=,aws_sdk_s3=trace"));
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::TRACE));
+    }
+}
@@ -1,33 +1,62 @@
-// Import required crates and modules
+// Command-line front end of the s3mem-run library
+mod bench;                                    // The bench subcommand
+mod chunk_args;                               // Chunk planning options
+mod doctor;                                   // The doctor subcommand
+mod fetch;                                    // The fetch subcommand
+mod progress_display;                         // Progress bar or periodic summary on stderr
+
 use anyhow::{Context, Result};                // Error handling with context
 use aws_config::BehaviorVersion;              // AWS SDK configuration
 use aws_sdk_s3::Client;                       // AWS S3 client
-use clap::Parser;                             // Command-line argument parsing
-use libc::{ftruncate, memfd_create};          // Linux system calls for memory file operations
-use std::env;                                 // Environment variable access
-use std::ffi::CString;                        // C-compatible strings for FFI
-use std::io::{Seek, SeekFrom, Write};         // I/O operations
-use std::os::unix::io::FromRawFd;             // Unix-specific file descriptor handling
+use clap::{Parser, Subcommand};               // Command-line argument parsing
+use s3mem_run::error::{self, ErrorKind};      // Typed errors
+use s3mem_run::exec::{create_memfd_and_exec, ExecOptions, PreparedProgram, S3ModelSource};  // Building the program's command
+use s3mem_run::loader::object_version;        // Model versions for hot swap mode
+use s3mem_run::progress::{self, DownloadProgress};  // Shared download progress
+use s3mem_run::{child_output, hotswap, inspect, logging, metrics, program, report};  // Subcommands and output
+use s3mem_run::{runtime_api, shards, supervisor, telemetry};  // Lambda, split models and process control
+use s3mem_run::S3Object;                      // Objects to download
 use std::os::unix::process::CommandExt;       // Unix-specific process extensions
 use std::path::PathBuf;                       // Path manipulation
 use std::process::Command;                    // Process execution
 use std::sync::Arc;                           // Thread-safe reference counting
-use tokio::sync::Semaphore;                   // Async concurrency limiting
-use tracing::{debug, error, info, instrument, Level};  // Structured logging
-use tracing_subscriber::{EnvFilter, FmtSubscriber};    // Logging configuration
-
-// Default values that can be overridden based on file size
-// These constants control the download behavior and are tuned for optimal performance
-const MIN_CHUNK_SIZE: i64 = 4 * 1024 * 1024;      // 4MB minimum chunk size
-const MAX_CHUNK_SIZE: i64 = 128 * 1024 * 1024;    // 128MB maximum chunk size
-const MIN_CONCURRENT_DOWNLOADS: usize = 4;         // Minimum number of parallel downloads
-const MAX_CONCURRENT_DOWNLOADS: usize = 16;        // Maximum number of parallel downloads
-const TARGET_CHUNKS_PER_FILE: i64 = 75;           // Target ~75 chunks per file for balanced parallelism
+use tracing::{debug, error, info, warn, Level};  // Structured logging
 
 #[derive(Parser, Debug)]
 #[command(name = "s3mem-run")]
 #[command(about = "A Rust utility that downloads large files from Amazon S3 into memory and executes programs with the memory file descriptor")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    /// Command to run; without one the options below download the file and execute the
+    /// program, as exec does
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
+    // Options of the flat invocation, the same as those of exec
+    #[command(flatten)]
+    exec: ExecArgs,
+
+    /// Export download spans to this OTLP/HTTP collector, such as http://localhost:4318,
+    /// in the X-Ray trace from _X_AMZN_TRACE_ID
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<telemetry::Endpoint>,
+
+    /// Log level (trace, debug, info, warn, error), the default for targets RUST_LOG doesn't name
+    #[arg(long, global = true, env = "LOG_LEVEL", default_value = "info")]
+    log_level: Level,
+
+    /// Log format: text, json (one object per line, span fields flattened) or compact
+    #[arg(long, global = true, env = "LOG_FORMAT", value_enum, default_value = "text")]
+    log_format: logging::LogFormat,
+
+    /// How a fatal error is written to stderr: text, or json with its type and exit code
+    #[arg(long, global = true, env = "ERROR_FORMAT", value_enum, default_value = "text")]
+    error_format: error::ErrorFormat,
+}
+
+// Download the files and execute the program, the default command
+#[derive(clap::Args, Debug)]
+struct ExecArgs {
     /// S3 bucket containing the file (defaults to S3_BUCKET env var)
     #[arg(long, env = "S3_BUCKET")]
     bucket: Option<String>,
@@ -41,9 +70,90 @@ struct Args {
     #[arg(long, env = "MEMFD_PLACEHOLDER", default_value = "{{memfd}}")]
     memfd_placeholder: String,
     
-    /// Log level (trace, debug, info, warn, error)
-    #[arg(long, default_value = "info")]
-    log_level: Level,
+    /// Validate the downloaded file as a GGUF model before executing the program
+    /// Checks the magic, version, metadata and that all tensor data lies within the file
+    #[arg(long, env = "VERIFY_GGUF")]
+    verify_gguf: bool,
+
+    /// S3 key of the program to execute; when set, the program is downloaded into its own
+    /// memory file and executed from there, and the first command word is only used as argv[0]
+    #[arg(long, env = "PROGRAM_S3_KEY")]
+    program_key: Option<String>,
+
+    /// S3 bucket containing the program (defaults to --bucket)
+    #[arg(long, env = "PROGRAM_S3_BUCKET", requires = "program_key")]
+    program_bucket: Option<String>,
+
+    /// Expected SHA-256 of the program downloaded with --program-key, as hex
+    #[arg(long, env = "PROGRAM_SHA256", requires = "program_key")]
+    program_sha256: Option<String>,
+
+    /// Serve download progress as JSON with status 503 on this address until the program
+    /// starts, for example 0.0.0.0:8080 to answer readiness checks on the program's port
+    #[arg(long, env = "PROGRESS_LISTEN")]
+    progress_listen: Option<std::net::SocketAddr>,
+
+    #[command(flatten)]
+    progress: progress_display::ProgressArgs,
+
+    #[command(flatten)]
+    chunks: chunk_args::ChunkArgs,
+
+    /// Write a JSON download timing report before the program starts: "stderr", "env" to
+    /// pass it in the S3MEM_RUN_REPORT env var, or the path of a file
+    #[arg(long, env = "DOWNLOAD_REPORT")]
+    report: Option<report::ReportTarget>,
+
+    /// Write cold start metrics to stdout in CloudWatch Embedded Metric Format before the
+    /// program starts
+    #[arg(long, env = "EMF_METRICS")]
+    emf_metrics: bool,
+
+    /// CloudWatch namespace of the EMF metrics
+    #[arg(long, env = "METRICS_NAMESPACE", default_value = metrics::DEFAULT_NAMESPACE, requires = "emf_metrics")]
+    metrics_namespace: String,
+
+    /// Run the program as a child and restart it from the memory files when it crashes,
+    /// instead of replacing this process with it
+    #[arg(long, env = "SUPERVISE")]
+    supervise: bool,
+
+    /// Pipe the program's stdout and stderr through s3mem-run and log each line with its
+    /// stream, the model key and fields parsed from known llama-server messages
+    /// s3mem-run then stays as the program's parent instead of exec'ing it
+    #[arg(long, env = "CAPTURE_OUTPUT")]
+    capture_output: bool,
+
+    /// Consecutive crashes to restart the program after, when supervising
+    #[arg(long, env = "MAX_RESTARTS", default_value = "5", requires = "supervise")]
+    max_restarts: u32,
+
+    /// Check the model's ETag every SECS seconds, and on SIGHUP, and swap in a new child
+    /// running the new version when it changes; the program must listen on {{port}}
+    #[arg(long, env = "WATCH_INTERVAL", requires = "listen", conflicts_with = "supervise")]
+    watch_interval: Option<u64>,
+
+    /// Public address to proxy to the active child when swapping models
+    #[arg(long, env = "SWAP_LISTEN", requires = "watch_interval")]
+    listen: Option<std::net::SocketAddr>,
+
+    /// Path that answers 200 once a new child is ready to take over
+    #[arg(long, env = "HEALTH_PATH", default_value = "/health")]
+    health_path: String,
+
+    /// Seconds a new child may take to become healthy before the swap is abandoned
+    #[arg(long, env = "HEALTH_TIMEOUT", default_value = "600")]
+    health_timeout: u64,
+
+    /// Seconds the old child may keep serving open connections after a swap
+    #[arg(long, env = "DRAIN_TIMEOUT", default_value = "30")]
+    drain_timeout: u64,
+
+    /// Place the memory file at this file descriptor number in the program (3 or higher)
+    /// Shards of a split model follow at consecutive numbers. All other descriptors are
+    /// closed on exec, and LISTEN_FDS/LISTEN_PID/LISTEN_FDNAMES are exported when N is 3
+    #[arg(long, env = "MEMFD_FD", value_parser = clap::value_parser!(i32).range(3..))]
+    fd: Option<i32>,
 
     /// Program to execute and its arguments
     /// The first argument is the program path, followed by its arguments
@@ -51,314 +161,101 @@ struct Args {
     command: Vec<String>,
 }
 
-// Calculate optimal chunk size based on file size
-// This function determines the best chunk size for downloading based on the total file size
-// Larger files use larger chunks to reduce the number of S3 requests
-fn calculate_optimal_chunk_size(file_size: i64) -> i64 {
-    // Target a reasonable number of chunks based on file size
-    let ideal_chunk_size = file_size / TARGET_CHUNKS_PER_FILE;
-    
-    // Clamp to our min/max boundaries to ensure we don't have too small or too large chunks
-    ideal_chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
-}
-
-// Calculate optimal concurrency based on file size
-// This function determines how many parallel downloads to use based on file size
-// Larger files benefit from more parallelism up to a point
-fn calculate_optimal_concurrency(file_size: i64) -> usize {
-    // For smaller files, use fewer concurrent downloads
-    // For larger files, scale up to the maximum
-    let size_gb = file_size as f64 / (1024.0 * 1024.0 * 1024.0);
-    
-    // Scale concurrency linearly from MIN to MAX based on file size from 0.5GB to 10GB
-    let concurrency = if size_gb <= 0.5 {
-        MIN_CONCURRENT_DOWNLOADS
-    } else if size_gb >= 10.0 {
-        MAX_CONCURRENT_DOWNLOADS
-    } else {
-        // Linear interpolation between min and max
-        let scale_factor = (size_gb - 0.5) / 9.5; // 0.5GB to 10GB range = 9.5GB
-        let range = MAX_CONCURRENT_DOWNLOADS - MIN_CONCURRENT_DOWNLOADS;
-        MIN_CONCURRENT_DOWNLOADS + (scale_factor * range as f64).round() as usize
-    };
-    
-    concurrency
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Download the file and execute the program with it (the default)
+    Exec(Box<ExecArgs>),
+    /// Download a file with the parallel loader and write it to a local file or stdout
+    Fetch(fetch::FetchArgs),
+    /// Measure the download throughput, optionally over a grid of chunk sizes and concurrency levels
+    Bench(bench::BenchArgs),
+    /// Print a GGUF model's metadata, tensors and memory estimate using only range reads
+    Inspect(inspect::InspectArgs),
+    /// Check the environment s3mem-run depends on
+    Doctor(doctor::DoctorArgs),
 }
 
-// MemFile represents a file that exists only in memory
-// This is the core data structure that allows us to avoid disk I/O
-struct MemFile {
-    file: std::fs::File,  // Standard file handle for I/O operations
-    fd: i32,              // Raw file descriptor for passing to other processes
-}
-
-impl MemFile {
-    // Create a new memory-backed file using memfd_create
-    fn new(name: &str) -> Result<Self> {
-        // Convert Rust string to C string for the system call
-        let name = CString::new(name)?;
-        
-        // Create an in-memory file using the Linux-specific memfd_create syscall
-        // This creates a file that exists only in memory, not on disk
-        let fd = unsafe { memfd_create(name.as_ptr(), 0) };
-
-        if fd == -1 {
-            return Err(std::io::Error::last_os_error()).context("Failed to create memfd");
+impl Args {
+    // The exec options, when the program is to be executed
+    fn exec_args(&self) -> Option<&ExecArgs> {
+        match &self.subcommand {
+            None => Some(&self.exec),
+            Some(Commands::Exec(exec)) => Some(exec),
+            Some(_) => None,
         }
-
-        // Convert the raw file descriptor to a Rust File object for easier handling
-        let file = unsafe { std::fs::File::from_raw_fd(fd) };
-        Ok(MemFile { file, fd })
-    }
-
-    // Write data at a specific offset in the memory file
-    // This is used to write downloaded chunks directly to their correct position
-    fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
-        // Seek to the specified position in the file
-        self.file
-            .seek(SeekFrom::Start(offset))
-            .context("Failed to seek in memfd")?;
-            
-        // Write the data at that position
-        self.file
-            .write_all(data)
-            .context("Failed to write to memfd")?;
-        Ok(())
     }
 }
 
-#[instrument(skip(client))]
-// Download a single chunk of the file from S3
-// This function is called in parallel for different chunks of the file
-async fn download_chunk(
-    client: &Client,
-    bucket: &str,
-    key: &str,
-    start: i64,
-    end: i64,
-) -> Result<(Vec<u8>, u64)> {
-    // Format the byte range header for the S3 request
-    let range = format!("bytes={}-{}", start, end);
-    debug!(range, "Downloading chunk");
-
-    // Make the S3 GetObject request with the byte range
-    let resp = client
-        .get_object()
-        .bucket(bucket)
-        .key(key)
-        .range(range)
-        .send()
-        .await
-        .context("Failed to get object from S3")?;
-
-    // Collect the streaming response body into a byte vector
-    let data = resp
-        .body
-        .collect()
-        .await
-        .context("Failed to collect response body")?;
-    
-    // Convert to a standard Vec<u8> and log the chunk size
-    let bytes = data.to_vec();
-    let chunk_size = bytes.len();
-    debug!(bytes = chunk_size, offset = start, "Chunk downloaded successfully");
-    
-    // Return both the data and the offset where it should be written
-    Ok((bytes, start as u64))
+// What main does once the async part is over
+enum Outcome {
+    Done,                             // A subcommand ran to completion
+    Start(Box<PreparedProgram>),      // Exec or supervise the program
+    Exit(i32),                        // Exit with this code, as hot swap mode does when its program stops
 }
 
-#[instrument(skip(client))]
-// Download a file from S3 in parallel chunks directly into memory
-// This is the main function that orchestrates the parallel download process
-async fn parallel_download_to_memfd(bucket: &str, key: &str, client: &Client) -> Result<MemFile> {
-    // First, get the object metadata to determine file size
-    info!("Getting object metadata from S3");
-    let head_object = client
-        .head_object()
-        .bucket(bucket)
-        .key(key)
-        .send()
-        .await
-        .context("Failed to get object metadata from S3")?;
-
-    // Extract the total file size from the metadata
-    let total_size = head_object
-        .content_length
-        .context("Content length not available")? as i64;
-
-    // Calculate optimal chunk size based on file size
-    let chunk_size = calculate_optimal_chunk_size(total_size);
-    
-    // Calculate optimal concurrency based on file size
-    let concurrent_downloads = calculate_optimal_concurrency(total_size);
-    
-    // Log the download parameters for monitoring and debugging
+// Write the download report where it was asked for
+fn write_report(target: &report::ReportTarget, report: &serde_json::Value, cmd: &mut Command) -> Result<()> {
+    match target {
+        report::ReportTarget::Stderr => eprintln!("{}", report),
+        report::ReportTarget::Env => {
+            cmd.env(report::REPORT_ENV, report.to_string());
+        }
+        report::ReportTarget::File(path) => std::fs::write(path, format!("{:#}\n", report))
+            .with_context(|| format!("Failed to write download report to {}", path.display()))?,
+    }
     info!(
-        file_size_bytes = total_size,
-        file_size_mb = total_size / (1024 * 1024),
-        chunk_size_bytes = chunk_size,
-        chunk_size_mb = chunk_size / (1024 * 1024),
-        concurrent_downloads = concurrent_downloads,
-        "Download parameters calculated"
+        wall_time_ms = report["wall_time_ms"].as_f64(),
+        effective_gbps = report["effective_gbps"].as_f64(),
+        retries = report["chunks"]["retries"].as_u64(),
+        "Download report written"
     );
+    Ok(())
+}
 
-    // Create a memory file to hold the downloaded data
-    debug!("Creating memory file");
-    let mut memfile = MemFile::new("s3_file")?;
-    
-    // Pre-allocate the full file size in memory to avoid resizing during writes
-    if unsafe { ftruncate(memfile.fd, total_size) } == -1 {
-        return Err(std::io::Error::last_os_error()).context("Failed to set file size");
-    }
-
-    // Create a semaphore to limit concurrent downloads
-    let semaphore = Arc::new(Semaphore::new(concurrent_downloads));
-    let mut tasks = Vec::new();
-
-    // Calculate chunk boundaries and spawn download tasks
-    let mut start = 0i64;
-    let total_chunks = (total_size + chunk_size - 1) / chunk_size;
-    let mut chunk_count = 0;
-    
-    info!(total_chunks, "Starting parallel download");
-    
-    // Spawn tasks for each chunk
-    while start < total_size {
-        chunk_count += 1;
-        // Calculate the end byte for this chunk (inclusive)
-        let end = (start + chunk_size - 1).min(total_size - 1);
-        
-        // Clone references for the async task
-        let client = client.clone();
-        let bucket = bucket.to_string();
-        let key = key.to_string();
-        
-        // Acquire a permit from the semaphore to limit concurrency
-        let permit = semaphore.clone().acquire_owned().await?;
-        
-        debug!(
-            chunk_number = chunk_count,
-            total_chunks = total_chunks,
-            start_byte = start,
-            end_byte = end,
-            "Scheduling chunk download"
-        );
-
-        // Spawn an async task to download this chunk
-        let task = tokio::spawn(async move {
-            // Download the chunk and release the semaphore permit when done
-            let result = download_chunk(&client, &bucket, &key, start, end).await;
-            drop(permit);
-            result
-        });
-
-        tasks.push(task);
-        start = end + 1;  // Move to the next chunk
-    }
+// Get the S3 bucket and key from arguments or environment variables
+fn require_bucket_and_key(bucket: Option<String>, key: Option<String>) -> Result<(String, String)> {
+    let bucket = bucket.ok_or_else(|| {
+        error!("S3_BUCKET environment variable not set and --bucket not provided");
+        error::Error::new(ErrorKind::MissingConfiguration, "S3_BUCKET environment variable not set and --bucket not provided")
+    })?;
 
-    info!(total_chunks = tasks.len(), "All chunks scheduled, waiting for completion");
-    
-    // Wait for all download tasks to complete and write their data to the memory file
-    let mut completed_chunks = 0;
-    for task in tasks {
-        completed_chunks += 1;
-        // Await the task completion and extract the data and offset
-        let (data, offset) = task
-            .await
-            .context("Task join failed")?
-            .context("Chunk download failed")?;
-            
-        debug!(
-            completed = completed_chunks,
-            total = total_chunks,
-            progress_percent = (completed_chunks as f64 / total_chunks as f64 * 100.0) as u32,
-            "Writing chunk to memory file"
-        );
-        
-        // Write the chunk data to the memory file at the correct offset
-        memfile.write_at(&data, offset)?;
-        
-        // Log progress periodically
-        if completed_chunks % 10 == 0 || completed_chunks == total_chunks {
-            info!(
-                completed_chunks,
-                total_chunks,
-                progress_percent = (completed_chunks as f64 / total_chunks as f64 * 100.0) as u32,
-                "Download progress"
-            );
-        }
-    }
+    let key = key.ok_or_else(|| {
+        error!("S3_KEY environment variable not set and --key not provided");
+        error::Error::new(ErrorKind::MissingConfiguration, "S3_KEY environment variable not set and --key not provided")
+    })?;
 
-    info!("Download completed successfully");
-    Ok(memfile)
+    Ok((bucket, key))
 }
 
-#[instrument(skip(client))]
-// Create a memory file descriptor, download the file, and execute the specified program
-// This is the main function that ties everything together
-async fn create_memfd_and_exec(
-    bucket: &str,
-    key: &str,
-    client: &Client,
-    program: &str,
-    args: &[String],
-    memfd_placeholder: &str,
-) -> Result<()> {
-    info!(bucket, key, program, "Starting download and execution process");
-    
-    // Download the file from S3 into memory
-    let memfile = parallel_download_to_memfd(bucket, key, client).await?;
-    
-    // Get the path to the memory file descriptor
-    // This is a special path in /proc that points to the memory file
-    let memfd_path = format!("/proc/self/fd/{}", memfile.fd);
-
-    // Set the environment variable with memfd_path for programs that might use it
-    env::set_var("MEMFD_PATH", &memfd_path);
-    debug!(memfd_path, "Set MEMFD_PATH environment variable");
-
-    // Replace placeholder with actual memfd path in all command arguments
-    // This allows the target program to access the memory file
-    let final_args: Vec<String> = args
-        .iter()
-        .map(|arg| arg.replace(memfd_placeholder, &memfd_path))
-        .collect();
-    
-    debug!(
-        program,
-        args = ?final_args,
-        "Preparing to execute program with memory file descriptor"
-    );
+// Initialize the AWS S3 client from the default credential and region chain
+async fn s3_client() -> Client {
+    debug!("Initializing AWS client");
+    let config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+    let client = Client::new(&config);
+    debug!("AWS client initialized");
+    client
+}
 
-    // Prevent the memory file from being dropped when this function returns
-    // This ensures the file descriptor remains valid for the child process
-    std::mem::forget(memfile);
-    
-    info!("Executing program: {}", program);
+fn main() {
+    // Time to exec is measured from here
+    let started = std::time::Instant::now();
 
-    // Create a new command to execute the target program
-    let mut cmd = Command::new(program);
-    cmd.args(final_args);
+    // Parse command line arguments
+    let args = Args::parse();
 
-    // Execute the command, replacing the current process
-    // This will only return if there's an error
-    Err(cmd.exec().into())
+    // Exit with the code of the error's kind, so wrappers can tell failures apart
+    let error_format = args.error_format;
+    if let Err(err) = start(args, started) {
+        error::report(&err, error_format);
+        std::process::exit(ErrorKind::of(&err).exit_code());
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Parse command line arguments
-    let args = Args::parse();
-    
+// Download the files and execute the program, or run a subcommand
+fn start(args: Args, started: std::time::Instant) -> Result<()> {
     // Initialize the tracing subscriber for structured logging
-    // This sets up the logging system with the specified log level
-    let subscriber = FmtSubscriber::builder()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_max_level(args.log_level)
-        .finish();
-    
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set tracing subscriber");
+    // This sets up the logging system with the specified log level and format
+    logging::init(args.log_format, args.log_level, args.otlp_endpoint.clone().map(telemetry::init));
     
     // Log the start of the program with version information
     info!(
@@ -366,27 +263,130 @@ async fn main() -> Result<()> {
         "Starting s3mem-run"
     );
 
-    // Get the S3 bucket name from arguments or environment variables
-    let bucket = args.bucket.ok_or_else(|| {
-        error!("S3_BUCKET environment variable not set and --bucket not provided");
-        anyhow::anyhow!("S3_BUCKET environment variable not set and --bucket not provided")
-    })?;
+    // Build the async runtime explicitly so it can be shut down before exec
+    // This leaves a single-threaded process whose descriptors can be rearranged safely
+    // When supervising, runtime threads block the signals the supervisor waits for, so a
+    // thread left behind by the shutdown can't take them; until the download finishes,
+    // the main thread leaves them unblocked so they stop the process as usual
+    // Capturing output also needs a parent process, which restarts nothing unless supervising
+    let exec = args.exec_args();
+    let restart_policy = match exec {
+        Some(exec) if exec.supervise => Some(supervisor::RestartPolicy::new(exec.max_restarts)),
+        Some(exec) if exec.capture_output && exec.watch_interval.is_none() => Some(supervisor::RestartPolicy::new(0)),
+        _ => None,
+    };
+    let capture = exec.filter(|exec| exec.capture_output).map(|exec| child_output::Capture {
+        model_key: exec.key.clone().unwrap_or_default(),
+    });
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if restart_policy.is_some() {
+        builder.on_thread_start(supervisor::block_signals);
+    }
+    let runtime = builder.enable_all().build().context("Failed to start async runtime")?;
+    let outcome = runtime.block_on(run(args, started));
+    runtime.shutdown_timeout(std::time::Duration::from_secs(1));
+
+    // Every span of the download has closed; send them before exec replaces the process
+    telemetry::flush();
+
+    // In Lambda, failures before the program starts are reported as init errors, so the
+    // function's error says what went wrong rather than only "Runtime exited"
+    // Supervision and hot swap mark the start, so their later failures are not reported
+    let outcome = outcome.inspect_err(runtime_api::report_init_error)?;
+
+    match outcome {
+        Outcome::Start(prepared) if restart_policy.is_some() => {
+            let mut cmd = prepared.into_command().inspect_err(runtime_api::report_init_error)?;
+            info!("Supervising program: {}", cmd.get_program().to_string_lossy());
+
+            // The memory files stay open here, so restarts don't download anything
+            let code = supervisor::supervise(&mut cmd, restart_policy.as_ref().unwrap(), capture.as_ref())
+                .inspect_err(runtime_api::report_init_error)?;
+            std::process::exit(code)
+        }
+        Outcome::Start(prepared) => {
+            let mut cmd = prepared.into_command().inspect_err(runtime_api::report_init_error)?;
+            info!("Executing program: {}", cmd.get_program().to_string_lossy());
+
+            // Execute the command, replacing the current process
+            // This will only return if there's an error
+            let err = anyhow::Error::new(cmd.exec()).context(error::Error::new(
+                ErrorKind::ExecFailed,
+                format!("Failed to execute {}", cmd.get_program().to_string_lossy()),
+            ));
+            runtime_api::report_init_error(&err);
+            Err(err)
+        }
+        Outcome::Exit(code) => std::process::exit(code),
+        Outcome::Done => Ok(()),
+    }
+}
 
-    // Get the S3 key from arguments or environment variables
-    let key = args.key.ok_or_else(|| {
-        error!("S3_KEY environment variable not set and --key not provided");
-        anyhow::anyhow!("S3_KEY environment variable not set and --key not provided")
-    })?;
+// Run the requested command
+async fn run(args: Args, started: std::time::Instant) -> Result<Outcome> {
+    match args.subcommand {
+        None => run_exec(args.exec, started).await,
+        Some(Commands::Exec(exec_args)) => run_exec(*exec_args, started).await,
+        Some(Commands::Fetch(fetch_args)) => {
+            let (bucket, key) = require_bucket_and_key(fetch_args.bucket.clone(), fetch_args.key.clone())?;
+            let client = s3_client().await;
+            fetch::run_fetch(&client, &bucket, &key, &fetch_args).await?;
+            Ok(Outcome::Done)
+        }
+        Some(Commands::Bench(bench_args)) => {
+            bench::run_bench(&bench_args).await?;
+            Ok(Outcome::Done)
+        }
+        Some(Commands::Inspect(inspect_args)) => {
+            let (bucket, key) = require_bucket_and_key(inspect_args.bucket.clone(), inspect_args.key.clone())?;
+            let client = s3_client().await;
+            inspect::run_inspect(&client, &bucket, &key, &inspect_args).await?;
+            Ok(Outcome::Done)
+        }
+        Some(Commands::Doctor(doctor_args)) => {
+            // Failed checks are in the table already; the exit code tells scripts about them
+            let passed = doctor::run_doctor(&doctor_args).await?;
+            Ok(if passed { Outcome::Done } else { Outcome::Exit(doctor::CHECKS_FAILED_EXIT_CODE) })
+        }
+    }
+}
+
+// Download the file and return the program to execute
+async fn run_exec(args: ExecArgs, started: std::time::Instant) -> Result<Outcome> {
+    let (bucket, key) = require_bucket_and_key(args.bucket, args.key)?;
+    let (planner, limits) = (args.chunks.planner()?, args.chunks.limits()?);
 
     // Get the program to execute (first element of command vector)
     let program = &args.command[0];
-    let program_path = PathBuf::from(program);
 
-    // Verify that the program exists
-    if !program_path.exists() {
-        error!(program, "Program does not exist");
-        return Err(anyhow::anyhow!("Program '{}' does not exist", program));
-    }
+    // Find the program on PATH and make sure it can start, unless it is downloaded from S3
+    // This happens before the download, which can take minutes for a large model
+    let program_path = match args.program_key {
+        Some(_) => None,
+        None => {
+            let path = program::resolve(program).inspect_err(|err| error!(program, "{:#}", err))?;
+            let origin = path
+                .canonicalize()
+                .with_context(|| format!("Failed to resolve {}", path.display()))?
+                .parent()
+                .map(PathBuf::from);
+            let report = program::check(&path, origin.as_deref())?;
+            report.ensure_runnable().inspect_err(|err| error!(program, "{:#}", err))?;
+            for warning in &report.warnings {
+                warn!(program, "{}", warning);
+            }
+            for library in &report.libraries {
+                debug!(library = %library.name, path = ?library.path, "Shared library found");
+            }
+            info!(
+                program,
+                path = %path.display(),
+                libraries = report.libraries.len(),
+                "Program checked"
+            );
+            Some(path)
+        }
+    };
 
     // Get program arguments (everything after the program name)
     let program_args: Vec<String> = args.command[1..].to_vec();
@@ -397,26 +397,135 @@ async fn main() -> Result<()> {
         key,
         program,
         args = ?program_args,
-        log_level = ?args.log_level,
         "Configuration loaded"
     );
 
+    // Answer readiness checks with the download progress until the program takes over
+    let progress = Arc::new(DownloadProgress::new());
+    let progress_server = match args.progress_listen {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| {
+                    error::Error::new(ErrorKind::InvalidConfiguration, format!("Failed to listen for progress requests on {}", addr))
+                })?;
+            info!(%addr, "Serving download progress");
+            Some(tokio::spawn(progress::serve(listener, progress.clone())))
+        }
+        None => None,
+    };
+
+    // Show the download's progress events as a bar or as summary lines
+    let display = args.progress.start(&progress);
+
     // Initialize the AWS S3 client
-    debug!("Initializing AWS client");
-    let config = aws_config::defaults(BehaviorVersion::latest()).load().await;
-    let client = Client::new(&config);
-    debug!("AWS client initialized");
+    let client = s3_client().await;
 
     // Download the file and execute the program
-    create_memfd_and_exec(
-        &bucket,
-        &key,
-        &client,
-        program,
-        &program_args,
-        &args.memfd_placeholder,
-    )
-    .await
+    let options = ExecOptions {
+        memfd_placeholder: args.memfd_placeholder,
+        verify_gguf: args.verify_gguf,
+        fd: args.fd,
+        program_object: args.program_key.map(|program_key| S3Object {
+            bucket: args.program_bucket.unwrap_or_else(|| bucket.clone()),
+            key: program_key,
+            executable: true,
+            sha256: args.program_sha256,
+        }),
+        program_path,
+        supervise: args.supervise || args.capture_output || args.watch_interval.is_some(),
+        capture_output: args.capture_output,
+        progress: progress.clone(),
+        planner,
+        limits,
+    };
+
+    // In hot swap mode each child gets its own private port through {{port}}
+    let swap = match (args.watch_interval, args.listen) {
+        (Some(interval), Some(listen)) => {
+            if !program_args.iter().any(|arg| arg.contains(hotswap::PORT_PLACEHOLDER)) {
+                anyhow::bail!(error::Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "--watch-interval needs {} in the program's arguments so each child can listen on its own port",
+                        hotswap::PORT_PLACEHOLDER
+                    ),
+                ));
+            }
+            let source = S3ModelSource {
+                client: client.clone(),
+                bucket: bucket.clone(),
+                key: key.clone(),
+                keys: shards::find_shard_keys(&client, &bucket, &key).await?,
+                program: program.clone(),
+                args: program_args.clone(),
+                options: options.clone(),
+            };
+            let swap_options = hotswap::SwapOptions {
+                listen,
+                interval: std::time::Duration::from_secs(interval),
+                health_path: args.health_path.clone(),
+                health_timeout: std::time::Duration::from_secs(args.health_timeout),
+                drain_timeout: std::time::Duration::from_secs(args.drain_timeout),
+                capture: args.capture_output.then(|| child_output::Capture { model_key: key.clone() }),
+            };
+            Some((source, swap_options))
+        }
+        _ => None,
+    };
+
+    // Download the model, as the first child's version in hot swap mode
+    let prepared = match &swap {
+        Some((source, _)) => {
+            // The version is read before the download, so a change during it is picked up later
+            let version = object_version(&client, &bucket, &source.keys).await?;
+            let port = hotswap::free_port()?;
+            source
+                .prepare_with(port, progress.clone())
+                .await
+                .map(|prepared| (Some((version, port)), prepared))
+        }
+        None => create_memfd_and_exec(&bucket, &key, &client, program, &program_args, &options)
+            .await
+            .map(|prepared| (None, prepared)),
+    };
+
+    // Leave the final progress on stderr before anything else is logged
+    if let Some(display) = display {
+        display.finish().await;
+    }
+
+    // Release the port before the program starts, since it will usually listen on it
+    if let Some(server) = progress_server {
+        server.abort();
+        let _ = server.await;
+        debug!("Progress endpoint stopped");
+    }
+
+    let (swap_state, mut prepared) = prepared?;
+    let download_report = progress.report(&bucket);
+    if let Some(target) = &args.report {
+        write_report(target, &download_report, &mut prepared.command)?;
+    }
+    if args.emf_metrics {
+        let memfd_size = prepared
+            .memfiles
+            .iter()
+            .map(|memfile| memfile.size())
+            .sum::<Result<u64>>()
+            .context("Failed to get the size of the memory files")?;
+        let cold_start = metrics::ColdStartMetrics::from_report(&download_report, memfd_size, started.elapsed());
+        metrics::emit(&args.metrics_namespace, &key, &cold_start).context("Failed to write EMF metrics")?;
+    }
+
+    match (swap, (swap_state, prepared)) {
+        (Some((source, swap_options)), (Some((version, port)), prepared)) => {
+            let initial = hotswap::Loaded { version, port, prepared };
+            let code = hotswap::run(&source, &swap_options, initial).await?;
+            Ok(Outcome::Exit(code))
+        }
+        (_, (_, prepared)) => Ok(Outcome::Start(Box::new(prepared))),
+    }
 }
 
 #[cfg(test)]
@@ -440,31 +549,12 @@ mod tests {
         ])
         .unwrap();
 
-        assert_eq!(args.bucket.unwrap(), "test-bucket");
-        assert_eq!(args.key.unwrap(), "test-key");
+        assert_eq!(args.exec.bucket.unwrap(), "test-bucket");
+        assert_eq!(args.exec.key.unwrap(), "test-key");
         assert_eq!(args.log_level, Level::DEBUG);
-        assert_eq!(args.command, vec!["program", "arg1", "arg2"]);
-        assert_eq!(args.memfd_placeholder, "{{memfd}}");
-    }
-
-    #[test]
-    fn test_memfile_creation() {
-        let memfile = MemFile::new("test_file").unwrap();
-        assert!(memfile.fd > 0);
-    }
-
-    #[test]
-    fn test_memfile_write() {
-        let mut memfile = MemFile::new("test_file").unwrap();
-        let test_data = b"Hello, World!";
-        memfile.write_at(test_data, 0).unwrap();
-
-        // Verify the write by reading back
-        use std::io::Read;
-        let mut buffer = Vec::new();
-        memfile.file.seek(SeekFrom::Start(0)).unwrap();
-        memfile.file.read_to_end(&mut buffer).unwrap();
-        assert_eq!(buffer, test_data);
+        assert_eq!(args.exec.command, vec!["program", "arg1", "arg2"]);
+        assert_eq!(args.exec.memfd_placeholder, "{{memfd}}");
+        assert!(!args.exec.verify_gguf);
     }
 
     #[test]
@@ -490,51 +580,231 @@ mod tests {
         ])
         .unwrap();
 
-        assert_eq!(args.memfd_placeholder, "{{custom}}");
-        let program_args: Vec<String> = args.command[1..].to_vec();
+        assert_eq!(args.exec.memfd_placeholder, "{{custom}}");
+        let program_args: Vec<String> = args.exec.command[1..].to_vec();
         assert!(program_args.contains(&"{{custom}}".to_string()));
     }
     
     #[test]
-    fn test_calculate_optimal_chunk_size() {
-        // Test with small file (512MB)
-        let small_file_size = 512 * 1024 * 1024;
-        let small_chunk_size = calculate_optimal_chunk_size(small_file_size);
-        assert!(small_chunk_size >= MIN_CHUNK_SIZE);
-        assert!(small_chunk_size <= MAX_CHUNK_SIZE);
-        
-        // Test with medium file (2GB)
-        let medium_file_size = 2 * 1024 * 1024 * 1024;
-        let medium_chunk_size = calculate_optimal_chunk_size(medium_file_size);
-        assert!(medium_chunk_size >= MIN_CHUNK_SIZE);
-        assert!(medium_chunk_size <= MAX_CHUNK_SIZE);
-        
-        // Test with large file (10GB)
-        let large_file_size = 10 * 1024 * 1024 * 1024;
-        let large_chunk_size = calculate_optimal_chunk_size(large_file_size);
-        assert!(large_chunk_size >= MIN_CHUNK_SIZE);
-        assert!(large_chunk_size <= MAX_CHUNK_SIZE);
-        
-        // Verify that larger files get larger chunks
-        assert!(large_chunk_size > small_chunk_size);
+    fn test_inspect_subcommand_parsing() {
+        let args = Args::try_parse_from([
+            "s3mem-run",
+            "inspect",
+            "--bucket",
+            "test-bucket",
+            "--key",
+            "model.gguf",
+            "--format",
+            "json",
+            "--ctx-size",
+            "8192",
+            "--cache-type-k",
+            "q8_0",
+        ])
+        .unwrap();
+
+        match args.subcommand {
+            Some(Commands::Inspect(inspect_args)) => {
+                assert_eq!(inspect_args.bucket.unwrap(), "test-bucket");
+                assert_eq!(inspect_args.format, inspect::ReportFormat::Json);
+                assert_eq!(inspect_args.ctx_size, Some(8192));
+                assert_eq!(inspect_args.cache_type_k, "q8_0");
+                assert_eq!(inspect_args.cache_type_v, "f16");
+            }
+            other => panic!("expected inspect subcommand, got {:?}", other),
+        }
+
+        // The flat invocation still works without a subcommand
+        let args = Args::try_parse_from(["s3mem-run", "--key", "k", "program", "inspect"]).unwrap();
+        assert!(args.subcommand.is_none());
+        assert_eq!(args.exec.command, vec!["program", "inspect"]);
+    }
+
+    #[test]
+    fn test_fd_option_parsing() {
+        let args = Args::try_parse_from(["s3mem-run", "--fd", "3", "program"]).unwrap();
+        assert_eq!(args.exec.fd, Some(3));
+
+        // Standard input, output and error can't be replaced
+        assert!(Args::try_parse_from(["s3mem-run", "--fd", "2", "program"]).is_err());
     }
-    
+
     #[test]
-    fn test_calculate_optimal_concurrency() {
-        // Test with small file (512MB)
-        let small_file_size = 512 * 1024 * 1024;
-        let small_concurrency = calculate_optimal_concurrency(small_file_size);
-        assert_eq!(small_concurrency, MIN_CONCURRENT_DOWNLOADS);
-        
-        // Test with large file (10GB)
-        let large_file_size = 10 * 1024 * 1024 * 1024;
-        let large_concurrency = calculate_optimal_concurrency(large_file_size);
-        assert_eq!(large_concurrency, MAX_CONCURRENT_DOWNLOADS);
-        
-        // Test with medium file (5GB) - should be somewhere in between
-        let medium_file_size = 5 * 1024 * 1024 * 1024;
-        let medium_concurrency = calculate_optimal_concurrency(medium_file_size);
-        assert!(medium_concurrency > MIN_CONCURRENT_DOWNLOADS);
-        assert!(medium_concurrency < MAX_CONCURRENT_DOWNLOADS);
+    fn test_program_key_parsing() {
+        let args = Args::try_parse_from([
+            "s3mem-run",
+            "--bucket",
+            "models",
+            "--program-key",
+            "bin/llama-server",
+            "--program-sha256",
+            "abc123",
+            "llama-server",
+            "-m",
+            "{{memfd}}",
+        ])
+        .unwrap();
+        assert_eq!(args.exec.program_key.as_deref(), Some("bin/llama-server"));
+        assert_eq!(args.exec.program_sha256.as_deref(), Some("abc123"));
+        assert_eq!(args.exec.command[0], "llama-server");
+
+        // A checksum only makes sense for a downloaded program
+        assert!(Args::try_parse_from(["s3mem-run", "--program-sha256", "abc", "program"]).is_err());
+    }
+
+    #[test]
+    fn test_supervise_parsing() {
+        let args = Args::try_parse_from([
+            "s3mem-run", "--supervise", "--max-restarts", "2", "program",
+        ])
+        .unwrap();
+        assert!(args.exec.supervise);
+        assert_eq!(args.exec.max_restarts, 2);
+
+        let args = Args::try_parse_from(["s3mem-run", "program"]).unwrap();
+        assert!(!args.exec.supervise);
+        assert_eq!(args.exec.max_restarts, 5);
+    }
+
+    #[test]
+    fn test_progress_listen_parsing() {
+        let args = Args::try_parse_from([
+            "s3mem-run", "--progress-listen", "0.0.0.0:8080", "program",
+        ])
+        .unwrap();
+        assert_eq!(args.exec.progress_listen, Some("0.0.0.0:8080".parse().unwrap()));
+        assert!(Args::try_parse_from(["s3mem-run", "--progress-listen", "8080", "program"]).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_emf_metrics_parsing() {
+        let args = Args::try_parse_from(["s3mem-run", "--emf-metrics", "program"]).unwrap();
+        assert!(args.exec.emf_metrics);
+        assert_eq!(args.exec.metrics_namespace, "s3mem-run");
+        assert!(Args::try_parse_from(["s3mem-run", "--metrics-namespace", "llm", "program"]).is_err());
+    }
+
+    #[test]
+    fn test_otlp_endpoint_parsing() {
+        let args = Args::try_parse_from(["s3mem-run", "--otlp-endpoint", "http://localhost:4318", "program"]).unwrap();
+        assert_eq!(args.otlp_endpoint.unwrap().to_string(), "http://localhost:4318");
+        assert!(Args::try_parse_from(["s3mem-run", "--otlp-endpoint", "https://collector", "program"]).is_err());
+    }
+
+    #[test]
+    fn test_error_format_parsing() {
+        let args = Args::try_parse_from(["s3mem-run", "--error-format", "json", "program"]).unwrap();
+        assert_eq!(args.error_format, error::ErrorFormat::Json);
+        let args = Args::try_parse_from(["s3mem-run", "program"]).unwrap();
+        assert_eq!(args.error_format, error::ErrorFormat::Text);
+    }
+
+    #[test]
+    fn test_report_parsing() {
+        let args = Args::try_parse_from(["s3mem-run", "--report", "env", "program"]).unwrap();
+        assert_eq!(args.exec.report, Some(report::ReportTarget::Env));
+        let args = Args::try_parse_from(["s3mem-run", "--report", "/tmp/report.json", "program"]).unwrap();
+        assert_eq!(args.exec.report, Some(report::ReportTarget::File("/tmp/report.json".into())));
+        let args = Args::try_parse_from(["s3mem-run", "program"]).unwrap();
+        assert!(args.exec.report.is_none());
+    }
+
+    #[test]
+    fn test_progress_parsing() {
+        let args = Args::try_parse_from(["s3mem-run", "program"]).unwrap();
+        assert_eq!(args.exec.progress.progress, progress_display::ProgressMode::Auto);
+        assert_eq!(args.exec.progress.progress_interval, 10);
+
+        let args = Args::try_parse_from(["s3mem-run", "--progress", "log", "--progress-interval", "30", "program"]).unwrap();
+        assert_eq!(args.exec.progress.progress, progress_display::ProgressMode::Log);
+        assert_eq!(args.exec.progress.progress_interval, 30);
+        assert!(Args::try_parse_from(["s3mem-run", "--progress-interval", "0", "program"]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_options_parsing() {
+        let args = Args::try_parse_from([
+            "s3mem-run", "--chunk-strategy", "adaptive", "--min-chunk-size", "8M", "--max-concurrency", "32", "program",
+        ])
+        .unwrap();
+        assert_eq!(args.exec.chunks.chunk_strategy, Some(s3mem_run::ChunkStrategy::Adaptive));
+        let limits = args.exec.chunks.limits().unwrap();
+        assert_eq!(limits.min_chunk_size, 8 << 20);
+        assert_eq!(limits.max_concurrency, 32);
+
+        let args = Args::try_parse_from(["s3mem-run", "fetch", "--key", "k", "--chunk-size", "16M"]).unwrap();
+        match args.subcommand {
+            Some(Commands::Fetch(fetch_args)) => assert_eq!(fetch_args.chunks.chunk_size, Some(16 << 20)),
+            other => panic!("expected fetch subcommand, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hot_swap_parsing() {
+        let args = Args::try_parse_from([
+            "s3mem-run", "--watch-interval", "60", "--listen", "0.0.0.0:8080",
+            "llama-server", "--port", "{{port}}",
+        ])
+        .unwrap();
+        assert_eq!(args.exec.watch_interval, Some(60));
+        assert_eq!(args.exec.health_path, "/health");
+
+        // Swapping needs the public address, and doesn't combine with supervising
+        assert!(Args::try_parse_from(["s3mem-run", "--watch-interval", "60", "program"]).is_err());
+        assert!(Args::try_parse_from([
+            "s3mem-run", "--watch-interval", "60", "--listen", "0.0.0.0:8080", "--supervise", "program",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_subcommand_parsing() {
+        // exec takes the same options as the flat invocation
+        let args = Args::try_parse_from([
+            "s3mem-run", "exec", "--bucket", "models", "--supervise", "--log-level", "debug", "program", "arg1",
+        ])
+        .unwrap();
+        assert_eq!(args.log_level, Level::DEBUG);
+        let exec = args.exec_args().unwrap();
+        assert_eq!(exec.bucket.as_deref(), Some("models"));
+        assert!(exec.supervise);
+        assert_eq!(exec.command, vec!["program", "arg1"]);
+
+        let args = Args::try_parse_from(["s3mem-run", "--key", "k", "program"]).unwrap();
+        assert_eq!(args.exec_args().unwrap().command, vec!["program"]);
+
+        let args = Args::try_parse_from([
+            "s3mem-run", "fetch", "--key", "model.gguf", "-o", "/tmp/model.gguf", "--verify-gguf", "--progress", "off",
+        ])
+        .unwrap();
+        assert!(args.exec_args().is_none());
+        match args.subcommand {
+            Some(Commands::Fetch(fetch_args)) => {
+                assert_eq!(fetch_args.output, PathBuf::from("/tmp/model.gguf"));
+                assert!(fetch_args.verify_gguf);
+                assert_eq!(fetch_args.progress.progress, progress_display::ProgressMode::Off);
+            }
+            other => panic!("expected fetch subcommand, got {:?}", other),
+        }
+        match Args::try_parse_from(["s3mem-run", "fetch", "--key", "k"]).unwrap().subcommand {
+            Some(Commands::Fetch(fetch_args)) => assert_eq!(fetch_args.output, PathBuf::from("-")),
+            other => panic!("expected fetch subcommand, got {:?}", other),
+        }
+
+        match Args::try_parse_from(["s3mem-run", "bench", "--key", "k", "--format", "json"]).unwrap().subcommand {
+            Some(Commands::Bench(bench_args)) => assert_eq!(bench_args.format, bench::BenchFormat::Json),
+            other => panic!("expected bench subcommand, got {:?}", other),
+        }
+        match Args::try_parse_from(["s3mem-run", "doctor", "--program", "llama-server", "--format", "json"]).unwrap().subcommand {
+            Some(Commands::Doctor(doctor_args)) => {
+                assert_eq!(doctor_args.program.as_deref(), Some("llama-server"));
+                assert_eq!(doctor_args.format, doctor::DoctorFormat::Json);
+            }
+            other => panic!("expected doctor subcommand, got {:?}", other),
+        }
+
+        // Exec options only go with exec or the flat invocation
+        assert!(Args::try_parse_from(["s3mem-run", "fetch", "--supervise", "--key", "k"]).is_err());
+        assert!(Args::try_parse_from(["s3mem-run", "exec", "--bucket", "models"]).is_err());
+    }
+}
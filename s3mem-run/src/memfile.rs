@@ -0,0 +1,233 @@
+// Memory files: anonymous files created with memfd_create that exist only in RAM
+// Once a download is complete the file is sealed against writes and resizing, so its
+// contents can't change any more and a read-only mapping of it is safe to hand out.
+
+use anyhow::{Context, Result};                // Error handling with context
+use libc::memfd_create;                       // Linux system call for memory files
+use std::ffi::CString;                        // C-compatible strings for FFI
+use std::io::{Seek, SeekFrom, Write};         // I/O operations
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};  // File descriptor access
+use std::path::PathBuf;                       // /proc paths of memory files
+
+// Seals that make the contents of a memory file immutable
+const CONTENT_SEALS: i32 = libc::F_SEAL_WRITE | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+
+// MemFile represents a file that exists only in memory
+// This is the core data structure that allows us to avoid disk I/O
+#[derive(Debug)]
+pub struct MemFile {
+    file: std::fs::File,  // Standard file handle for I/O operations
+    fd: i32,              // Raw file descriptor for passing to other processes
+}
+
+impl MemFile {
+    // Create a new memory-backed file using memfd_create
+    pub(crate) fn new(name: &str) -> Result<Self> {
+        Self::with_flags(name, 0)
+    }
+
+    // Create a memory-backed file that can be executed
+    // Kernels with vm.memfd_noexec set need MFD_EXEC for that; older kernels don't
+    // know the flag and reject it, in which case every memfd is executable anyway
+    pub(crate) fn new_executable(name: &str) -> Result<Self> {
+        match Self::with_flags(name, libc::MFD_EXEC) {
+            Err(err)
+                if err
+                    .downcast_ref::<std::io::Error>()
+                    .and_then(std::io::Error::raw_os_error)
+                    == Some(libc::EINVAL) =>
+            {
+                Self::with_flags(name, 0)
+            }
+            result => result,
+        }
+    }
+
+    // Create a memory-backed file with the given memfd_create flags
+    // Sealing is always allowed, so the file can be made immutable once it is written, and
+    // the file is closed on exec, so only a program it is handed to on purpose inherits it
+    fn with_flags(name: &str, flags: u32) -> Result<Self> {
+        // Convert Rust string to C string for the system call
+        let name = CString::new(name)?;
+
+        // Create an in-memory file using the Linux-specific memfd_create syscall
+        // This creates a file that exists only in memory, not on disk
+        let fd = unsafe { memfd_create(name.as_ptr(), flags | libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC) };
+
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error()).context("Failed to create memfd");
+        }
+
+        // Convert the raw file descriptor to a Rust File object for easier handling
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        Ok(MemFile { file, fd })
+    }
+
+    // Write data at a specific offset in the memory file
+    // This is used to write downloaded chunks directly to their correct position
+    pub(crate) fn write_at(&mut self, data: &[u8], offset: u64) -> Result<()> {
+        // Seek to the specified position in the file
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .context("Failed to seek in memfd")?;
+
+        // Write the data at that position
+        self.file
+            .write_all(data)
+            .context("Failed to write to memfd")?;
+        Ok(())
+    }
+
+    // Seal the file against writes and size changes, for good
+    pub(crate) fn seal(&self) -> Result<()> {
+        if unsafe { libc::fcntl(self.fd, libc::F_ADD_SEALS, CONTENT_SEALS) } == -1 {
+            return Err(std::io::Error::last_os_error()).context("Failed to seal memfd");
+        }
+        Ok(())
+    }
+
+    // Whether the contents can no longer change
+    pub fn is_sealed(&self) -> bool {
+        let seals = unsafe { libc::fcntl(self.fd, libc::F_GET_SEALS) };
+        seals != -1 && seals & CONTENT_SEALS == CONTENT_SEALS
+    }
+
+    // The file descriptor, which stays open for as long as the MemFile lives
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    // The path other programs can open the file at while this process is alive
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.fd))
+    }
+
+    // The size of the file in bytes
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata().context("Failed to get memfd size")?.len())
+    }
+
+    // The file for reading, with std::os::unix::fs::FileExt::read_at for example
+    pub fn file(&self) -> &std::fs::File {
+        &self.file
+    }
+
+    // Map the whole file read-only
+    // Only sealed files can be mapped, since a write or truncation through another
+    // descriptor would change the bytes behind the returned slice
+    pub fn mmap(&self) -> Result<Mmap> {
+        if !self.is_sealed() {
+            anyhow::bail!("Only sealed memory files can be mapped");
+        }
+        let len = usize::try_from(self.size()?).context("Memory file is too large to map")?;
+        if len == 0 {
+            // mmap rejects empty mappings
+            return Ok(Mmap { ptr: std::ptr::null_mut(), len: 0 });
+        }
+
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, self.fd, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).context("Failed to map memfd");
+        }
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl AsFd for MemFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl AsRawFd for MemFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+// A read-only mapping of a sealed memory file
+// The mapping keeps the file's memory alive on its own, so it may outlive the MemFile
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: *mut libc::c_void,  // Start of the mapping, null when empty
+    len: usize,              // Length of the mapping in bytes
+}
+
+// The mapping is read-only and its file is sealed, so it can be shared between threads
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memfile_creation() {
+        let memfile = MemFile::new("test_file").unwrap();
+        assert!(memfile.fd > 0);
+        assert_eq!(memfile.path(), PathBuf::from(format!("/proc/self/fd/{}", memfile.fd())));
+    }
+
+    #[test]
+    fn test_memfile_write() {
+        let mut memfile = MemFile::new("test_file").unwrap();
+        let test_data = b"Hello, World!";
+        memfile.write_at(test_data, 0).unwrap();
+
+        // Verify the write by reading back
+        use std::io::Read;
+        let mut buffer = Vec::new();
+        memfile.file.seek(SeekFrom::Start(0)).unwrap();
+        memfile.file.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, test_data);
+        assert_eq!(memfile.size().unwrap(), test_data.len() as u64);
+    }
+
+    #[test]
+    fn test_sealed_memfile_mmap() {
+        let mut memfile = MemFile::new("test_file").unwrap();
+        memfile.write_at(b"model bytes", 0).unwrap();
+
+        // Mapping an unsealed file would let the slice change underneath
+        assert!(memfile.mmap().is_err());
+
+        memfile.seal().unwrap();
+        assert!(memfile.is_sealed());
+        assert!(memfile.write_at(b"x", 0).is_err());
+
+        let map = memfile.mmap().unwrap();
+        drop(memfile);
+        assert_eq!(&map[..], b"model bytes");
+
+        let empty = MemFile::new("empty").unwrap();
+        empty.seal().unwrap();
+        assert!(empty.mmap().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+// Cold start metrics in CloudWatch Embedded Metric Format
+// A JSON line on stdout with an "_aws" member is turned into metrics by CloudWatch Logs,
+// so Lambda functions get metrics without a PutMetricData call or an agent. One line is
+// written once every file is in memory, just before the program starts.
+
+use serde_json::{json, Map, Value};           // EMF lines
+use std::io::Write;                           // Writing the line to stdout
+use std::time::{Duration, SystemTime, UNIX_EPOCH};  // Measured times and the timestamp
+
+pub const DEFAULT_NAMESPACE: &str = "s3mem-run";
+const MEMORY_SIZE_ENV: &str = "AWS_LAMBDA_FUNCTION_MEMORY_SIZE";  // Set by Lambda, in MB
+
+// Dimension names, in the order of the dimension set
+const MODEL_KEY: &str = "ModelKey";
+const MEMORY_SIZE: &str = "MemorySize";
+
+// The metrics of one cold start
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColdStartMetrics {
+    pub download_duration: Duration,      // From the first HEAD request to the last chunk
+    pub bytes_downloaded: u64,            // Bytes of every chunk
+    pub throughput_mbps: f64,             // Megabytes per second over the download
+    pub chunk_retries: u64,               // Chunk requests that were retried
+    pub memfd_size_bytes: u64,            // Combined size of the memory files
+    pub time_to_exec: Duration,           // From s3mem-run starting to the program starting
+}
+
+impl ColdStartMetrics {
+    // Take the download figures from the download report
+    pub fn from_report(report: &Value, memfd_size_bytes: u64, time_to_exec: Duration) -> Self {
+        ColdStartMetrics {
+            download_duration: Duration::from_secs_f64(report["wall_time_ms"].as_f64().unwrap_or(0.0) / 1000.0),
+            bytes_downloaded: report["total_bytes"].as_u64().unwrap_or(0),
+            throughput_mbps: report["throughput_mbps"].as_f64().unwrap_or(0.0),
+            chunk_retries: report["chunks"]["retries"].as_u64().unwrap_or(0),
+            memfd_size_bytes,
+            time_to_exec,
+        }
+    }
+}
+
+// The Lambda memory size in MB, or "unknown" outside Lambda
+pub fn memory_size() -> String {
+    std::env::var(MEMORY_SIZE_ENV).unwrap_or_else(|_| "unknown".to_string())
+}
+
+// Build the EMF line for a cold start
+pub fn emf_line(namespace: &str, model_key: &str, memory_size: &str, metrics: &ColdStartMetrics, timestamp_ms: u64) -> Value {
+    let values = [
+        ("DownloadDurationMs", "Milliseconds", json!(millis(metrics.download_duration))),
+        ("BytesDownloaded", "Bytes", json!(metrics.bytes_downloaded)),
+        ("ThroughputMBps", "Megabytes/Second", json!(metrics.throughput_mbps)),
+        ("ChunkRetries", "Count", json!(metrics.chunk_retries)),
+        ("MemfdSizeBytes", "Bytes", json!(metrics.memfd_size_bytes)),
+        ("TimeToExecMs", "Milliseconds", json!(millis(metrics.time_to_exec))),
+    ];
+
+    let mut line = Map::new();
+    line.insert(
+        "_aws".to_string(),
+        json!({
+            "Timestamp": timestamp_ms,
+            "CloudWatchMetrics": [{
+                "Namespace": namespace,
+                "Dimensions": [[MODEL_KEY, MEMORY_SIZE]],
+                "Metrics": values
+                    .iter()
+                    .map(|(name, unit, _)| json!({ "Name": name, "Unit": unit }))
+                    .collect::<Vec<_>>(),
+            }],
+        }),
+    );
+    line.insert(MODEL_KEY.to_string(), json!(model_key));
+    line.insert(MEMORY_SIZE.to_string(), json!(memory_size));
+    for (name, _, value) in values {
+        line.insert(name.to_string(), value);
+    }
+    Value::Object(line)
+}
+
+// Write the EMF line for a cold start to stdout
+pub fn emit(namespace: &str, model_key: &str, metrics: &ColdStartMetrics) -> std::io::Result<()> {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let line = emf_line(namespace, model_key, &memory_size(), metrics, timestamp_ms);
+
+    // Flushed here, since the program that inherits stdout never sees our buffer
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", line)?;
+    stdout.flush()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Units CloudWatch accepts
+    const UNITS: [&str; 27] = [
+        "Seconds", "Microseconds", "Milliseconds", "Bytes", "Kilobytes", "Megabytes", "Gigabytes",
+        "Terabytes", "Bits", "Kilobits", "Megabits", "Gigabits", "Terabits", "Percent", "Count",
+        "Bytes/Second", "Kilobytes/Second", "Megabytes/Second", "Gigabytes/Second",
+        "Terabytes/Second", "Bits/Second", "Kilobits/Second", "Megabits/Second",
+        "Gigabits/Second", "Terabits/Second", "Count/Second", "None",
+    ];
+
+    // Check a line against the EMF specification's JSON schema and its reference rules
+    fn validate_emf(line: &Value) -> Result<(), String> {
+        let root = line.as_object().ok_or("the line is not an object")?;
+        let aws = root.get("_aws").and_then(Value::as_object).ok_or("_aws is missing")?;
+        aws.get("Timestamp").and_then(Value::as_u64).ok_or("Timestamp is not a non-negative integer")?;
+        let directives = aws.get("CloudWatchMetrics").and_then(Value::as_array).ok_or("CloudWatchMetrics is missing")?;
+        if directives.is_empty() {
+            return Err("CloudWatchMetrics is empty".to_string());
+        }
+        for directive in directives {
+            let namespace = directive["Namespace"].as_str().ok_or("Namespace is not a string")?;
+            if namespace.is_empty() || namespace.len() > 255 {
+                return Err(format!("Namespace {:?} has an invalid length", namespace));
+            }
+            for set in directive["Dimensions"].as_array().ok_or("Dimensions is not an array")? {
+                let set = set.as_array().ok_or("a dimension set is not an array")?;
+                if set.len() > 30 {
+                    return Err("a dimension set has more than 30 dimensions".to_string());
+                }
+                for name in set {
+                    let name = name.as_str().ok_or("a dimension name is not a string")?;
+                    root.get(name).and_then(Value::as_str).ok_or(format!("dimension {} has no string value", name))?;
+                }
+            }
+            let metrics = directive["Metrics"].as_array().ok_or("Metrics is not an array")?;
+            if metrics.len() > 100 {
+                return Err("more than 100 metrics".to_string());
+            }
+            for metric in metrics {
+                let name = metric["Name"].as_str().ok_or("a metric name is not a string")?;
+                if name.is_empty() || name.len() > 255 {
+                    return Err(format!("metric name {:?} has an invalid length", name));
+                }
+                if let Some(unit) = metric.get("Unit") {
+                    let unit = unit.as_str().ok_or("a unit is not a string")?;
+                    if !UNITS.contains(&unit) {
+                        return Err(format!("unit {} of {} is not a CloudWatch unit", unit, name));
+                    }
+                }
+                root.get(name).filter(|value| value.is_number()).ok_or(format!("metric {} has no number value", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sample_metrics() -> ColdStartMetrics {
+        ColdStartMetrics {
+            download_duration: Duration::from_millis(1500),
+            bytes_downloaded: 3_000_000,
+            throughput_mbps: 2.0,
+            chunk_retries: 1,
+            memfd_size_bytes: 3_000_000,
+            time_to_exec: Duration::from_millis(1800),
+        }
+    }
+
+    #[test]
+    fn test_emf_line_matches_schema() {
+        let line = emf_line("s3mem-run", "models/llama.gguf", "3008", &sample_metrics(), 1_700_000_000_000);
+        validate_emf(&line).unwrap();
+
+        let directive = &line["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(directive["Dimensions"], json!([["ModelKey", "MemorySize"]]));
+        let names: Vec<&str> = directive["Metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|metric| metric["Name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["DownloadDurationMs", "BytesDownloaded", "ThroughputMBps", "ChunkRetries", "MemfdSizeBytes", "TimeToExecMs"]
+        );
+        assert_eq!(line["_aws"]["Timestamp"], 1_700_000_000_000u64);
+        assert_eq!(line["ModelKey"], "models/llama.gguf");
+        assert_eq!(line["MemorySize"], "3008");
+        assert_eq!(line["DownloadDurationMs"], 1500.0);
+        assert_eq!(line["TimeToExecMs"], 1800.0);
+        assert_eq!(line["ChunkRetries"], 1);
+
+        // The line must fit on one line of output
+        assert!(!line.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn test_validator_rejects_broken_lines() {
+        let mut line = emf_line("s3mem-run", "model.gguf", "unknown", &sample_metrics(), 0);
+        line.as_object_mut().unwrap().remove("MemorySize");
+        assert!(validate_emf(&line).unwrap_err().contains("MemorySize"));
+
+        let mut line = emf_line("", "model.gguf", "unknown", &sample_metrics(), 0);
+        assert!(validate_emf(&line).is_err());
+        line["_aws"]["CloudWatchMetrics"][0]["Namespace"] = json!("s3mem-run");
+        line["_aws"]["CloudWatchMetrics"][0]["Metrics"][0]["Unit"] = json!("Millis");
+        assert!(validate_emf(&line).unwrap_err().contains("Millis"));
+    }
+
+    #[test]
+    fn test_metrics_from_report() {
+        let report = json!({
+            "wall_time_ms": 1500.0,
+            "total_bytes": 3_000_000,
+            "throughput_mbps": 2.0,
+            "chunks": { "retries": 1 },
+        });
+        let metrics = ColdStartMetrics::from_report(&report, 3_000_000, Duration::from_millis(1800));
+        assert_eq!(metrics, sample_metrics());
+    }
+}
@@ -0,0 +1,478 @@
+// How objects are split into ranged GET requests
+// A ChunkPlanner turns an object's size (and, for multipart uploads, its part size) into
+// the byte ranges the loader requests. Every plan must cover [0, size) exactly once, in
+// order; the loader checks that again before downloading. The size-scaled defaults come
+// from ChunkLimits, which can be overridden at runtime instead of recompiling.
+
+use crate::error::fail;                       // Typed errors
+use anyhow::Result;                           // Error handling
+use std::ops::Range;                          // Planned byte ranges
+
+// Default values that can be overridden based on file size
+// These constants control the download behavior and are tuned for optimal performance
+pub const MIN_CHUNK_SIZE: u64 = 4 * 1024 * 1024;      // 4MB minimum chunk size
+pub const MAX_CHUNK_SIZE: u64 = 128 * 1024 * 1024;    // 128MB maximum chunk size
+pub const MIN_CONCURRENT_DOWNLOADS: usize = 4;         // Minimum number of parallel downloads
+pub const MAX_CONCURRENT_DOWNLOADS: usize = 16;        // Maximum number of parallel downloads
+pub const TARGET_CHUNKS_PER_FILE: u64 = 75;           // Target ~75 chunks per file for balanced parallelism
+
+// Bounds of what can be configured, so a mistyped size can't plan billions of requests
+pub const MIN_ALLOWED_CHUNK_SIZE: u64 = 64 * 1024;    // Smallest chunk of any strategy
+pub const MAX_CHUNKS_PER_OBJECT: u64 = 100_000;       // Most ranged requests for one object
+pub const MAX_ALLOWED_CONCURRENCY: usize = 256;       // Most parallel requests
+
+// Bounds of the chunk sizes and concurrency scaled with the object size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLimits {
+    pub min_chunk_size: u64,
+    pub max_chunk_size: u64,
+    pub target_chunks: u64,               // Chunks per object before the size bounds apply
+    pub min_concurrency: usize,           // For objects up to 0.5GB
+    pub max_concurrency: usize,           // For objects of 10GB and more
+}
+
+impl Default for ChunkLimits {
+    fn default() -> Self {
+        ChunkLimits {
+            min_chunk_size: MIN_CHUNK_SIZE,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            target_chunks: TARGET_CHUNKS_PER_FILE,
+            min_concurrency: MIN_CONCURRENT_DOWNLOADS,
+            max_concurrency: MAX_CONCURRENT_DOWNLOADS,
+        }
+    }
+}
+
+impl ChunkLimits {
+    // Reject limits that can't produce a plan
+    pub fn validate(&self) -> Result<()> {
+        if self.min_chunk_size == 0 || self.target_chunks == 0 || self.min_concurrency == 0 {
+            fail!(InvalidConfiguration, "Chunk sizes, target chunks and concurrency must be at least 1");
+        }
+        if self.min_chunk_size < MIN_ALLOWED_CHUNK_SIZE {
+            fail!(
+                InvalidConfiguration,
+                "The minimum chunk size ({}) is below the smallest allowed, {} bytes",
+                self.min_chunk_size,
+                MIN_ALLOWED_CHUNK_SIZE
+            );
+        }
+        if self.target_chunks > MAX_CHUNKS_PER_OBJECT {
+            fail!(
+                InvalidConfiguration,
+                "The target of {} chunks per object is more than the {} allowed",
+                self.target_chunks,
+                MAX_CHUNKS_PER_OBJECT
+            );
+        }
+        check_concurrency(self.max_concurrency)?;
+        if self.min_chunk_size > self.max_chunk_size {
+            fail!(
+                InvalidConfiguration,
+                "The minimum chunk size ({}) is larger than the maximum ({})",
+                self.min_chunk_size,
+                self.max_chunk_size
+            );
+        }
+        if self.min_concurrency > self.max_concurrency {
+            fail!(
+                InvalidConfiguration,
+                "The minimum concurrency ({}) is larger than the maximum ({})",
+                self.min_concurrency,
+                self.max_concurrency
+            );
+        }
+        Ok(())
+    }
+
+    // Calculate optimal chunk size based on file size
+    // Larger files use larger chunks to reduce the number of S3 requests
+    pub fn chunk_size(&self, file_size: u64) -> u64 {
+        // Target a reasonable number of chunks, clamped so chunks are neither tiny nor huge
+        (file_size / self.target_chunks.max(1)).clamp(self.min_chunk_size, self.max_chunk_size.max(self.min_chunk_size))
+    }
+
+    // Calculate optimal concurrency based on file size
+    // Larger files benefit from more parallelism up to a point
+    #[allow(clippy::let_and_return)]
+    pub fn concurrency(&self, file_size: u64) -> usize {
+        // For smaller files, use fewer concurrent downloads
+        // For larger files, scale up to the maximum
+        let size_gb = file_size as f64 / (1024.0 * 1024.0 * 1024.0);
+        
+        // Scale concurrency linearly from min to max based on file size from 0.5GB to 10GB
+        let concurrency = if size_gb <= 0.5 {
+            self.min_concurrency
+        } else if size_gb >= 10.0 {
+            self.max_concurrency
+        } else {
+            // Linear interpolation between min and max
+            let scale_factor = (size_gb - 0.5) / 9.5; // 0.5GB to 10GB range = 9.5GB
+            let range = self.max_concurrency.saturating_sub(self.min_concurrency);
+            self.min_concurrency + (scale_factor * range as f64).round() as usize
+        };
+        
+        concurrency
+    }
+}
+
+// Reject a number of parallel requests that is zero or too many
+pub fn check_concurrency(concurrency: usize) -> Result<()> {
+    if concurrency == 0 {
+        fail!(InvalidConfiguration, "Chunk size and concurrency must be at least 1");
+    }
+    if concurrency > MAX_ALLOWED_CONCURRENCY {
+        fail!(
+            InvalidConfiguration,
+            "A concurrency of {} is more than the {} allowed",
+            concurrency,
+            MAX_ALLOWED_CONCURRENCY
+        );
+    }
+    Ok(())
+}
+
+// What is known about an object before planning its download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectLayout {
+    pub size: u64,
+    pub part_size: Option<u64>,           // Size of every part but the last, for multipart uploads
+}
+
+// A strategy for splitting an object into ranged requests
+pub trait ChunkPlanner: std::fmt::Debug + Send + Sync {
+    // The byte ranges to request, in order, covering [0, size) exactly once
+    fn plan(&self, object: &ObjectLayout, limits: &ChunkLimits) -> Vec<Range<u64>>;
+
+    // How many ranges plan returns, without building them
+    fn chunk_count(&self, object: &ObjectLayout, limits: &ChunkLimits) -> u64;
+
+    // The plan, unless it has more chunks than an object may be split into
+    fn checked_plan(&self, object: &ObjectLayout, limits: &ChunkLimits) -> Result<Vec<Range<u64>>> {
+        let chunks = self.chunk_count(object, limits);
+        if chunks > MAX_CHUNKS_PER_OBJECT {
+            fail!(
+                InvalidConfiguration,
+                "Splitting {} bytes into {} chunks is more than the {} allowed per object, use larger chunks",
+                object.size,
+                chunks,
+                MAX_CHUNKS_PER_OBJECT
+            );
+        }
+        Ok(self.plan(object, limits))
+    }
+
+    // Whether plan uses the part size, which costs another HEAD request per object
+    fn needs_part_size(&self) -> bool {
+        false
+    }
+
+    // Reject settings that can't produce a plan
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Ranges of `chunk_size` bytes, the last one shorter
+fn even_ranges(size: u64, chunk_size: u64) -> Vec<Range<u64>> {
+    let chunk_size = chunk_size.max(1);
+    (0..size.div_ceil(chunk_size))
+        .map(|index| index * chunk_size..((index + 1) * chunk_size).min(size))
+        .collect()
+}
+
+// Equal chunks scaled with the object size, aiming for ~75 chunks of 4MB to 128MB
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeScaled;
+
+impl ChunkPlanner for SizeScaled {
+    fn plan(&self, object: &ObjectLayout, limits: &ChunkLimits) -> Vec<Range<u64>> {
+        even_ranges(object.size, limits.chunk_size(object.size))
+    }
+
+    fn chunk_count(&self, object: &ObjectLayout, limits: &ChunkLimits) -> u64 {
+        object.size.div_ceil(limits.chunk_size(object.size).max(1))
+    }
+}
+
+// The same number of bytes per request for every object
+#[derive(Debug, Clone, Copy)]
+pub struct FixedSize(pub u64);
+
+impl ChunkPlanner for FixedSize {
+    fn plan(&self, object: &ObjectLayout, _limits: &ChunkLimits) -> Vec<Range<u64>> {
+        even_ranges(object.size, self.0)
+    }
+
+    fn chunk_count(&self, object: &ObjectLayout, _limits: &ChunkLimits) -> u64 {
+        object.size.div_ceil(self.0.max(1))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.0 == 0 {
+            fail!(InvalidConfiguration, "Chunk size and concurrency must be at least 1");
+        }
+        if self.0 < MIN_ALLOWED_CHUNK_SIZE {
+            fail!(
+                InvalidConfiguration,
+                "The chunk size ({}) is below the smallest allowed, {} bytes",
+                self.0,
+                MIN_ALLOWED_CHUNK_SIZE
+            );
+        }
+        Ok(())
+    }
+}
+
+// Chunks that start and end on the part boundaries of a multipart upload
+// S3 stores each part separately, so a range within whole parts avoids stitching parts
+// together; the chunk is the multiple of the part size closest to the size-scaled one.
+// Objects uploaded in one part are planned as SizeScaled does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PartAligned;
+
+impl PartAligned {
+    // Whole parts per chunk, or the size-scaled chunk for an object in one part
+    fn chunk_size(object: &ObjectLayout, limits: &ChunkLimits) -> u64 {
+        match object.part_size {
+            Some(part_size) if part_size > 0 && part_size < object.size => {
+                let parts = (limits.chunk_size(object.size) as f64 / part_size as f64).round().max(1.0) as u64;
+                parts * part_size
+            }
+            _ => limits.chunk_size(object.size),
+        }
+    }
+}
+
+impl ChunkPlanner for PartAligned {
+    fn plan(&self, object: &ObjectLayout, limits: &ChunkLimits) -> Vec<Range<u64>> {
+        even_ranges(object.size, Self::chunk_size(object, limits))
+    }
+
+    fn chunk_count(&self, object: &ObjectLayout, limits: &ChunkLimits) -> u64 {
+        object.size.div_ceil(Self::chunk_size(object, limits).max(1))
+    }
+
+    fn needs_part_size(&self) -> bool {
+        true
+    }
+}
+
+// Chunks that start at the minimum size and double up to the size-scaled one
+// The first requests finish quickly, so every connection is busy and bytes are arriving
+// early, while the bulk of the object goes in fewer, larger requests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Adaptive;
+
+impl ChunkPlanner for Adaptive {
+    fn plan(&self, object: &ObjectLayout, limits: &ChunkLimits) -> Vec<Range<u64>> {
+        let target = limits.chunk_size(object.size);
+        let mut chunk_size = limits.min_chunk_size.clamp(1, target.max(1));
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < object.size {
+            let end = (start + chunk_size).min(object.size);
+            ranges.push(start..end);
+            start = end;
+            chunk_size = (chunk_size * 2).min(target.max(1));
+        }
+        ranges
+    }
+
+    fn chunk_count(&self, object: &ObjectLayout, limits: &ChunkLimits) -> u64 {
+        // The doubling chunks, then the rest in chunks of the target size
+        let target = limits.chunk_size(object.size).max(1);
+        let mut chunk_size = limits.min_chunk_size.clamp(1, target);
+        let (mut start, mut count) = (0, 0);
+        while start < object.size && chunk_size < target {
+            start += chunk_size;
+            count += 1;
+            chunk_size = (chunk_size * 2).min(target);
+        }
+        count + object.size.saturating_sub(start).div_ceil(target)
+    }
+}
+
+// The planners that can be chosen by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ChunkStrategy {
+    #[default]
+    SizeScaled,
+    Fixed,
+    PartAligned,
+    Adaptive,
+}
+
+impl ChunkStrategy {
+    // The planner of this strategy; `chunk_size` is required by fixed and refused by the others
+    pub fn planner(self, chunk_size: Option<u64>) -> Result<std::sync::Arc<dyn ChunkPlanner>> {
+        let planner: std::sync::Arc<dyn ChunkPlanner> = match (self, chunk_size) {
+            (ChunkStrategy::Fixed, Some(bytes)) => std::sync::Arc::new(FixedSize(bytes)),
+            (ChunkStrategy::Fixed, None) => fail!(InvalidConfiguration, "The fixed chunk strategy needs a chunk size"),
+            (_, Some(_)) => fail!(InvalidConfiguration, "A chunk size only applies to the fixed chunk strategy"),
+            (ChunkStrategy::SizeScaled, None) => std::sync::Arc::new(SizeScaled),
+            (ChunkStrategy::PartAligned, None) => std::sync::Arc::new(PartAligned),
+            (ChunkStrategy::Adaptive, None) => std::sync::Arc::new(Adaptive),
+        };
+        planner.validate()?;
+        Ok(planner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_calculate_optimal_chunk_size() {
+        let limits = ChunkLimits::default();
+
+        // Test with small file (512MB)
+        let small_chunk_size = limits.chunk_size(512 * 1024 * 1024);
+        assert!(small_chunk_size >= MIN_CHUNK_SIZE);
+        assert!(small_chunk_size <= MAX_CHUNK_SIZE);
+
+        // Test with medium file (2GB)
+        let medium_chunk_size = limits.chunk_size(2 * 1024 * 1024 * 1024);
+        assert!(medium_chunk_size >= MIN_CHUNK_SIZE);
+        assert!(medium_chunk_size <= MAX_CHUNK_SIZE);
+
+        // Test with large file (10GB)
+        let large_chunk_size = limits.chunk_size(10 * 1024 * 1024 * 1024);
+        assert!(large_chunk_size >= MIN_CHUNK_SIZE);
+        assert!(large_chunk_size <= MAX_CHUNK_SIZE);
+
+        // Verify that larger files get larger chunks
+        assert!(large_chunk_size > small_chunk_size);
+    }
+
+    #[test]
+    fn test_calculate_optimal_concurrency() {
+        let limits = ChunkLimits::default();
+
+        // Test with small file (512MB)
+        assert_eq!(limits.concurrency(512 * 1024 * 1024), MIN_CONCURRENT_DOWNLOADS);
+
+        // Test with large file (10GB)
+        assert_eq!(limits.concurrency(10 * 1024 * 1024 * 1024), MAX_CONCURRENT_DOWNLOADS);
+
+        // Test with medium file (5GB) - should be somewhere in between
+        let medium_concurrency = limits.concurrency(5 * 1024 * 1024 * 1024);
+        assert!(medium_concurrency > MIN_CONCURRENT_DOWNLOADS);
+        assert!(medium_concurrency < MAX_CONCURRENT_DOWNLOADS);
+    }
+
+    #[test]
+    fn test_limits_validation() {
+        assert!(ChunkLimits::default().validate().is_ok());
+        let limits = ChunkLimits { min_chunk_size: 64, max_chunk_size: 32, ..Default::default() };
+        assert!(limits.validate().is_err());
+        let limits = ChunkLimits { min_concurrency: 8, max_concurrency: 4, ..Default::default() };
+        assert!(limits.validate().is_err());
+        let limits = ChunkLimits { target_chunks: 0, ..Default::default() };
+        assert!(limits.validate().is_err());
+
+        // Bounds that would plan a flood of tiny requests or connections
+        let limits = ChunkLimits { min_chunk_size: 1, ..Default::default() };
+        assert!(limits.validate().is_err());
+        let limits = ChunkLimits { min_chunk_size: MIN_ALLOWED_CHUNK_SIZE, ..Default::default() };
+        assert!(limits.validate().is_ok());
+        let limits = ChunkLimits { target_chunks: MAX_CHUNKS_PER_OBJECT + 1, ..Default::default() };
+        assert!(limits.validate().is_err());
+        let limits = ChunkLimits { max_concurrency: MAX_ALLOWED_CONCURRENCY + 1, ..Default::default() };
+        assert!(limits.validate().is_err());
+        assert!(check_concurrency(MAX_ALLOWED_CONCURRENCY).is_ok());
+        assert!(check_concurrency(0).is_err());
+
+        // Limits that pass can still plan too many chunks for a huge object, which is
+        // refused before anything is requested
+        let limits = ChunkLimits { min_chunk_size: MIN_ALLOWED_CHUNK_SIZE, max_chunk_size: MIN_ALLOWED_CHUNK_SIZE, ..Default::default() };
+        let object = ObjectLayout { size: 100 << 30, part_size: None };
+        let err = SizeScaled.checked_plan(&object, &limits).unwrap_err();
+        assert_eq!(crate::error::ErrorKind::of(&err), crate::error::ErrorKind::InvalidConfiguration);
+        assert!(FixedSize(MIN_ALLOWED_CHUNK_SIZE).checked_plan(&object, &limits).is_err());
+        assert_eq!(SizeScaled.checked_plan(&object, &ChunkLimits::default()).unwrap().len(), 800);
+    }
+
+    #[test]
+    fn test_strategy_planners() {
+        assert!(ChunkStrategy::Fixed.planner(Some(MIN_ALLOWED_CHUNK_SIZE)).is_ok());
+        assert!(ChunkStrategy::Fixed.planner(Some(1024)).is_err());
+        assert!(ChunkStrategy::Fixed.planner(Some(0)).is_err());
+        assert!(ChunkStrategy::Fixed.planner(None).is_err());
+        assert!(ChunkStrategy::Adaptive.planner(Some(1024)).is_err());
+        assert!(ChunkStrategy::PartAligned.planner(None).unwrap().needs_part_size());
+    }
+
+    #[test]
+    fn test_part_aligned_and_adaptive_plans() {
+        let limits = ChunkLimits { min_chunk_size: 10, max_chunk_size: 100, target_chunks: 4, ..Default::default() };
+
+        // 1000 bytes scale to 100-byte chunks, the nearest multiple of 32-byte parts is 96
+        let object = ObjectLayout { size: 1000, part_size: Some(32) };
+        let plan = PartAligned.plan(&object, &limits);
+        assert!(plan.iter().all(|range| range.start % 32 == 0));
+        assert_eq!(plan[0], 0..96);
+
+        // Without parts it is the size-scaled plan
+        let object = ObjectLayout { size: 1000, part_size: None };
+        assert_eq!(PartAligned.plan(&object, &limits), SizeScaled.plan(&object, &limits));
+
+        let sizes: Vec<u64> = Adaptive.plan(&object, &limits).iter().map(|range| range.end - range.start).collect();
+        assert_eq!(&sizes[..5], &[10, 20, 40, 80, 100]);
+    }
+
+    // Every byte of [0, size) in exactly one range, in order, none of them empty
+    fn assert_tiles(plan: &[Range<u64>], size: u64) {
+        let mut expected_start = 0;
+        for range in plan {
+            assert_eq!(range.start, expected_start, "gap or overlap in {:?}", plan);
+            assert!(range.end > range.start, "empty range in {:?}", plan);
+            expected_start = range.end;
+        }
+        assert_eq!(expected_start, size);
+    }
+
+    fn limits() -> impl Strategy<Value = ChunkLimits> {
+        (1u64..10_000, 1u64..10_000, 1u64..200).prop_map(|(min, extra, target_chunks)| ChunkLimits {
+            min_chunk_size: min,
+            max_chunk_size: min + extra,
+            target_chunks,
+            ..Default::default()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_every_planner_covers_the_object_once(
+            size in 0u64..2_000_000,
+            part_size in proptest::option::of(1u64..500_000),
+            fixed in 1u64..500_000,
+            limits in limits(),
+        ) {
+            let object = ObjectLayout { size, part_size };
+            let planners: [&dyn ChunkPlanner; 4] = [&SizeScaled, &FixedSize(fixed), &PartAligned, &Adaptive];
+            for planner in planners {
+                let plan = planner.plan(&object, &limits);
+                assert_tiles(&plan, size);
+                assert_eq!(planner.chunk_count(&object, &limits), plan.len() as u64, "{:?}", planner);
+            }
+        }
+
+        #[test]
+        fn test_part_aligned_chunks_end_on_part_boundaries(
+            size in 1u64..2_000_000,
+            part_size in 1u64..500_000,
+            limits in limits(),
+        ) {
+            // An object in one part is planned as SizeScaled does
+            prop_assume!(part_size < size);
+            let object = ObjectLayout { size, part_size: Some(part_size) };
+            for range in PartAligned.plan(&object, &limits) {
+                prop_assert!(range.start % part_size == 0);
+                prop_assert!(range.end % part_size == 0 || range.end == size);
+            }
+        }
+    }
+}
@@ -0,0 +1,859 @@
+// Checks that the target program can actually be executed
+// These run before the download, so a program that would fail to start is reported
+// right away instead of after several gigabytes have been pulled from S3. The program
+// is looked up on PATH the way execvp does, then its execute permission, ELF
+// interpreter or #! interpreter, and shared-library dependencies are checked the way
+// the kernel and the dynamic loader would. Only what certainly stops exec is a problem;
+// a library that isn't found is a warning, since the loader's search can't be
+// reproduced exactly from outside it.
+
+use crate::error::fail;                       // Typed errors
+use anyhow::{Context, Result};                // Error handling with context
+use std::ffi::CString;                        // C-compatible strings for FFI
+use std::fs::File;                            // Reading the program's headers
+use std::os::unix::ffi::OsStrExt;             // Paths as bytes for FFI
+use std::os::unix::fs::FileExt;               // Positional reads
+use std::path::{Path, PathBuf};               // Path manipulation
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";       // First bytes of every ELF file
+const SHEBANG_MAX_LEN: usize = 256;           // Bytes of a #! line the kernel looks at
+const DEFAULT_PATH: &str = "/bin:/usr/bin";   // execvp's search path when PATH is unset
+const LD_SO_CACHE: &str = "/etc/ld.so.cache"; // glibc's dynamic loader cache
+const LD_SO_CACHE_MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+
+// ELF header and dynamic section constants
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+// Look up a program the way execvp does
+// Names containing a slash are used as they are, anything else is searched for in
+// each PATH directory, where an empty entry means the current directory
+pub fn resolve(name: &str) -> Result<PathBuf> {
+    if name.is_empty() {
+        fail!(ProgramNotRunnable, "The program name is empty");
+    }
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        if !path.exists() {
+            fail!(ProgramNotRunnable, "Program '{}' does not exist", name);
+        }
+        return Ok(path);
+    }
+
+    let search_path = std::env::var("PATH").unwrap_or_else(|_| DEFAULT_PATH.to_string());
+    let mut not_executable = Vec::new();
+    for dir in search_path.split(':') {
+        let candidate = if dir.is_empty() { PathBuf::from(name) } else { Path::new(dir).join(name) };
+        match std::fs::metadata(&candidate) {
+            Ok(metadata) if metadata.is_file() && is_executable(&candidate) => return Ok(candidate),
+            // Like execvp, keep looking but remember why this one was skipped
+            Ok(metadata) if metadata.is_file() => not_executable.push(candidate),
+            _ => {}
+        }
+    }
+
+    if !not_executable.is_empty() {
+        fail!(
+            ProgramNotRunnable,
+            "Program '{}' was found on PATH at {} but is not executable, run chmod +x on it",
+            name,
+            join_paths(&not_executable)
+        );
+    }
+    fail!(
+        ProgramNotRunnable,
+        "Program '{}' was not found in any PATH directory ({}), give its full path or add its directory to PATH",
+        name,
+        search_path
+    )
+}
+
+// What was learned about a program, and what would stop it from starting
+#[derive(Debug, Default)]
+pub struct ProgramReport {
+    pub path: PathBuf,                    // Path that was checked
+    pub interpreter: Option<PathBuf>,     // ELF interpreter or #! interpreter
+    pub libraries: Vec<Library>,          // Shared libraries the program and its libraries need
+    pub problems: Vec<String>,            // Everything that would make exec fail
+    pub warnings: Vec<String>,            // What may make the program fail to start
+}
+
+// A shared library dependency and where the dynamic loader would find it
+#[derive(Debug)]
+pub struct Library {
+    pub name: String,                     // DT_NEEDED name, such as libgomp.so.1
+    pub path: Option<PathBuf>,            // Where it was found, None when missing
+}
+
+impl ProgramReport {
+    // Fail with every problem found, if there are any
+    pub fn ensure_runnable(&self) -> Result<()> {
+        if self.problems.is_empty() {
+            return Ok(());
+        }
+        fail!(
+            ProgramNotRunnable,
+            "{} can't be executed:\n  - {}",
+            self.path.display(),
+            self.problems.join("\n  - ")
+        )
+    }
+}
+
+// Check a program the way exec would see it
+// `origin` is the directory $ORIGIN expands to in the program's library search paths;
+// it is None for a program that doesn't live in a directory, such as a memory file
+pub fn check(path: &Path, origin: Option<&Path>) -> Result<ProgramReport> {
+    let mut report = ProgramReport { path: path.to_path_buf(), ..Default::default() };
+
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if metadata.is_dir() {
+        report.problems.push("it is a directory, not a program".to_string());
+        return Ok(report);
+    }
+    if !metadata.is_file() {
+        report.problems.push("it is not a regular file".to_string());
+        return Ok(report);
+    }
+    if !is_executable(path) {
+        report.problems.push(format!("it is not executable, run chmod +x {}", path.display()));
+    }
+    if is_on_noexec_mount(path) {
+        report.problems.push(
+            "it is on a file system mounted noexec, move it to another file system".to_string(),
+        );
+    }
+
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut head = vec![0u8; SHEBANG_MAX_LEN];
+    let len = read_up_to(&file, &mut head, 0)?;
+    head.truncate(len);
+
+    if head.starts_with(b"#!") {
+        check_script(&head, &mut report);
+    } else if head.starts_with(ELF_MAGIC) {
+        check_elf(&file, origin, &mut report)?;
+    } else {
+        report.problems.push("it is neither an ELF binary nor a script starting with #!".to_string());
+    }
+    Ok(report)
+}
+
+// Check the interpreter named on a script's #! line
+fn check_script(head: &[u8], report: &mut ProgramReport) {
+    let line = head[2..].split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.split_whitespace();
+    let Some(interpreter) = words.next() else {
+        report.problems.push("its #! line doesn't name an interpreter".to_string());
+        return;
+    };
+    let interpreter = PathBuf::from(interpreter);
+    report.interpreter = Some(interpreter.clone());
+
+    if !interpreter.is_file() {
+        report.problems.push(format!(
+            "its #! interpreter {} does not exist, install it or change the #! line",
+            interpreter.display()
+        ));
+        return;
+    }
+    if !is_executable(&interpreter) {
+        report.problems.push(format!("its #! interpreter {} is not executable", interpreter.display()));
+        return;
+    }
+
+    // "#!/usr/bin/env NAME" looks NAME up on PATH when the script runs
+    if interpreter.file_name().is_some_and(|name| name == "env") {
+        if let Some(name) = words.next().filter(|word| !word.starts_with('-')) {
+            if let Err(err) = resolve(name) {
+                report.problems.push(format!("its #! line runs {} through env: {}", name, err));
+            }
+        }
+    }
+}
+
+// Check an ELF program's type, architecture, interpreter and shared libraries
+fn check_elf(file: &File, origin: Option<&Path>, report: &mut ProgramReport) -> Result<()> {
+    let elf = match Elf::read(file) {
+        Ok(elf) => elf,
+        Err(err) => {
+            report.problems.push(format!("its ELF headers can't be read: {}", err));
+            return Ok(());
+        }
+    };
+
+    if elf.file_type != ET_EXEC && elf.file_type != ET_DYN {
+        report.problems.push("it is an ELF file but not an executable".to_string());
+        return Ok(());
+    }
+    if let Some(host) = host_machine() {
+        if elf.machine != host {
+            report.problems.push(format!(
+                "it is built for {} but this machine is {}, use a {} build",
+                machine_name(elf.machine),
+                std::env::consts::ARCH,
+                std::env::consts::ARCH
+            ));
+            return Ok(());
+        }
+    }
+
+    let Some(interpreter) = &elf.interpreter else {
+        // Statically linked, nothing else to load
+        return Ok(());
+    };
+    let interpreter = PathBuf::from(interpreter);
+    report.interpreter = Some(interpreter.clone());
+    if !interpreter.is_file() {
+        report.problems.push(format!(
+            "its ELF interpreter {} does not exist, the program was built for a different C library \
+             or distribution; link it statically or build it on the target image",
+            interpreter.display()
+        ));
+        return Ok(());
+    }
+
+    let search = LibrarySearch::new(&elf, &interpreter);
+    search.resolve_all(&elf, origin, report);
+    Ok(())
+}
+
+// The dynamic loader's search order for one program
+struct LibrarySearch {
+    class64: bool,                        // Libraries must match the program's ELF class
+    machine: u16,                         // and architecture, or the loader skips them
+    musl: bool,                           // musl's loader has no cache and other defaults
+    ld_library_path: Vec<String>,         // LD_LIBRARY_PATH entries
+    cache: Vec<(String, PathBuf)>,        // Entries of /etc/ld.so.cache
+    exe_rpath: Vec<String>,               // The program's DT_RPATH, used for all its libraries
+}
+
+impl LibrarySearch {
+    fn new(elf: &Elf, interpreter: &Path) -> Self {
+        let musl = interpreter.to_string_lossy().contains("ld-musl");
+        let ld_library_path = std::env::var("LD_LIBRARY_PATH")
+            .map(|value| value.split([':', ';']).filter(|dir| !dir.is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        LibrarySearch {
+            class64: elf.class64,
+            machine: elf.machine,
+            musl,
+            ld_library_path,
+            cache: if musl { Vec::new() } else { read_ld_so_cache() },
+            exe_rpath: if elf.runpath.is_empty() { elf.rpath.clone() } else { Vec::new() },
+        }
+    }
+
+    // Resolve the libraries of the program and, in turn, of every library found
+    fn resolve_all(&self, elf: &Elf, origin: Option<&Path>, report: &mut ProgramReport) {
+        let mut pending: Vec<(String, Vec<PathBuf>)> = Vec::new();
+        let dirs = self.search_dirs(elf, origin, true);
+        pending.extend(elf.needed.iter().map(|name| (name.clone(), dirs.clone())));
+
+        while let Some((name, dirs)) = pending.pop() {
+            if report.libraries.iter().any(|library| library.name == name) {
+                continue;
+            }
+            let found = self.find(&name, &dirs);
+            match &found {
+                Some((path, library)) => {
+                    let library_origin = path.parent().map(Path::to_path_buf);
+                    let dirs = self.search_dirs(library, library_origin.as_deref(), false);
+                    pending.extend(library.needed.iter().map(|needed| (needed.clone(), dirs.clone())));
+                }
+                None => report.warnings.push(format!(
+                    "shared library {} was not found where the dynamic loader usually looks; if the program \
+                     fails to start, put it in a directory on LD_LIBRARY_PATH (such as /opt/lib in a Lambda \
+                     layer) or next to the binary with an $ORIGIN run path",
+                    name
+                )),
+            }
+            report.libraries.push(Library { name, path: found.map(|(path, _)| path) });
+        }
+    }
+
+    // Directories to search for the libraries an object needs, in the loader's order
+    // The ld.so.cache and default directories are searched by `find` after these
+    fn search_dirs(&self, elf: &Elf, origin: Option<&Path>, is_program: bool) -> Vec<PathBuf> {
+        let expand = |dirs: &[String]| -> Vec<PathBuf> {
+            dirs.iter().filter_map(|dir| expand_origin(dir, origin, self.class64)).collect()
+        };
+
+        let mut dirs = Vec::new();
+        if elf.runpath.is_empty() {
+            dirs.extend(expand(&elf.rpath));
+            if !is_program {
+                dirs.extend(expand(&self.exe_rpath));
+            }
+        }
+        dirs.extend(self.ld_library_path.iter().map(PathBuf::from));
+        dirs.extend(expand(&elf.runpath));
+        dirs
+    }
+
+    // Find a library by name, returning its path and parsed headers
+    fn find(&self, name: &str, dirs: &[PathBuf]) -> Option<(PathBuf, Elf)> {
+        if name.contains('/') {
+            return self.matching(Path::new(name));
+        }
+        for dir in dirs {
+            if let Some(found) = self.matching(&dir.join(name)) {
+                return Some(found);
+            }
+        }
+        for (cached, path) in &self.cache {
+            if cached == name {
+                if let Some(found) = self.matching(path) {
+                    return Some(found);
+                }
+            }
+        }
+        self.default_dirs().iter().find_map(|dir| {
+            // glibc prefers builds for the CPU's feature level in glibc-hwcaps subdirectories
+            let hwcaps = match self.musl {
+                true => Vec::new(),
+                false => std::fs::read_dir(dir.join("glibc-hwcaps"))
+                    .map(|entries| entries.filter_map(|entry| Some(entry.ok()?.path())).collect())
+                    .unwrap_or_default(),
+            };
+            let found = hwcaps.iter().chain([dir]).find_map(|dir| self.matching(&dir.join(name)));
+            found
+        })
+    }
+
+    // Directories searched when the cache has no entry, with the multiarch directories
+    // Debian and Ubuntu list in ld.so.conf, in case the cache is missing or stale
+    fn default_dirs(&self) -> Vec<PathBuf> {
+        let defaults: &[&str] = match (self.musl, self.class64) {
+            (true, _) => &["/lib", "/usr/local/lib", "/usr/lib"],
+            (false, true) => &["/lib64", "/usr/lib64", "/lib", "/usr/lib"],
+            (false, false) => &["/lib", "/usr/lib"],
+        };
+        let mut dirs: Vec<PathBuf> = defaults.iter().map(PathBuf::from).collect();
+        if let (false, Some(triplet)) = (self.musl, multiarch_triplet(self.machine)) {
+            for base in ["/usr/local/lib", "/lib", "/usr/lib"] {
+                dirs.push(Path::new(base).join(triplet));
+            }
+        }
+        dirs
+    }
+
+    // A library file the loader would accept for this program
+    fn matching(&self, path: &Path) -> Option<(PathBuf, Elf)> {
+        let file = File::open(path).ok()?;
+        let elf = Elf::read(&file).ok()?;
+        (elf.class64 == self.class64 && elf.machine == self.machine).then(|| (path.to_path_buf(), elf))
+    }
+}
+
+// Expand $ORIGIN, ${ORIGIN}, $LIB and ${LIB} in a run path entry
+// Entries using $ORIGIN are dropped when there is no origin directory
+fn expand_origin(dir: &str, origin: Option<&Path>, class64: bool) -> Option<PathBuf> {
+    let mut dir = dir.to_string();
+    if dir.contains("$ORIGIN") || dir.contains("${ORIGIN}") {
+        let origin = origin?.to_string_lossy().into_owned();
+        dir = dir.replace("${ORIGIN}", &origin).replace("$ORIGIN", &origin);
+    }
+    let lib = if class64 { "lib64" } else { "lib" };
+    dir = dir.replace("${LIB}", lib).replace("$LIB", lib);
+    (!dir.is_empty()).then(|| PathBuf::from(dir))
+}
+
+// The parts of an ELF file that matter for starting it
+#[derive(Debug, Default)]
+struct Elf {
+    class64: bool,                        // ELFCLASS64 rather than ELFCLASS32
+    file_type: u16,                       // e_type
+    machine: u16,                         // e_machine
+    interpreter: Option<String>,          // PT_INTERP
+    needed: Vec<String>,                  // DT_NEEDED entries
+    rpath: Vec<String>,                   // DT_RPATH entries
+    runpath: Vec<String>,                 // DT_RUNPATH entries
+}
+
+// A program header, widened to 64 bits
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+impl Elf {
+    // Read the ELF header, program headers and dynamic section
+    fn read(file: &File) -> Result<Self> {
+        let mut ident = [0u8; 64];
+        let len = read_up_to(file, &mut ident, 0)?;
+        if len < 52 || &ident[..4] != ELF_MAGIC {
+            anyhow::bail!("not an ELF file");
+        }
+        let class64 = match ident[4] {
+            1 => false,
+            2 => true,
+            class => anyhow::bail!("unknown ELF class {}", class),
+        };
+        let little = match ident[5] {
+            1 => true,
+            2 => false,
+            data => anyhow::bail!("unknown ELF data encoding {}", data),
+        };
+        if class64 && len < 64 {
+            anyhow::bail!("truncated ELF header");
+        }
+        let fields = Fields { little };
+
+        let mut elf = Elf {
+            class64,
+            file_type: fields.u16(&ident, 16),
+            machine: fields.u16(&ident, 18),
+            ..Default::default()
+        };
+        let (phoff, phentsize, phnum) = if class64 {
+            (fields.u64(&ident, 32), fields.u16(&ident, 54), fields.u16(&ident, 56))
+        } else {
+            (fields.u32(&ident, 28) as u64, fields.u16(&ident, 42), fields.u16(&ident, 44))
+        };
+        let min_entsize = if class64 { 56 } else { 32 };
+        if phnum > 0 && (phentsize as usize) < min_entsize {
+            anyhow::bail!("invalid program header size {}", phentsize);
+        }
+
+        // Both sizes come from the header, so the table is bounded like any other segment
+        let table = read_segment(file, phoff, phentsize as u64 * phnum as u64).context("invalid program headers")?;
+        let headers: Vec<ProgramHeader> = table
+            .chunks_exact(phentsize.max(1) as usize)
+            .map(|entry| {
+                if class64 {
+                    ProgramHeader {
+                        kind: fields.u32(entry, 0),
+                        offset: fields.u64(entry, 8),
+                        vaddr: fields.u64(entry, 16),
+                        filesz: fields.u64(entry, 32),
+                    }
+                } else {
+                    ProgramHeader {
+                        kind: fields.u32(entry, 0),
+                        offset: fields.u32(entry, 4) as u64,
+                        vaddr: fields.u32(entry, 8) as u64,
+                        filesz: fields.u32(entry, 16) as u64,
+                    }
+                }
+            })
+            .collect();
+
+        if let Some(interp) = headers.iter().find(|header| header.kind == PT_INTERP) {
+            let bytes = read_segment(file, interp.offset, interp.filesz)?;
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            elf.interpreter = Some(String::from_utf8_lossy(&bytes[..end]).into_owned());
+        }
+
+        if let Some(dynamic) = headers.iter().find(|header| header.kind == PT_DYNAMIC) {
+            let bytes = read_segment(file, dynamic.offset, dynamic.filesz)?;
+            let entry_size = if class64 { 16 } else { 8 };
+            let mut entries = Vec::new();
+            for entry in bytes.chunks_exact(entry_size) {
+                let (tag, value) = if class64 {
+                    (fields.u64(entry, 0), fields.u64(entry, 8))
+                } else {
+                    (fields.u32(entry, 0) as u64, fields.u32(entry, 4) as u64)
+                };
+                if tag == DT_NULL {
+                    break;
+                }
+                entries.push((tag, value));
+            }
+
+            let find_tag = |tag| entries.iter().find(|(t, _)| *t == tag).map(|(_, value)| *value);
+            if let (Some(strtab), Some(strsz)) = (find_tag(DT_STRTAB), find_tag(DT_STRSZ)) {
+                // DT_STRTAB is an address, map it back to a file offset through PT_LOAD
+                let offset = headers
+                    .iter()
+                    .filter(|header| header.kind == PT_LOAD)
+                    .find(|header| strtab >= header.vaddr && strtab - header.vaddr < header.filesz)
+                    .map(|header| strtab - header.vaddr + header.offset)
+                    .context("dynamic string table is outside every loadable segment")?;
+                let strings = read_segment(file, offset, strsz)?;
+                let string_at = |index: u64| -> String {
+                    let start = (index as usize).min(strings.len());
+                    let end = strings[start..].iter().position(|&b| b == 0).map_or(strings.len(), |end| start + end);
+                    String::from_utf8_lossy(&strings[start..end]).into_owned()
+                };
+                let split = |value: u64| -> Vec<String> {
+                    string_at(value).split(':').map(String::from).collect()
+                };
+
+                for &(tag, value) in &entries {
+                    match tag {
+                        DT_NEEDED => elf.needed.push(string_at(value)),
+                        DT_RPATH => elf.rpath.extend(split(value)),
+                        DT_RUNPATH => elf.runpath.extend(split(value)),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(elf)
+    }
+}
+
+// Reads fixed-width integers in the file's byte order
+struct Fields {
+    little: bool,
+}
+
+impl Fields {
+    fn u16(&self, buf: &[u8], at: usize) -> u16 {
+        let bytes = [buf[at], buf[at + 1]];
+        if self.little { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) }
+    }
+
+    fn u32(&self, buf: &[u8], at: usize) -> u32 {
+        let bytes = buf[at..at + 4].try_into().unwrap();
+        if self.little { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    }
+
+    fn u64(&self, buf: &[u8], at: usize) -> u64 {
+        let bytes = buf[at..at + 8].try_into().unwrap();
+        if self.little { u64::from_le_bytes(bytes) } else { u64::from_be_bytes(bytes) }
+    }
+}
+
+// Read a segment of a bounded size, since the sizes come from the file itself
+// Nothing is allocated for a segment that extends past the end of the file
+fn read_segment(file: &File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    if len > 16 * 1024 * 1024 {
+        anyhow::bail!("segment of {} bytes is implausibly large", len);
+    }
+    let file_len = file.metadata().context("Failed to get the file size")?.len();
+    if offset.checked_add(len).is_none_or(|end| end > file_len) {
+        anyhow::bail!("truncated segment");
+    }
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact_at(&mut buf, offset).context("truncated segment")?;
+    Ok(buf)
+}
+
+// Read as many bytes as are available, up to the size of the buffer
+fn read_up_to(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = file.read_at(&mut buf[filled..], offset + filled as u64)?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+// Read the library names and paths in glibc's ld.so.cache
+// Only the current format is understood; anything else leaves the cache out of the search
+fn read_ld_so_cache() -> Vec<(String, PathBuf)> {
+    let Ok(data) = std::fs::read(LD_SO_CACHE) else {
+        return Vec::new();
+    };
+    parse_ld_so_cache(&data).unwrap_or_default()
+}
+
+fn parse_ld_so_cache(data: &[u8]) -> Option<Vec<(String, PathBuf)>> {
+    // Old caches put the current format after a legacy table, so look for its magic
+    let start = data.windows(LD_SO_CACHE_MAGIC.len()).position(|window| window == LD_SO_CACHE_MAGIC)?;
+    let cache = &data[start..];
+    let fields = Fields { little: cfg!(target_endian = "little") };
+    let nlibs = fields.u32(cache.get(..48)?, 20) as usize;
+
+    let string_at = |offset: u32| -> Option<String> {
+        let bytes = cache.get(offset as usize..)?;
+        let end = bytes.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    };
+
+    let mut entries = Vec::with_capacity(nlibs);
+    for index in 0..nlibs {
+        let entry = cache.get(48 + index * 24..48 + (index + 1) * 24)?;
+        let key = string_at(fields.u32(entry, 4))?;
+        let value = string_at(fields.u32(entry, 8))?;
+        entries.push((key, PathBuf::from(value)));
+    }
+    Some(entries)
+}
+
+// Whether the current user may execute a file
+fn is_executable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::X_OK) == 0 }
+}
+
+// Whether a file is on a file system that doesn't allow exec
+fn is_on_noexec_mount(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    unsafe { libc::statvfs(path.as_ptr(), &mut stat) == 0 && stat.f_flag & libc::ST_NOEXEC != 0 }
+}
+
+// The ELF machine number of the architecture this binary was built for
+fn host_machine() -> Option<u16> {
+    match std::env::consts::ARCH {
+        "x86" => Some(3),
+        "arm" => Some(40),
+        "x86_64" => Some(62),
+        "aarch64" => Some(183),
+        "riscv64" => Some(243),
+        "powerpc64" => Some(21),
+        "s390x" => Some(22),
+        _ => None,
+    }
+}
+
+// A readable name for an ELF machine number
+fn machine_name(machine: u16) -> String {
+    match machine {
+        3 => "x86".to_string(),
+        40 => "arm".to_string(),
+        62 => "x86_64".to_string(),
+        183 => "aarch64".to_string(),
+        243 => "riscv64".to_string(),
+        21 => "powerpc64".to_string(),
+        22 => "s390x".to_string(),
+        other => format!("ELF machine {}", other),
+    }
+}
+
+// Debian's multiarch directory name for an architecture's glibc libraries
+fn multiarch_triplet(machine: u16) -> Option<&'static str> {
+    match machine {
+        3 => Some("i386-linux-gnu"),
+        40 => Some("arm-linux-gnueabihf"),
+        62 => Some("x86_64-linux-gnu"),
+        183 => Some("aarch64-linux-gnu"),
+        243 => Some("riscv64-linux-gnu"),
+        21 => Some("powerpc64le-linux-gnu"),
+        22 => Some("s390x-linux-gnu"),
+        _ => None,
+    }
+}
+
+// Join paths for an error message
+fn join_paths(paths: &[PathBuf]) -> String {
+    paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Build a minimal little-endian ELF64 file for this machine with an interpreter,
+    // DT_NEEDED entries and an optional DT_RUNPATH
+    fn build_elf(file_type: u16, interpreter: Option<&str>, needed: &[&str], runpath: Option<&str>) -> Vec<u8> {
+        let phnum = 3u16;
+        let mut interp = interpreter.map(|i| format!("{}\0", i).into_bytes()).unwrap_or_default();
+        let interp_offset = 64 + 56 * phnum as u64;
+
+        let mut strtab = vec![0u8];
+        let mut dynamic = Vec::new();
+        for name in needed {
+            dynamic.push((DT_NEEDED, strtab.len() as u64));
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        if let Some(runpath) = runpath {
+            dynamic.push((DT_RUNPATH, strtab.len() as u64));
+            strtab.extend_from_slice(runpath.as_bytes());
+            strtab.push(0);
+        }
+        let strtab_offset = interp_offset + interp.len() as u64;
+        let dynamic_offset = strtab_offset + strtab.len() as u64;
+        dynamic.push((DT_STRTAB, strtab_offset));
+        dynamic.push((DT_STRSZ, strtab.len() as u64));
+        dynamic.push((DT_NULL, 0));
+        let total = dynamic_offset + 16 * dynamic.len() as u64;
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(ELF_MAGIC);
+        buf.extend_from_slice(&[2, 1, 1, 0]);
+        buf.resize(16, 0);
+        buf.extend_from_slice(&file_type.to_le_bytes());
+        buf.extend_from_slice(&host_machine().unwrap_or(62).to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        buf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        buf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        buf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        buf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        buf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        buf.extend_from_slice(&phnum.to_le_bytes());
+        buf.extend_from_slice(&[0u8; 6]);
+
+        let mut program_header = |kind: u32, offset: u64, filesz: u64| {
+            buf.extend_from_slice(&kind.to_le_bytes());
+            buf.extend_from_slice(&5u32.to_le_bytes());
+            for value in [offset, offset, offset, filesz, filesz, 8] {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        };
+        program_header(PT_LOAD, 0, total);
+        program_header(if interpreter.is_some() { PT_INTERP } else { 0 }, interp_offset, interp.len() as u64);
+        program_header(PT_DYNAMIC, dynamic_offset, 16 * dynamic.len() as u64);
+
+        buf.append(&mut interp);
+        buf.extend_from_slice(&strtab);
+        for (tag, value) in dynamic {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf
+    }
+
+    fn write_program(path: &Path, contents: &[u8], mode: u32) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_on_path() {
+        let sh = resolve("sh").unwrap();
+        assert!(sh.is_absolute() && sh.ends_with("sh"));
+
+        let err = resolve("s3mem-run-no-such-program").unwrap_err();
+        assert!(err.to_string().contains("not found in any PATH directory"));
+        assert!(resolve("./no/such/program").is_err());
+    }
+
+    #[test]
+    fn test_check_script_and_permissions() {
+        let dir = crate::runtime_dir::RuntimeDir::new().unwrap();
+
+        let script = dir.path().join("run.sh");
+        write_program(&script, b"#!/bin/sh\necho hi\n", 0o755);
+        let report = check(&script, None).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.interpreter.as_deref(), Some(Path::new("/bin/sh")));
+
+        let missing = dir.path().join("missing.sh");
+        write_program(&missing, b"#!/no/such/interpreter\n", 0o755);
+        let report = check(&missing, None).unwrap();
+        assert!(report.problems[0].contains("/no/such/interpreter does not exist"));
+        assert!(report.ensure_runnable().is_err());
+
+        let data = dir.path().join("data.txt");
+        write_program(&data, b"plain text", 0o644);
+        let problems = check(&data, None).unwrap().problems;
+        // Root may execute anything with an execute bit, but there is none here either
+        assert!(problems.iter().any(|problem| problem.contains("not executable")));
+        assert!(problems.iter().any(|problem| problem.contains("neither an ELF binary")));
+
+        assert!(check(dir.path(), None).unwrap().problems[0].contains("directory"));
+    }
+
+    #[test]
+    fn test_check_elf_libraries() {
+        let dir = crate::runtime_dir::RuntimeDir::new().unwrap();
+        let interpreter = dir.path().join("ld.so");
+        write_program(&interpreter, &build_elf(ET_DYN, None, &[], None), 0o755);
+        let interpreter = interpreter.to_str().unwrap();
+
+        // A library next to the binary is found through an $ORIGIN run path
+        let program = dir.path().join("server");
+        let elf = build_elf(ET_EXEC, Some(interpreter), &["libs3memtest.so.1"], Some("$ORIGIN"));
+        write_program(&program, &elf, 0o755);
+        let report = check(&program, Some(dir.path())).unwrap();
+        assert!(report.warnings[0].contains("libs3memtest.so.1 was not found"));
+        // The loader may still find it somewhere the check doesn't know of, so it can run
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(report.ensure_runnable().is_ok());
+
+        let library = dir.path().join("libs3memtest.so.1");
+        write_program(&library, &build_elf(ET_DYN, None, &[], None), 0o755);
+        let report = check(&program, Some(dir.path())).unwrap();
+        assert!(report.problems.is_empty() && report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(report.libraries[0].path.as_deref(), Some(library.as_path()));
+
+        // Without an origin, as for a program in a memory file, the run path doesn't apply
+        assert!(!check(&program, None).unwrap().warnings.is_empty());
+
+        let no_interpreter = dir.path().join("other");
+        write_program(&no_interpreter, &build_elf(ET_EXEC, Some("/no/such/ld.so"), &[], None), 0o755);
+        let report = check(&no_interpreter, None).unwrap();
+        assert!(report.problems[0].contains("ELF interpreter /no/such/ld.so does not exist"));
+
+        // A program header table sized past the end of the file is rejected before reading it
+        let mut elf = build_elf(ET_EXEC, None, &[], None);
+        elf[54..58].copy_from_slice(&[0xff; 4]);
+        let huge = dir.path().join("huge");
+        write_program(&huge, &elf, 0o755);
+        let err = Elf::read(&File::open(&huge).unwrap()).unwrap_err();
+        assert!(format!("{:#}", err).contains("invalid program headers"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_check_real_binary() {
+        let report = check(&resolve("sh").unwrap().canonicalize().unwrap(), None).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    }
+
+    #[test]
+    fn test_default_dirs() {
+        let search = LibrarySearch {
+            class64: true,
+            machine: 62,
+            musl: false,
+            ld_library_path: Vec::new(),
+            cache: Vec::new(),
+            exe_rpath: Vec::new(),
+        };
+        let dirs = search.default_dirs();
+        assert!(dirs.contains(&PathBuf::from("/usr/lib64")));
+        assert!(dirs.contains(&PathBuf::from("/usr/lib/x86_64-linux-gnu")));
+
+        // Without the cache, the real shell's libraries are still found in the default directories
+        let sh = resolve("sh").unwrap().canonicalize().unwrap();
+        let elf = Elf::read(&File::open(&sh).unwrap()).unwrap();
+        if elf.machine == 62 && elf.class64 && !elf.needed.is_empty() {
+            let mut report = ProgramReport::default();
+            search.resolve_all(&elf, None, &mut report);
+            assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        }
+    }
+
+    #[test]
+    fn test_parse_ld_so_cache() {
+        let mut data = LD_SO_CACHE_MAGIC.to_vec();
+        data.extend_from_slice(&1u32.to_ne_bytes());
+        data.resize(48, 0);
+        data.extend_from_slice(&0x303i32.to_ne_bytes());
+        data.extend_from_slice(&72u32.to_ne_bytes());
+        data.extend_from_slice(&80u32.to_ne_bytes());
+        data.resize(72, 0);
+        data.extend_from_slice(b"libx.so\0/lib/libx.so\0");
+        assert_eq!(
+            parse_ld_so_cache(&data).unwrap(),
+            vec![("libx.so".to_string(), PathBuf::from("/lib/libx.so"))]
+        );
+    }
+
+    #[test]
+    fn test_expand_origin() {
+        let origin = Path::new("/opt/bin");
+        assert_eq!(expand_origin("$ORIGIN/../lib", Some(origin), true).unwrap(), Path::new("/opt/bin/../lib"));
+        assert_eq!(expand_origin("${ORIGIN}", Some(origin), true).unwrap(), origin);
+        assert_eq!(expand_origin("/usr/$LIB", None, true).unwrap(), Path::new("/usr/lib64"));
+        assert!(expand_origin("$ORIGIN", None, true).is_none());
+    }
+}
@@ -0,0 +1,309 @@
+// Download progress, its event stream, and an optional HTTP endpoint that reports it
+// Chunk downloads report started, retried, completed and failed events here. Embedders
+// receive them through a callback or a channel, and the CLI's progress display is built
+// on the same stream.
+// Lambda Web Adapter polls a readiness path on the program's port, but nothing listens
+// there until the program starts, so "still downloading" looks the same as "dead".
+// The endpoint answers on that port while the model downloads, always with 503 so the
+// adapter keeps waiting, and the port is released before the program is started.
+
+use crate::report::Timings;                   // Timings for the download report
+use serde_json::json;                         // JSON responses
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};  // Lock-free counters
+use std::sync::{Arc, Mutex};                  // Shared between download tasks and the server
+use std::time::{Duration, Instant};           // Elapsed time and throughput
+use tokio::io::{AsyncReadExt, AsyncWriteExt}; // Reading requests, writing responses
+use tokio::net::{TcpListener, TcpStream};     // The endpoint's sockets
+use tokio::sync::mpsc;                        // Event channels
+use tracing::{debug, warn};                   // Structured logging
+
+const MAX_REQUEST_LEN: usize = 8 * 1024;      // Request bytes read before answering anyway
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Bytes downloaded so far, updated by the chunk download tasks
+#[derive(Debug)]
+pub struct DownloadProgress {
+    started: Instant,                     // When the download started
+    bytes_total: AtomicU64,               // Combined size of every object
+    bytes_done: AtomicU64,                // Bytes of completed chunks
+    downloaded: AtomicBool,               // Every file is in memory
+    timings: Mutex<Timings>,              // Request timings for the report
+    listeners: Mutex<Listeners>,          // Receivers of progress events
+}
+
+// Something that happened during a download
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    // An object's size is known and it has been split into chunks
+    Planned { key: String, size: u64, chunk_size: u64, chunks: usize },
+    // The first request for a chunk was sent
+    ChunkStarted { key: String, chunk: usize, start: u64, end: u64 },
+    // An attempt failed and the chunk will be requested again after `delay`
+    ChunkRetried { key: String, chunk: usize, attempt: u32, delay: Duration, error: String },
+    // The chunk arrived; `progress` has the aggregate bytes, throughput and ETA after it
+    ChunkCompleted { key: String, chunk: usize, bytes: u64, attempts: u32, progress: ProgressSnapshot },
+    // Every attempt failed, which fails the whole download
+    ChunkFailed { key: String, chunk: usize, attempts: u32, error: String },
+}
+
+// Called with every progress event, on the task that caused it
+pub type EventCallback = Arc<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+// The callbacks and channels progress events are delivered to
+#[derive(Default)]
+struct Listeners {
+    callbacks: Vec<EventCallback>,
+    channels: Vec<mpsc::UnboundedSender<ProgressEvent>>,
+}
+
+impl std::fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Listeners")
+            .field("callbacks", &self.callbacks.len())
+            .field("channels", &self.channels.len())
+            .finish()
+    }
+}
+
+// A consistent view of the progress at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressSnapshot {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub elapsed: Duration,
+    pub downloaded: bool,
+}
+
+impl DownloadProgress {
+    pub fn new() -> Self {
+        DownloadProgress {
+            started: Instant::now(),
+            bytes_total: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+            downloaded: AtomicBool::new(false),
+            timings: Mutex::new(Timings::default()),
+            listeners: Mutex::new(Listeners::default()),
+        }
+    }
+
+    // Call `callback` with every event from now on
+    pub fn on_event(&self, callback: EventCallback) {
+        self.listeners.lock().unwrap().callbacks.push(callback);
+    }
+
+    // Receive every event from now on; the channel closes when the progress is dropped
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<ProgressEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.listeners.lock().unwrap().channels.push(sender);
+        receiver
+    }
+
+    // Deliver an event to every listener, forgetting channels whose receiver is gone
+    // Callbacks run without the lock held, so they may use this progress themselves
+    pub fn emit(&self, event: ProgressEvent) {
+        let callbacks = {
+            let mut listeners = self.listeners.lock().unwrap();
+            listeners.channels.retain(|channel| channel.send(event.clone()).is_ok());
+            listeners.callbacks.clone()
+        };
+        for callback in &callbacks {
+            callback(&event);
+        }
+    }
+
+    // Record the HEAD request of an object
+    pub fn record_head(&self, key: &str, size: u64, chunk_size: u64, latency: Duration) {
+        self.timings.lock().unwrap().record_head(key, size, chunk_size, latency);
+    }
+
+    // Record the number of parallel chunk downloads
+    pub fn record_concurrency(&self, concurrency: usize) {
+        self.timings.lock().unwrap().set_concurrency(concurrency);
+    }
+
+    // Record a completed chunk with its time to first byte, duration and attempts
+    pub fn record_chunk(&self, bytes: u64, ttfb: Duration, duration: Duration, attempts: u32) {
+        self.add_done(bytes);
+        self.timings.lock().unwrap().record_chunk(bytes, ttfb, duration, attempts);
+    }
+
+    // The download report as JSON
+    pub fn report(&self, bucket: &str) -> serde_json::Value {
+        self.timings.lock().unwrap().to_json(bucket)
+    }
+
+    // Record the combined size once it is known from the HEAD requests
+    pub fn set_total(&self, bytes: u64) {
+        self.bytes_total.store(bytes, Ordering::Relaxed);
+    }
+
+    // Record a completed chunk
+    pub fn add_done(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Record that every file is in memory and the program is about to start
+    pub fn finish(&self) {
+        self.timings.lock().unwrap().finish(self.started.elapsed());
+        self.downloaded.store(true, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for DownloadProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSnapshot {
+    // Average throughput since the download started, in bytes per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.bytes_done as f64 / secs } else { 0.0 }
+    }
+
+    // Seconds left at the average throughput, unknown until some bytes have arrived
+    pub fn eta_secs(&self) -> Option<f64> {
+        let throughput = self.throughput();
+        (throughput > 0.0)
+            .then(|| self.bytes_total.saturating_sub(self.bytes_done) as f64 / throughput)
+    }
+
+    // The JSON body served by the endpoint
+    pub fn to_json(&self) -> serde_json::Value {
+        let percent = if self.bytes_total > 0 {
+            self.bytes_done as f64 / self.bytes_total as f64 * 100.0
+        } else {
+            0.0
+        };
+        json!({
+            "status": if self.downloaded { "starting" } else { "downloading" },
+            "ready": false,
+            "bytes_done": self.bytes_done,
+            "bytes_total": self.bytes_total,
+            "percent": (percent * 10.0).round() / 10.0,
+            "elapsed_secs": self.elapsed.as_secs_f64(),
+            "throughput_bytes_per_sec": self.throughput().round(),
+            "eta_secs": self.eta_secs(),
+        })
+    }
+}
+
+// Answer every request on the listener with the current progress until the task is aborted
+// Aborting the task drops the listener, which releases the port for the program
+pub async fn serve(listener: TcpListener, progress: Arc<DownloadProgress>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(error = %err, "Failed to accept progress connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        debug!(%peer, "Progress request");
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &progress).await {
+                debug!(%peer, error = %err, "Progress request failed");
+            }
+        });
+    }
+}
+
+// Read one request and answer it with 503 and the progress as JSON
+async fn respond(mut stream: TcpStream, progress: &DownloadProgress) -> std::io::Result<()> {
+    // The request itself doesn't matter, every path gets the same answer, but it is read
+    // up to the end of its headers so the client doesn't see a reset
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read_headers = async {
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read_headers).await.is_err() {
+        return Ok(());
+    }
+
+    let body = progress.snapshot().to_json().to_string();
+    let head = request.starts_with(b"HEAD ");
+    let response = format!(
+        "HTTP/1.1 503 Service Unavailable\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Retry-After: 1\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        if head { "" } else { body.as_str() }
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_json() {
+        let snapshot = ProgressSnapshot {
+            bytes_done: 250,
+            bytes_total: 1000,
+            elapsed: Duration::from_secs(5),
+            downloaded: false,
+        };
+        assert_eq!(snapshot.throughput(), 50.0);
+        assert_eq!(snapshot.eta_secs(), Some(15.0));
+
+        let json = snapshot.to_json();
+        assert_eq!(json["status"], "downloading");
+        assert_eq!(json["percent"], 25.0);
+        assert_eq!(json["eta_secs"], 15.0);
+
+        let empty = ProgressSnapshot { bytes_done: 0, bytes_total: 0, elapsed: Duration::ZERO, downloaded: false };
+        assert!(empty.eta_secs().is_none());
+        assert!(empty.to_json()["eta_secs"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_serve_progress() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let progress = Arc::new(DownloadProgress::new());
+        progress.set_total(1000);
+        progress.add_done(400);
+        let server = tokio::spawn(serve(listener, progress.clone()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        let body: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["bytes_done"], 400);
+        assert_eq!(body["bytes_total"], 1000);
+        assert_eq!(body["ready"], false);
+
+        // Stopping the server releases the port
+        server.abort();
+        let _ = server.await;
+        TcpListener::bind(addr).await.unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+// Download progress on stderr
+// Both displays are driven by the download's progress events: a bar redrawn in place when
+// stderr is a terminal, and otherwise a one-line summary logged every few seconds, which
+// reads well in CloudWatch and in JSON logs.
+
+use s3mem_run::inspect::format_bytes;         // Human-readable sizes
+use s3mem_run::logging;                       // The status line below the logs
+use s3mem_run::progress::{DownloadProgress, ProgressEvent, ProgressSnapshot};  // The event stream
+use std::io::IsTerminal;                      // Whether stderr is a terminal
+use std::sync::Arc;                           // The stop signal shared with the task
+use std::time::Duration;                      // Redraw and summary intervals
+use tokio::sync::{mpsc, Notify};              // Events in, stop signal
+use tracing::info;                            // Summary lines
+
+const BAR_WIDTH: usize = 30;                  // Characters between the brackets
+const BAR_REDRAW: Duration = Duration::from_millis(100);
+
+// How download progress is shown
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ProgressMode {
+    Auto,                                 // A bar when stderr is a terminal, summaries otherwise
+    Bar,                                  // Always the bar
+    Log,                                  // Always the periodic summary
+    Off,                                  // Nothing beyond the usual logs
+}
+
+// The options of every command that downloads
+#[derive(clap::Args, Debug, Clone)]
+pub struct ProgressArgs {
+    /// How to show download progress: auto (a bar when stderr is a terminal, a summary
+    /// line every --progress-interval seconds otherwise), bar, log or off
+    #[arg(long, env = "PROGRESS", value_enum, default_value = "auto")]
+    pub progress: ProgressMode,
+
+    /// Seconds between progress summary lines when no bar is shown
+    #[arg(long, env = "PROGRESS_INTERVAL", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub progress_interval: u64,
+}
+
+impl ProgressArgs {
+    // Show the progress of the download that reports to `progress` as asked
+    pub fn start(&self, progress: &DownloadProgress) -> Option<ProgressDisplay> {
+        ProgressDisplay::start(self.progress, progress, Duration::from_secs(self.progress_interval))
+    }
+}
+
+// What the events so far add up to
+#[derive(Debug, Default)]
+struct Tally {
+    chunks_total: usize,                  // Chunks of every planned object
+    chunks_done: usize,                   // Completed chunks
+    retries: u64,                         // Failed attempts that were retried
+    failures: u64,                        // Chunks that failed for good
+    progress: Option<ProgressSnapshot>,   // Aggregate bytes and ETA after the latest chunk
+    changed: bool,                        // Something happened since the last draw
+}
+
+impl Tally {
+    fn apply(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Planned { chunks, .. } => self.chunks_total += chunks,
+            ProgressEvent::ChunkStarted { .. } => {}
+            ProgressEvent::ChunkRetried { .. } => self.retries += 1,
+            ProgressEvent::ChunkCompleted { progress, .. } => {
+                self.chunks_done += 1;
+                self.progress = Some(progress);
+            }
+            ProgressEvent::ChunkFailed { .. } => self.failures += 1,
+        }
+        self.changed = true;
+    }
+
+    fn percent(&self) -> f64 {
+        match &self.progress {
+            Some(progress) if progress.bytes_total > 0 => {
+                progress.bytes_done as f64 / progress.bytes_total as f64 * 100.0
+            }
+            _ => 0.0,
+        }
+    }
+
+    // [=========>          ]  45.0%  1.20 GiB / 2.67 GiB  85.3 MiB/s  ETA 18s  34/75 chunks  1 retry
+    fn bar(&self) -> String {
+        let percent = self.percent();
+        let filled = ((percent / 100.0 * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+        let mut bar = "=".repeat(filled);
+        if filled < BAR_WIDTH {
+            bar.push('>');
+            bar.push_str(&" ".repeat(BAR_WIDTH - filled - 1));
+        }
+
+        let (done, total, throughput, eta) = match &self.progress {
+            Some(progress) => (progress.bytes_done, progress.bytes_total, progress.throughput(), progress.eta_secs()),
+            None => (0, 0, 0.0, None),
+        };
+        let mut line = format!(
+            "[{}] {:5.1}%  {} / {}  {}/s  ETA {}  {}/{} chunks",
+            bar,
+            percent,
+            format_bytes(done),
+            format_bytes(total),
+            format_bytes(throughput as u64),
+            eta.map_or_else(|| "?".to_string(), |secs| format!("{}s", secs.ceil() as u64)),
+            self.chunks_done,
+            self.chunks_total,
+        );
+        match self.retries {
+            0 => {}
+            1 => line.push_str("  1 retry"),
+            retries => line.push_str(&format!("  {} retries", retries)),
+        }
+        if self.failures > 0 {
+            line.push_str(&format!("  {} failed", self.failures));
+        }
+        line
+    }
+
+    fn log_summary(&self) {
+        let progress = self.progress.clone().unwrap_or(ProgressSnapshot {
+            bytes_done: 0,
+            bytes_total: 0,
+            elapsed: Duration::ZERO,
+            downloaded: false,
+        });
+        info!(
+            bytes_done = progress.bytes_done,
+            bytes_total = progress.bytes_total,
+            percent = (self.percent() * 10.0).round() / 10.0,
+            throughput_mbps = progress.throughput() / 1_000_000.0,
+            eta_secs = progress.eta_secs(),
+            chunks_done = self.chunks_done,
+            chunks_total = self.chunks_total,
+            retries = self.retries,
+            "Download progress"
+        );
+    }
+}
+
+// A running progress display, stopped with finish
+pub struct ProgressDisplay {
+    stop: Arc<Notify>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ProgressDisplay {
+    // Show the progress of the download that reports to `progress`, unless the mode is off
+    pub fn start(mode: ProgressMode, progress: &DownloadProgress, interval: Duration) -> Option<Self> {
+        let bar = match mode {
+            ProgressMode::Off => return None,
+            ProgressMode::Bar => true,
+            ProgressMode::Log => false,
+            ProgressMode::Auto => std::io::stderr().is_terminal(),
+        };
+        let stop = Arc::new(Notify::new());
+        let interval = if bar { BAR_REDRAW } else { interval };
+        let task = tokio::spawn(render(progress.subscribe(), bar, interval, stop.clone()));
+        Some(ProgressDisplay { stop, task })
+    }
+
+    // Draw the final state and stop, leaving the bar on its own line
+    pub async fn finish(self) {
+        self.stop.notify_one();
+        let _ = self.task.await;
+    }
+}
+
+async fn render(mut events: mpsc::UnboundedReceiver<ProgressEvent>, bar: bool, interval: Duration, stop: Arc<Notify>) {
+    let mut tally = Tally::default();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            Some(event) = events.recv() => tally.apply(event),
+            _ = ticker.tick() => {
+                if tally.changed {
+                    tally.changed = false;
+                    if bar {
+                        logging::set_status_line(Some(tally.bar()));
+                    } else {
+                        tally.log_summary();
+                    }
+                }
+            }
+            _ = stop.notified() => break,
+        }
+    }
+
+    // Events sent just before the stop are still in the channel
+    while let Ok(event) = events.try_recv() {
+        tally.apply(event);
+    }
+    if bar {
+        logging::set_status_line(None);
+        if tally.chunks_total > 0 {
+            eprintln!("{}", tally.bar());
+        }
+    } else if tally.changed {
+        tally.log_summary();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completed(bytes_done: u64) -> ProgressEvent {
+        ProgressEvent::ChunkCompleted {
+            key: "model.gguf".to_string(),
+            chunk: 0,
+            bytes: 100,
+            attempts: 1,
+            progress: ProgressSnapshot {
+                bytes_done,
+                bytes_total: 400,
+                elapsed: Duration::from_secs(2),
+                downloaded: false,
+            },
+        }
+    }
+
+    #[test]
+    fn test_tally_bar() {
+        let mut tally = Tally::default();
+        tally.apply(ProgressEvent::Planned { key: "model.gguf".to_string(), size: 400, chunk_size: 100, chunks: 4 });
+        assert_eq!(
+            tally.bar(),
+            "[>                             ]   0.0%  0 B / 0 B  0 B/s  ETA ?  0/4 chunks"
+        );
+
+        tally.apply(completed(100));
+        tally.apply(ProgressEvent::ChunkRetried {
+            key: "model.gguf".to_string(),
+            chunk: 1,
+            attempt: 1,
+            delay: Duration::from_millis(250),
+            error: "timeout".to_string(),
+        });
+        tally.apply(completed(200));
+        assert_eq!(
+            tally.bar(),
+            "[===============>              ]  50.0%  200 B / 400 B  100 B/s  ETA 2s  2/4 chunks  1 retry"
+        );
+
+        tally.apply(completed(400));
+        assert!(tally.bar().starts_with(&format!("[{}] 100.0%", "=".repeat(BAR_WIDTH))));
+    }
+}
@@ -0,0 +1,221 @@
+// Download timing report
+// Memory size and concurrency are otherwise tuned blind, so every download records the
+// HEAD latency of each object and the time to first byte, duration, size and attempts of
+// each chunk. They are summarized into one JSON report, written before the program
+// starts. The schema is versioned: fields may be added, but existing fields keep their
+// names, units and meaning until schema_version changes.
+
+use serde_json::{json, Value};                // The report
+use std::path::PathBuf;                       // Report files
+use std::time::Duration;                      // Measured times
+
+pub const SCHEMA_VERSION: u32 = 1;            // Bumped on incompatible schema changes
+pub const REPORT_ENV: &str = "S3MEM_RUN_REPORT";  // Env var the report is passed in
+pub const REPORT_PLACEHOLDER: &str = "{{report}}";  // Replaced with the report in program arguments
+
+// Upper bounds of the histogram buckets; the last bucket has no bound
+const DURATION_BUCKETS_MS: [f64; 9] = [100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 30000.0, 60000.0];
+const THROUGHPUT_BUCKETS_MBPS: [f64; 8] = [5.0, 10.0, 25.0, 50.0, 100.0, 200.0, 400.0, 800.0];
+
+// Where the report goes
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReportTarget {
+    Stderr,                               // One line on stderr
+    Env,                                  // The S3MEM_RUN_REPORT env var of the program
+    File(PathBuf),                        // A file, replaced if it exists
+}
+
+impl std::str::FromStr for ReportTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "" => Err("the report target is empty".to_string()),
+            "stderr" => Ok(ReportTarget::Stderr),
+            "env" => Ok(ReportTarget::Env),
+            path => Ok(ReportTarget::File(PathBuf::from(path))),
+        }
+    }
+}
+
+// Timings recorded while downloading
+#[derive(Debug, Default)]
+pub struct Timings {
+    objects: Vec<ObjectTiming>,
+    chunks: Vec<ChunkTiming>,
+    concurrency: usize,
+    wall_time: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct ObjectTiming {
+    key: String,
+    size: u64,
+    chunk_size: u64,
+    head_latency: Duration,
+}
+
+#[derive(Debug)]
+struct ChunkTiming {
+    bytes: u64,
+    ttfb: Duration,                       // From sending the request to the response headers
+    duration: Duration,                   // From starting the first attempt to the whole body
+    attempts: u32,
+}
+
+impl Timings {
+    pub fn record_head(&mut self, key: &str, size: u64, chunk_size: u64, head_latency: Duration) {
+        self.objects.push(ObjectTiming { key: key.to_string(), size, chunk_size, head_latency });
+    }
+
+    pub fn record_chunk(&mut self, bytes: u64, ttfb: Duration, duration: Duration, attempts: u32) {
+        self.chunks.push(ChunkTiming { bytes, ttfb, duration, attempts });
+    }
+
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency;
+    }
+
+    pub fn finish(&mut self, wall_time: Duration) {
+        self.wall_time = Some(wall_time);
+    }
+
+    // Build the report
+    pub fn to_json(&self, bucket: &str) -> Value {
+        let total_bytes: u64 = self.chunks.iter().map(|chunk| chunk.bytes).sum();
+        let wall_secs = self.wall_time.unwrap_or_default().as_secs_f64();
+        let rate = |bytes: f64, secs: f64| if secs > 0.0 { bytes / secs } else { 0.0 };
+
+        let ttfb: Vec<f64> = self.chunks.iter().map(|chunk| millis(chunk.ttfb)).collect();
+        let durations: Vec<f64> = self.chunks.iter().map(|chunk| millis(chunk.duration)).collect();
+        let throughputs: Vec<f64> = self
+            .chunks
+            .iter()
+            .map(|chunk| rate(chunk.bytes as f64, chunk.duration.as_secs_f64()) / 1_000_000.0)
+            .collect();
+        let heads: Vec<f64> = self.objects.iter().map(|object| millis(object.head_latency)).collect();
+
+        json!({
+            "schema_version": SCHEMA_VERSION,
+            "bucket": bucket,
+            "objects": self.objects.iter().map(|object| json!({
+                "key": object.key,
+                "size_bytes": object.size,
+                "chunk_size_bytes": object.chunk_size,
+                "head_latency_ms": millis(object.head_latency),
+            })).collect::<Vec<_>>(),
+            "total_bytes": total_bytes,
+            "concurrency": self.concurrency,
+            "wall_time_ms": millis(self.wall_time.unwrap_or_default()),
+            "throughput_mbps": rate(total_bytes as f64, wall_secs) / 1_000_000.0,
+            "effective_gbps": rate(total_bytes as f64 * 8.0, wall_secs) / 1_000_000_000.0,
+            "head_latency_ms": summary(&heads),
+            "chunks": {
+                "count": self.chunks.len(),
+                "attempts": self.chunks.iter().map(|chunk| chunk.attempts as u64).sum::<u64>(),
+                "retries": self.chunks.iter().map(|chunk| chunk.attempts.saturating_sub(1) as u64).sum::<u64>(),
+                "ttfb_ms": summary(&ttfb),
+                "duration_ms": summary(&durations),
+                "throughput_mbps": summary(&throughputs),
+                "duration_histogram_ms": histogram(&durations, &DURATION_BUCKETS_MS),
+                "throughput_histogram_mbps": histogram(&throughputs, &THROUGHPUT_BUCKETS_MBPS),
+            },
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Count, mean and percentiles of a set of values, all zero when there are none
+fn summary(values: &[f64]) -> Value {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let percentile = |p: f64| -> f64 {
+        if sorted.is_empty() {
+            return 0.0;
+        }
+        // Nearest rank
+        let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    };
+    let mean = if sorted.is_empty() { 0.0 } else { sorted.iter().sum::<f64>() / sorted.len() as f64 };
+    json!({
+        "count": sorted.len(),
+        "min": sorted.first().copied().unwrap_or(0.0),
+        "mean": mean,
+        "p50": percentile(50.0),
+        "p90": percentile(90.0),
+        "p99": percentile(99.0),
+        "max": sorted.last().copied().unwrap_or(0.0),
+    })
+}
+
+// Count the values in each bucket; `le` is the bucket's inclusive upper bound, null for the last
+fn histogram(values: &[f64], bounds: &[f64]) -> Value {
+    let mut counts = vec![0u64; bounds.len() + 1];
+    for &value in values {
+        let bucket = bounds.iter().position(|&bound| value <= bound).unwrap_or(bounds.len());
+        counts[bucket] += 1;
+    }
+    let buckets: Vec<Value> = counts
+        .iter()
+        .enumerate()
+        .map(|(index, &count)| json!({ "le": bounds.get(index), "count": count }))
+        .collect();
+    Value::Array(buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_schema() {
+        let mut timings = Timings::default();
+        timings.record_head("model.gguf", 3_000_000, 1_000_000, Duration::from_millis(20));
+        timings.set_concurrency(4);
+        timings.record_chunk(1_000_000, Duration::from_millis(50), Duration::from_millis(200), 1);
+        timings.record_chunk(1_000_000, Duration::from_millis(60), Duration::from_millis(400), 2);
+        timings.record_chunk(1_000_000, Duration::from_millis(70), Duration::from_millis(1000), 1);
+        timings.finish(Duration::from_secs(1));
+
+        let report = timings.to_json("models");
+        assert_eq!(report["schema_version"], 1);
+        assert_eq!(report["objects"][0]["head_latency_ms"], 20.0);
+        assert_eq!(report["total_bytes"], 3_000_000);
+        assert_eq!(report["effective_gbps"], 0.024);
+        assert_eq!(report["throughput_mbps"], 3.0);
+        assert_eq!(report["chunks"]["count"], 3);
+        assert_eq!(report["chunks"]["retries"], 1);
+        assert_eq!(report["chunks"]["duration_ms"]["p50"], 400.0);
+        assert_eq!(report["chunks"]["duration_ms"]["max"], 1000.0);
+        assert_eq!(report["chunks"]["throughput_mbps"]["max"], 5.0);
+
+        let histogram = report["chunks"]["duration_histogram_ms"].as_array().unwrap();
+        assert_eq!(histogram.len(), DURATION_BUCKETS_MS.len() + 1);
+        assert_eq!(histogram[1], json!({ "le": 250.0, "count": 1 }));
+        assert_eq!(histogram[2], json!({ "le": 500.0, "count": 1 }));
+        assert_eq!(histogram[3], json!({ "le": 1000.0, "count": 1 }));
+        assert!(histogram.last().unwrap()["le"].is_null());
+    }
+
+    #[test]
+    fn test_empty_report() {
+        let report = Timings::default().to_json("models");
+        assert_eq!(report["chunks"]["ttfb_ms"]["p99"], 0.0);
+        assert_eq!(report["effective_gbps"], 0.0);
+    }
+
+    #[test]
+    fn test_report_target_parsing() {
+        assert_eq!("stderr".parse::<ReportTarget>().unwrap(), ReportTarget::Stderr);
+        assert_eq!("env".parse::<ReportTarget>().unwrap(), ReportTarget::Env);
+        assert_eq!(
+            "/tmp/report.json".parse::<ReportTarget>().unwrap(),
+            ReportTarget::File(PathBuf::from("/tmp/report.json"))
+        );
+        assert!("".parse::<ReportTarget>().is_err());
+    }
+}
@@ -0,0 +1,177 @@
+// Init error reporting to the Lambda Runtime API
+// When the process exits during init, Lambda only reports "Runtime exited" with the exit
+// status. Posting to /runtime/init/error first puts an error type and message in the
+// invocation error and the init logs instead. AWS_LAMBDA_RUNTIME_API is only set inside
+// Lambda, so elsewhere nothing is sent. Once the program has started, init is over and
+// later failures, such as a supervised program crashing, are not reported.
+
+use crate::error::ErrorKind;                  // Error types
+use serde_json::json;                         // Error body
+use std::io::{Read, Write};                   // Talking HTTP to the Runtime API
+use std::net::{TcpStream, ToSocketAddrs};     // Runtime API connection
+use std::sync::atomic::{AtomicBool, Ordering};  // Whether init is over
+use std::time::Duration;                      // Request timeouts
+use tracing::{debug, warn};                   // Structured logging
+
+pub const RUNTIME_API_ENV: &str = "AWS_LAMBDA_RUNTIME_API";  // host:port of the Runtime API
+const INIT_ERROR_PATH: &str = "/2018-06-01/runtime/init/error";
+const ERROR_TYPE_HEADER: &str = "Lambda-Runtime-Function-Error-Type";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);  // Per connect, write and read
+
+static PROGRAM_STARTED: AtomicBool = AtomicBool::new(false);  // Set by the first child started
+
+// Record that the program has started, after which errors are no longer init errors
+pub fn program_started() {
+    PROGRAM_STARTED.store(true, Ordering::SeqCst);
+}
+
+// Post an init error to the Runtime API, if s3mem-run runs in Lambda
+// Failures are only logged; the process exits with the error either way
+pub fn report_init_error(err: &anyhow::Error) {
+    let runtime_api = std::env::var(RUNTIME_API_ENV).ok();
+    report_init_error_to(runtime_api.as_deref(), PROGRAM_STARTED.load(Ordering::SeqCst), err);
+}
+
+// Post an init error to this Runtime API, unless the program has already started
+// Returns whether the error was posted
+fn report_init_error_to(runtime_api: Option<&str>, program_started: bool, err: &anyhow::Error) -> bool {
+    let Some(runtime_api) = runtime_api else { return false };
+    if program_started {
+        debug!("Not reporting an init error, the program has already started");
+        return false;
+    }
+    let error_type = format!("S3MemRun.{}", ErrorKind::of(err));
+    match post_init_error(runtime_api, &error_type, &format!("{:#}", err)) {
+        Ok(()) => {
+            debug!(error_type, "Reported init error to the Runtime API");
+            true
+        }
+        Err(err) => {
+            warn!(error_type, error = %err, "Failed to report init error to the Runtime API");
+            false
+        }
+    }
+}
+
+pub fn post_init_error(runtime_api: &str, error_type: &str, message: &str) -> std::io::Result<()> {
+    let body = json!({
+        "errorMessage": message,
+        "errorType": error_type,
+        "stackTrace": [],
+    })
+    .to_string();
+
+    let addr = runtime_api
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other(format!("{} has no address", runtime_api)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         {}: {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        INIT_ERROR_PATH,
+        runtime_api,
+        ERROR_TYPE_HEADER,
+        error_type,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // The Runtime API answers 202 Accepted; only the status line matters
+    let mut response = Vec::new();
+    let mut buf = [0u8; 512];
+    while !response.contains(&b'\n') {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..read]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(std::io::Error::other(format!("Runtime API answered {:?}", status_line))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // A stand-in Runtime API that answers one request and returns it
+    fn mock_runtime_api(status: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_post_init_error_to_mock_runtime_api() {
+        let (addr, runtime_api) = mock_runtime_api("202 Accepted");
+        post_init_error(&addr, "S3MemRun.AccessDenied", "Failed to get object metadata from S3: 403").unwrap();
+        let request = runtime_api.join().unwrap();
+
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /2018-06-01/runtime/init/error HTTP/1.1\r\n"));
+        assert!(head.contains("Lambda-Runtime-Function-Error-Type: S3MemRun.AccessDenied\r\n"));
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["errorType"], "S3MemRun.AccessDenied");
+        assert_eq!(body["errorMessage"], "Failed to get object metadata from S3: 403");
+        assert_eq!(body["stackTrace"], json!([]));
+
+        // Anything but a 2xx is a failure
+        let (addr, runtime_api) = mock_runtime_api("403 Forbidden");
+        let err = post_init_error(&addr, "S3MemRun.InitError", "boom").unwrap_err();
+        assert!(err.to_string().contains("403"));
+        runtime_api.join().unwrap();
+    }
+
+    #[test]
+    fn test_init_error_only_before_program_started() {
+        let err = anyhow::anyhow!("Program crashed too many times in a row");
+        assert!(!report_init_error_to(None, false, &err));
+
+        let (addr, runtime_api) = mock_runtime_api("202 Accepted");
+        assert!(report_init_error_to(Some(&addr), false, &err));
+        assert!(runtime_api.join().unwrap().contains("S3MemRun.Internal"));
+
+        // Once the program has started nothing is sent, so nothing connects
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(!report_init_error_to(Some(&addr), true, &err));
+        assert!(listener.accept().is_err(), "an error after the program started was reported");
+    }
+}
@@ -0,0 +1,198 @@
+// Private runtime directory of named symlinks to memory files
+// Some programs need a real file name rather than /proc/self/fd/N, for example
+// llama.cpp finds the shards of a split model by name, and others decide the file
+// type from its extension. The directory is created with mkdtemp, so it is only
+// accessible to the current user.
+
+use anyhow::{Context, Result};                // Error handling with context
+use std::ffi::CString;                        // C-compatible strings for FFI
+use std::os::unix::ffi::OsStringExt;          // Convert mkdtemp's result back to a path
+use std::path::{Path, PathBuf};               // Path manipulation
+
+pub struct RuntimeDir {
+    path: PathBuf,  // Absolute path of the private directory
+}
+
+impl RuntimeDir {
+    // Create a new private directory under the system temporary directory
+    pub fn new() -> Result<Self> {
+        let template = std::env::temp_dir().join("s3mem-run.XXXXXX");
+        let template = CString::new(template.into_os_string().into_vec())
+            .context("Temporary directory path contains a NUL byte")?;
+        let raw = template.into_raw();
+
+        // mkdtemp replaces the XXXXXX suffix in place and creates the directory with mode 0700
+        let result = unsafe { libc::mkdtemp(raw) };
+        let template = unsafe { CString::from_raw(raw) };
+        if result.is_null() {
+            return Err(std::io::Error::last_os_error()).context("Failed to create runtime directory");
+        }
+
+        let path = PathBuf::from(std::ffi::OsString::from_vec(template.into_bytes()));
+        Ok(RuntimeDir { path })
+    }
+
+    // Path of the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Create a symlink named `name` in the directory that points at a file descriptor
+    // Returns the path of the symlink
+    pub fn link_fd(&self, name: &str, fd: i32) -> Result<PathBuf> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            anyhow::bail!("Invalid link name '{}', it must be a plain file name", name);
+        }
+        let link = self.path.join(name);
+        let target = PathBuf::from(format!("/proc/self/fd/{}", fd));
+
+        // The same name may be requested more than once, which is fine as long as
+        // it refers to the same memory file every time
+        if let Ok(existing) = std::fs::read_link(&link) {
+            if existing == target {
+                return Ok(link);
+            }
+            anyhow::bail!(
+                "Link name '{}' is already used for {}, each memory file needs its own name",
+                name,
+                existing.display()
+            );
+        }
+
+        std::os::unix::fs::symlink(&target, &link)
+            .with_context(|| format!("Failed to create symlink {}", link.display()))?;
+        Ok(link)
+    }
+
+    // Hand the directory over to a watcher process that removes it once this process exits
+    // The current process is about to exec, so nothing would be left to clean up after
+    // the program it becomes. The watcher waits on a pidfd for this process, which stays
+    // the same across exec, then removes the symlinks and the directory.
+    pub fn remove_on_exit(self) -> Result<()> {
+        // Everything the watcher needs is prepared before fork, since the forked child of a
+        // multi-threaded process may only make async-signal-safe calls
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.path).context("Failed to list runtime directory")? {
+            paths.push(CString::new(entry?.path().into_os_string().into_vec())?);
+        }
+        let dir = CString::new(self.path.clone().into_os_string().into_vec())?;
+
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, libc::getpid(), 0) } as i32;
+        if pidfd == -1 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to open pidfd, runtime directory cleanup needs Linux 5.3+");
+        }
+
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(pidfd) };
+            return Err(err).context("Failed to start runtime directory cleanup watcher");
+        }
+
+        if pid == 0 {
+            // Watcher: detach from the session and drop every inherited descriptor except
+            // the pidfd, so it doesn't hold memory files, sockets or pipes open
+            unsafe {
+                libc::setsid();
+                libc::dup2(pidfd, 0);
+                if libc::syscall(libc::SYS_close_range, 1u32, u32::MAX, 0u32) == -1 {
+                    // close_range needs Linux 5.9, fall back to closing descriptors one by one
+                    for fd in 1..4096 {
+                        libc::close(fd);
+                    }
+                }
+
+                // Wait until the watched process exits
+                let mut poll_fd = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+                while libc::poll(&mut poll_fd, 1, -1) == -1
+                    && *libc::__errno_location() == libc::EINTR
+                {}
+
+                for path in &paths {
+                    libc::unlink(path.as_ptr());
+                }
+                libc::rmdir(dir.as_ptr());
+                libc::_exit(0);
+            }
+        }
+
+        // The watcher owns cleanup now, so dropping must not remove the directory
+        unsafe { libc::close(pidfd) };
+        std::mem::forget(self);
+        Ok(())
+    }
+}
+
+impl Drop for RuntimeDir {
+    // Remove the directory and its symlinks when it is no longer needed
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_runtime_dir_links() {
+        let file = std::fs::File::open("/proc/self/exe").unwrap();
+        let dir = RuntimeDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+
+        let link = dir.link_fd("model-00001-of-00002.gguf", file.as_raw_fd()).unwrap();
+        assert_eq!(link.parent().unwrap(), path);
+        assert!(std::fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert!(dir.link_fd("../escape", file.as_raw_fd()).is_err());
+
+        // Dropping the directory removes it along with its links
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_link_name_reuse() {
+        let file = std::fs::File::open("/proc/self/exe").unwrap();
+        let other = std::fs::File::open("/proc/self/exe").unwrap();
+        let dir = RuntimeDir::new().unwrap();
+
+        let first = dir.link_fd("model.gguf", file.as_raw_fd()).unwrap();
+        assert_eq!(dir.link_fd("model.gguf", file.as_raw_fd()).unwrap(), first);
+        assert!(dir.link_fd("model.gguf", other.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn test_remove_on_exit() {
+        // Run a short-lived child whose exit should trigger the cleanup, since the
+        // test process itself has to keep running
+        let dir = RuntimeDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        std::fs::write(path.join("marker"), b"").unwrap();
+        std::mem::forget(dir);
+
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .env("S3MEM_RUN_TEST_RUNTIME_DIR", &path)
+            .args(["--exact", "runtime_dir::tests::remove_on_exit_helper"])
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while path.exists() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn remove_on_exit_helper() {
+        // Only does something when started by test_remove_on_exit
+        if let Some(path) = std::env::var_os("S3MEM_RUN_TEST_RUNTIME_DIR") {
+            let dir = RuntimeDir { path: PathBuf::from(path) };
+            dir.remove_on_exit().unwrap();
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// Split GGUF models
+// llama.cpp splits large models into shards named "PREFIX-00001-of-00004.gguf" and
+// loads the siblings of the first shard by building the same names from its prefix.
+// The shard index and count are also recorded in each shard's split.* metadata.
+
+use crate::error::{self, ErrorKind};          // Typed errors
+use crate::gguf::{GgufFile, Value};           // Parsed GGUF metadata
+use crate::inspect::{fetch_gguf_header, object_info};  // Range reads of the first shard's header
+use anyhow::{Context, Result};                // Error handling with context
+use aws_sdk_s3::Client;                       // AWS S3 client
+use tracing::{debug, instrument};             // Structured logging
+
+const SPLIT_COUNT_KEY: &str = "split.count";  // Number of shards, present in every shard
+const SPLIT_NO_KEY: &str = "split.no";        // Zero-based index of this shard
+
+// Build the name of a shard the way llama.cpp's llama_split_path does
+pub fn shard_name(prefix: &str, index: u32, count: u32) -> String {
+    format!("{}-{:05}-of-{:05}.gguf", prefix, index, count)
+}
+
+// Split a key named like a llama.cpp shard into its prefix, shard index and shard count
+fn parse_shard_key(key: &str) -> Option<(&str, u32, u32)> {
+    let stem = key.strip_suffix(".gguf")?;
+    let (rest, count) = stem.rsplit_once("-of-")?;
+    let (prefix, index) = rest.rsplit_once('-')?;
+    if index.len() != 5 || count.len() != 5 || prefix.is_empty() {
+        return None;
+    }
+    let index: u32 = index.parse().ok()?;
+    let count: u32 = count.parse().ok()?;
+    (index >= 1 && index <= count).then_some((prefix, index, count))
+}
+
+// Return the keys of every shard of a split model, first shard first
+// Keys that don't follow the shard naming pattern, or name a single-shard model,
+// return None and are downloaded as a single file
+pub fn split_shard_keys(key: &str) -> Option<Vec<String>> {
+    let (prefix, _, count) = parse_shard_key(key)?;
+    if count < 2 {
+        return None;
+    }
+    Some((1..=count).map(|index| shard_name(prefix, index, count)).collect())
+}
+
+#[instrument(skip(client))]
+// Find the keys of every shard of the model at key, first shard first
+// The first shard's header is read with range requests and its split.count must agree
+// with the name, so a renamed shard fails here instead of loading half a model.
+// Objects that aren't GGUF files, or can't be read yet, are left to the download.
+pub async fn find_shard_keys(client: &Client, bucket: &str, key: &str) -> Result<Vec<String>> {
+    let keys = split_shard_keys(key).unwrap_or_else(|| vec![key.to_string()]);
+    if keys.len() == 1 && !key.to_ascii_lowercase().ends_with(".gguf") {
+        return Ok(keys);
+    }
+
+    let first = &keys[0];
+    let header = match object_info(client, bucket, first).await {
+        Ok(object) => fetch_gguf_header(client, bucket, first, object.size as i64).await,
+        Err(err) => Err(err),
+    };
+    let header = match header {
+        Ok((header, _)) => header,
+        Err(err) => {
+            debug!(key = %first, "Not checking split metadata: {:#}", err);
+            return Ok(keys);
+        }
+    };
+
+    check_split_metadata(&header, first, 0, keys.len()).with_context(|| {
+        error::Error::new(ErrorKind::InvalidModel, format!("s3://{}/{} is not a complete split model", bucket, key))
+    })?;
+    Ok(keys)
+}
+
+// The shard count recorded in a GGUF file, treating a missing key as a single file
+pub fn split_count(gguf: &GgufFile) -> u64 {
+    gguf.get(SPLIT_COUNT_KEY).and_then(Value::as_u64).unwrap_or(1)
+}
+
+// Check that a shard's split metadata agrees with its position in the set of keys
+pub fn check_split_metadata(gguf: &GgufFile, key: &str, index: usize, count: usize) -> Result<()> {
+    let recorded_count = split_count(gguf);
+    if recorded_count != count as u64 {
+        if count == 1 {
+            anyhow::bail!(
+                "{} is one of {} shards, but its name doesn't follow the llama.cpp pattern \
+                 PREFIX-00001-of-{:05}.gguf so the other shards can't be found",
+                key, recorded_count, recorded_count
+            );
+        }
+        anyhow::bail!(
+            "{} records {}={} but {} shards were found from its name",
+            key, SPLIT_COUNT_KEY, recorded_count, count
+        );
+    }
+
+    if let Some(recorded_index) = gguf.get(SPLIT_NO_KEY).and_then(Value::as_u64) {
+        if recorded_index != index as u64 {
+            anyhow::bail!(
+                "{} records {}={} but its name says it is shard {}",
+                key, SPLIT_NO_KEY, recorded_index, index
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::tests::build_gguf;
+
+    #[test]
+    fn test_split_shard_keys() {
+        assert_eq!(
+            split_shard_keys("models/qwen-7b-00001-of-00003.gguf").unwrap(),
+            vec![
+                "models/qwen-7b-00001-of-00003.gguf",
+                "models/qwen-7b-00002-of-00003.gguf",
+                "models/qwen-7b-00003-of-00003.gguf",
+            ]
+        );
+
+        // Any shard of the set leads to all of them, first shard first
+        assert_eq!(
+            split_shard_keys("m-00002-of-00002.gguf").unwrap()[0],
+            "m-00001-of-00002.gguf"
+        );
+
+        assert!(split_shard_keys("models/qwen-7b.gguf").is_none());
+        assert!(split_shard_keys("m-00001-of-00001.gguf").is_none());
+        assert!(split_shard_keys("m-1-of-2.gguf").is_none());
+        assert!(split_shard_keys("m-00003-of-00002.gguf").is_none());
+    }
+
+    #[test]
+    fn test_check_split_metadata() {
+        let bytes = build_gguf(
+            &[
+                (SPLIT_NO_KEY, 2, 1u16.to_le_bytes().to_vec()),
+                (SPLIT_COUNT_KEY, 2, 2u16.to_le_bytes().to_vec()),
+            ],
+            &[],
+            0,
+        );
+        let gguf = crate::gguf::parse(&bytes, None).unwrap();
+        assert!(check_split_metadata(&gguf, "m-00002-of-00002.gguf", 1, 2).is_ok());
+        assert!(check_split_metadata(&gguf, "m-00001-of-00002.gguf", 0, 2).is_err());
+        assert!(check_split_metadata(&gguf, "m.gguf", 0, 1).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_find_shard_keys() {
+        let shard = |no: u16, count: u16| {
+            build_gguf(
+                &[
+                    (SPLIT_NO_KEY, 2, no.to_le_bytes().to_vec()),
+                    (SPLIT_COUNT_KEY, 2, count.to_le_bytes().to_vec()),
+                ],
+                &[],
+                0,
+            )
+        };
+        let s3 = crate::local_s3::LocalS3::start(vec![
+            ("models", "m-00001-of-00002.gguf", shard(0, 2)),
+            ("models", "m-00002-of-00002.gguf", shard(1, 2)),
+            ("models", "renamed.gguf", shard(0, 2)),
+            ("models", "wrong-00001-of-00003.gguf", shard(0, 2)),
+            ("models", "plain.gguf", build_gguf(&[], &[], 0)),
+            ("models", "weights.bin", vec![0; 64]),
+        ])
+        .await
+        .unwrap();
+        let client = s3.client().await;
+
+        let keys = find_shard_keys(&client, "models", "m-00002-of-00002.gguf").await.unwrap();
+        assert_eq!(keys, vec!["m-00001-of-00002.gguf", "m-00002-of-00002.gguf"]);
+        assert_eq!(find_shard_keys(&client, "models", "plain.gguf").await.unwrap(), vec!["plain.gguf"]);
+        assert_eq!(find_shard_keys(&client, "models", "weights.bin").await.unwrap(), vec!["weights.bin"]);
+
+        // A shard whose name lost the pattern, or whose name disagrees with its metadata
+        for key in ["renamed.gguf", "wrong-00001-of-00003.gguf"] {
+            let err = find_shard_keys(&client, "models", key).await.unwrap_err();
+            assert_eq!(ErrorKind::of(&err), ErrorKind::InvalidModel, "{}", key);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Supervisor mode: run the program as a child instead of exec'ing it
+// The memory files stay open in this process, so when the program crashes (an out of
+// memory KV cache, a failed assertion) it is restarted from them in seconds instead of
+// the whole sandbox cold starting and downloading the model again. SIGTERM and SIGINT
+// are forwarded to the child, and a restart limit stops a crash loop.
+
+use crate::child_output::{self, Capture};     // Structured program output
+use crate::error::{Error, ErrorKind};         // Typed errors
+use crate::runtime_api;                       // End of init for error reporting
+use anyhow::{Context, Result};                // Error handling with context
+use std::process::Command;                    // Process execution
+use std::time::{Duration, Instant};           // Backoff and uptime
+use tracing::{error, info, warn};             // Structured logging
+
+// Signals the supervisor handles itself; they are blocked and waited for synchronously
+const HANDLED_SIGNALS: [i32; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGCHLD];
+
+// How the child is restarted
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,                // Consecutive crashes before giving up
+    pub initial_backoff: Duration,        // Delay before the first restart, doubled each time
+    pub max_backoff: Duration,            // Upper bound for the delay
+    pub reset_after: Duration,            // A child that ran this long resets the crash count
+}
+
+impl RestartPolicy {
+    pub fn new(max_restarts: u32) -> Self {
+        RestartPolicy {
+            max_restarts,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            reset_after: Duration::from_secs(300),
+        }
+    }
+
+    // Delay before restart number `attempt`, counting from 1
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+// Block the handled signals in the calling thread
+// Threads inherit the mask of the thread that creates them, so calling this from the
+// async runtime's thread start hook keeps its threads from taking the signals, and
+// calling it before supervising routes them to the supervisor's sigtimedwait
+pub fn block_signals() {
+    unsafe {
+        let set = signal_set();
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
+
+// Run the command until it exits cleanly, is stopped by a signal sent to the supervisor,
+// or crashes more often in a row than the policy allows
+// Returns the exit code the supervisor should exit with
+// With `capture`, the lines of a child started with piped output are re-emitted as logs
+pub fn supervise(cmd: &mut Command, policy: &RestartPolicy, capture: Option<&Capture>) -> Result<i32> {
+    block_signals();
+
+    let mut crashes = 0;
+    loop {
+        let started = Instant::now();
+        let mut child = cmd
+            .spawn()
+            .context(Error::new(ErrorKind::ExecFailed, "Failed to start program"))?;
+        let pid = child.id() as libc::pid_t;
+        runtime_api::program_started();
+        info!(pid, restarts = crashes, "Program started");
+        let readers = capture.map(|capture| child_output::capture_child(&mut child, capture));
+
+        let (status, stopping) = wait_forwarding_signals(pid)?;
+        // Let the last lines of output through before deciding what to do next
+        if let Some(readers) = readers {
+            readers.wait(Duration::from_secs(1));
+        }
+        let code = exit_code(status);
+        let uptime = started.elapsed();
+
+        if stopping {
+            info!(code, "Program stopped");
+            return Ok(code);
+        }
+        if libc::WIFEXITED(status) && code == 0 {
+            info!("Program exited successfully");
+            return Ok(0);
+        }
+
+        // A child that ran for a while crashed for a new reason, not in a loop
+        if uptime >= policy.reset_after {
+            crashes = 0;
+        }
+        if crashes >= policy.max_restarts {
+            error!(code, restarts = crashes, "Program crashed too many times in a row, giving up");
+            return Ok(code);
+        }
+        crashes += 1;
+
+        let delay = policy.backoff(crashes);
+        warn!(
+            code,
+            uptime_secs = uptime.as_secs_f64(),
+            restart = crashes,
+            max_restarts = policy.max_restarts,
+            delay_ms = delay.as_millis() as u64,
+            "Program crashed, restarting"
+        );
+        if let Some(signal) = wait_for_signal(Some(delay))
+            .filter(|&signal| signal == libc::SIGTERM || signal == libc::SIGINT)
+        {
+            info!(signal, "Stopping instead of restarting the program");
+            return Ok(128 + signal);
+        }
+    }
+}
+
+// Wait for the child to exit, forwarding SIGTERM and SIGINT to it
+// Returns its wait status and whether it was asked to stop
+fn wait_forwarding_signals(pid: libc::pid_t) -> Result<(i32, bool)> {
+    let mut stopping = false;
+    loop {
+        let mut status = 0;
+        let reaped = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+        if reaped == pid {
+            return Ok((status, stopping));
+        }
+        if reaped == -1 {
+            return Err(std::io::Error::last_os_error()).context("Failed to wait for program");
+        }
+
+        // SIGCHLD wakes this up when the child exits; the timeout covers a SIGCHLD
+        // taken by another thread that doesn't block it
+        match wait_for_signal(Some(Duration::from_millis(200))) {
+            Some(signal) if signal == libc::SIGTERM || signal == libc::SIGINT => {
+                info!(signal, pid, "Forwarding signal to program");
+                unsafe { libc::kill(pid, signal) };
+                stopping = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Wait up to `timeout` for one of the handled signals, which must be blocked
+fn wait_for_signal(timeout: Option<Duration>) -> Option<i32> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let timespec = remaining.map(|remaining| libc::timespec {
+            tv_sec: remaining.as_secs() as libc::time_t,
+            tv_nsec: remaining.subsec_nanos() as libc::c_long,
+        });
+        let set = signal_set();
+        let signal = unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            libc::sigtimedwait(
+                &set,
+                &mut info,
+                timespec.as_ref().map_or(std::ptr::null(), |timespec| timespec as *const _),
+            )
+        };
+        if signal > 0 {
+            return Some(signal);
+        }
+        // EINTR from an unrelated signal is retried; EAGAIN means the timeout expired
+        if std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            return None;
+        }
+    }
+}
+
+// The set of handled signals
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in HANDLED_SIGNALS {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    }
+}
+
+// Exit code for a wait status, using the shell's 128 + N convention for signals
+fn exit_code(status: i32) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fast_policy(max_restarts: u32) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(10),
+            ..RestartPolicy::new(max_restarts)
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy::new(5);
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(10), Duration::from_secs(30));
+        assert_eq!(policy.backoff(64), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_restart_limit() {
+        let dir = crate::runtime_dir::RuntimeDir::new().unwrap();
+        let starts = dir.path().join("starts");
+
+        // Crashes every time, so it runs once plus two restarts
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!("echo >> {}; exit 3", starts.display()));
+        assert_eq!(supervise(&mut cmd, &fast_policy(2), None).unwrap(), 3);
+        assert_eq!(std::fs::read_to_string(&starts).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_restart_until_success() {
+        let dir = crate::runtime_dir::RuntimeDir::new().unwrap();
+        let starts = dir.path().join("starts");
+
+        // Killed by a signal on the first start, exits cleanly on the second
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "echo >> {0}; [ $(wc -l < {0}) -ge 2 ] || kill -KILL $$",
+            starts.display()
+        ));
+        assert_eq!(supervise(&mut cmd, &fast_policy(5), None).unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&starts).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let status = std::process::Command::new("sh").args(["-c", "exit 7"]).status().unwrap();
+        assert_eq!(exit_code(std::os::unix::process::ExitStatusExt::into_raw(status)), 7);
+    }
+}
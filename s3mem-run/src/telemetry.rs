@@ -0,0 +1,797 @@
+// OpenTelemetry trace export over OTLP/HTTP
+// The #[instrument] spans of the download pipeline are recorded by a tracing layer and
+// exported as OTLP protobuf to a collector, such as the ADOT Lambda extension listening
+// on localhost:4318. When Lambda sets _X_AMZN_TRACE_ID, the spans join that X-Ray trace
+// under its parent segment, and S3 requests carry the chunk span as their parent.
+// Spans are buffered and sent by flush(), which main calls before the program replaces
+// the process; an exec'd process never gets to run a background exporter.
+
+use std::fmt;                                 // Field values recorded as strings
+use std::io::{Read, Write};                   // Talking HTTP to the collector
+use std::net::{TcpStream, ToSocketAddrs};     // Collector connection
+use std::sync::{Arc, Mutex, OnceLock};        // Buffered spans shared with flush()
+use std::time::{Duration, SystemTime, UNIX_EPOCH};  // Span times
+use tracing::field::{Field, Visit};           // Reading span and event fields
+use tracing::span::{Attributes, Id, Record};  // Span lifecycle
+use tracing::{warn, Event, Level, Subscriber};  // Events attached to spans
+use tracing_subscriber::layer::{Context, Layer};  // The recording layer
+use tracing_subscriber::registry::LookupSpan; // Span extensions
+use tracing_subscriber::Registry;             // Looking up the current span's ids
+
+pub const TRACE_HEADER_ENV: &str = "_X_AMZN_TRACE_ID";  // Set by Lambda with tracing active
+pub const TRACE_HEADER: &str = "X-Amzn-Trace-Id";       // X-Ray header on S3 requests
+const TRACES_PATH: &str = "/v1/traces";       // OTLP/HTTP traces path under the endpoint
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(2);  // Per connect, write and read
+const MAX_BUFFERED_SPANS: usize = 4096;       // Finished spans kept until the next flush
+const MAX_SPAN_EVENTS: usize = 32;            // Events kept per span
+
+// The exporter installed by init(), flushed by flush()
+static EXPORTER: OnceLock<Arc<Exporter>> = OnceLock::new();
+
+// An OTLP/HTTP collector endpoint such as http://localhost:4318
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    host: String,                         // Host name or address
+    port: u16,                            // Port, 80 when not given
+    path: String,                         // Base path, without a trailing slash
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let rest = value.strip_prefix("http://").ok_or_else(|| {
+            format!("{} is not an http:// URL; the exporter doesn't speak TLS, so use a local collector", value)
+        })?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        // IPv6 addresses are in brackets, so their colons aren't taken for a port
+        let port_separator = match authority.find(']') {
+            Some(bracket) => authority[bracket..].find(':').map(|colon| bracket + colon),
+            None => authority.rfind(':'),
+        };
+        let (host, port) = match port_separator {
+            Some(colon) => (
+                &authority[..colon],
+                authority[colon + 1..].parse().map_err(|_| format!("invalid port in {}", value))?,
+            ),
+            None => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("no host in {}", value));
+        }
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+// The trace the spans belong to
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_span_id: Option<[u8; 8]>,  // Parent of the root spans, from the X-Ray header
+    pub sampled: bool,                    // Spans of unsampled traces are not exported
+}
+
+impl TraceContext {
+    // Parse an X-Ray trace header: "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+    pub fn from_xray_header(header: &str) -> Option<Self> {
+        let mut root = None;
+        let mut parent = None;
+        let mut sampled = true;
+        for part in header.split(';') {
+            match part.trim().split_once('=') {
+                Some(("Root", value)) => root = Some(value),
+                Some(("Parent", value)) => parent = Some(value),
+                Some(("Sampled", value)) => sampled = value != "0",
+                _ => {}
+            }
+        }
+
+        // The root is "1-" followed by 8 hex digits of epoch seconds, a dash and 24 more
+        let (time, random) = root?.strip_prefix("1-")?.split_once('-')?;
+        if time.len() != 8 || random.len() != 24 {
+            return None;
+        }
+        let trace_id = parse_hex(&format!("{}{}", time, random))?;
+        let parent_span_id = match parent {
+            Some(parent) => Some(parse_hex(parent)?),
+            None => None,
+        };
+        Some(TraceContext { trace_id, parent_span_id, sampled })
+    }
+
+    // The trace from _X_AMZN_TRACE_ID, or a new one in X-Ray format
+    pub fn from_env() -> Self {
+        std::env::var(TRACE_HEADER_ENV)
+            .ok()
+            .and_then(|header| Self::from_xray_header(&header))
+            .unwrap_or_else(Self::generate)
+    }
+
+    fn generate() -> Self {
+        let mut trace_id = random_bytes::<16>();
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+        trace_id[..4].copy_from_slice(&seconds.to_be_bytes());
+        TraceContext { trace_id, parent_span_id: None, sampled: true }
+    }
+
+    // The X-Ray header naming a span of this trace as the parent
+    pub fn xray_header(&self, span_id: [u8; 8]) -> String {
+        let trace = hex(&self.trace_id);
+        format!(
+            "Root=1-{}-{};Parent={};Sampled={}",
+            &trace[..8],
+            &trace[8..],
+            hex(&span_id),
+            if self.sampled { 1 } else { 0 }
+        )
+    }
+}
+
+// A value of a span attribute
+#[derive(Debug, Clone, PartialEq)]
+enum AttributeValue {
+    String(String),
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+}
+
+type AttributeList = Vec<(String, AttributeValue)>;
+
+// A span while it is open, kept in the span's extensions
+#[derive(Debug)]
+struct OpenSpan {
+    context: TraceContext,
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: AttributeList,
+    events: Vec<SpanEvent>,
+    error: Option<String>,                // Message of the last error event
+}
+
+#[derive(Debug, Clone)]
+struct SpanEvent {
+    time: SystemTime,
+    name: String,
+    attributes: AttributeList,
+}
+
+// A closed span waiting to be exported
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: AttributeList,
+    events: Vec<SpanEvent>,
+    error: Option<String>,
+}
+
+// Buffers closed spans and sends them to the collector
+#[derive(Debug)]
+pub struct Exporter {
+    endpoint: Endpoint,
+    resource: AttributeList,
+    finished: Mutex<Vec<SpanData>>,
+    dropped: Mutex<usize>,                // Spans dropped because the buffer was full
+}
+
+impl Exporter {
+    pub fn new(endpoint: Endpoint) -> Self {
+        let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "s3mem-run".to_string());
+        let mut resource = vec![
+            ("service.name".to_string(), AttributeValue::String(service)),
+            ("service.version".to_string(), AttributeValue::String(env!("CARGO_PKG_VERSION").to_string())),
+        ];
+        if let Ok(function) = std::env::var("AWS_LAMBDA_FUNCTION_NAME") {
+            resource.push(("cloud.provider".to_string(), AttributeValue::String("aws".to_string())));
+            resource.push(("faas.name".to_string(), AttributeValue::String(function)));
+        }
+        Exporter { endpoint, resource, finished: Mutex::new(Vec::new()), dropped: Mutex::new(0) }
+    }
+
+    fn push(&self, span: SpanData) {
+        let mut finished = self.finished.lock().unwrap();
+        if finished.len() < MAX_BUFFERED_SPANS {
+            finished.push(span);
+        } else {
+            *self.dropped.lock().unwrap() += 1;
+        }
+    }
+
+    // Send every buffered span; failures are logged, never returned, so tracing can't
+    // stop the program from starting
+    pub fn flush(&self) {
+        let spans = std::mem::take(&mut *self.finished.lock().unwrap());
+        let dropped = std::mem::take(&mut *self.dropped.lock().unwrap());
+        if dropped > 0 {
+            warn!(dropped, "Trace buffer was full, spans were dropped");
+        }
+        if spans.is_empty() {
+            return;
+        }
+        let body = encode_request(&self.resource, &spans);
+        match self.post(&body) {
+            Ok(()) => tracing::debug!(spans = spans.len(), endpoint = %self.endpoint, "Exported spans"),
+            Err(err) => warn!(spans = spans.len(), endpoint = %self.endpoint, error = %err, "Failed to export spans"),
+        }
+    }
+
+    fn post(&self, body: &[u8]) -> std::io::Result<()> {
+        let addr = (self.endpoint.host.as_str(), self.endpoint.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::other("the collector host has no address"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+
+        let head = format!(
+            "POST {}{} HTTP/1.1\r\n\
+             Host: {}:{}\r\n\
+             Content-Type: application/x-protobuf\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.endpoint.path,
+            TRACES_PATH,
+            self.endpoint.host,
+            self.endpoint.port,
+            body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+
+        // Only the status line matters
+        let mut response = Vec::new();
+        let mut buf = [0u8; 512];
+        while !response.contains(&b'\n') {
+            let read = stream.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..read]);
+        }
+        let status_line = String::from_utf8_lossy(&response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(std::io::Error::other(format!("collector answered {:?}", status_line))),
+        }
+    }
+}
+
+// Records spans for the exporter
+pub struct OtlpLayer {
+    context: TraceContext,
+    exporter: Arc<Exporter>,
+}
+
+impl OtlpLayer {
+    pub fn new(context: TraceContext, exporter: Arc<Exporter>) -> Self {
+        OtlpLayer { context, exporter }
+    }
+}
+
+// Create the exporter for an endpoint and the layer that feeds it, in the trace from
+// _X_AMZN_TRACE_ID; flush() sends its spans from then on
+pub fn init(endpoint: Endpoint) -> OtlpLayer {
+    let exporter = EXPORTER.get_or_init(|| Arc::new(Exporter::new(endpoint))).clone();
+    OtlpLayer::new(TraceContext::from_env(), exporter)
+}
+
+// Send the spans closed so far, if an exporter is installed
+pub fn flush() {
+    if let Some(exporter) = EXPORTER.get() {
+        exporter.flush();
+    }
+}
+
+// The X-Ray header naming the current span as the parent, if it is being recorded
+pub fn current_trace_header() -> Option<String> {
+    let id = tracing::Span::current().id()?;
+    tracing::dispatcher::get_default(|dispatch| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(&id)?;
+        let extensions = span.extensions();
+        let open = extensions.get::<OpenSpan>()?;
+        Some(open.context.xray_header(open.span_id))
+    })
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        // Child spans inherit the trace of their parent, root spans join the X-Ray trace
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            extensions.get::<OpenSpan>().map(|open| (open.context.clone(), open.span_id))
+        });
+        let (context, parent_span_id) = match parent {
+            Some((context, span_id)) => (context, Some(span_id)),
+            None => (self.context.clone(), self.context.parent_span_id),
+        };
+
+        let mut attributes = Vec::new();
+        attrs.record(&mut AttributeVisitor { attributes: &mut attributes, message: None });
+        span.extensions_mut().insert(OpenSpan {
+            context,
+            span_id: random_bytes(),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+            events: Vec::new(),
+            error: None,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(open) = extensions.get_mut::<OpenSpan>() {
+            values.record(&mut AttributeVisitor { attributes: &mut open.attributes, message: None });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else { return };
+        let mut extensions = span.extensions_mut();
+        let Some(open) = extensions.get_mut::<OpenSpan>() else { return };
+
+        let mut attributes = Vec::new();
+        let mut message = None;
+        event.record(&mut AttributeVisitor { attributes: &mut attributes, message: Some(&mut message) });
+        let name = message.unwrap_or_else(|| event.metadata().name().to_string());
+        if *event.metadata().level() == Level::ERROR {
+            open.error = Some(name.clone());
+        }
+        if open.events.len() < MAX_SPAN_EVENTS {
+            attributes.push(("level".to_string(), AttributeValue::String(event.metadata().level().to_string())));
+            open.events.push(SpanEvent { time: SystemTime::now(), name, attributes });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else { return };
+        if !open.context.sampled {
+            return;
+        }
+        self.exporter.push(SpanData {
+            trace_id: open.context.trace_id,
+            span_id: open.span_id,
+            parent_span_id: open.parent_span_id,
+            name: span.name(),
+            start: open.start,
+            end: SystemTime::now(),
+            attributes: open.attributes,
+            events: open.events,
+            error: open.error,
+        });
+    }
+}
+
+// Records fields as attributes, and the message separately when asked for it
+struct AttributeVisitor<'a> {
+    attributes: &'a mut AttributeList,
+    message: Option<&'a mut Option<String>>,
+}
+
+impl AttributeVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: AttributeValue) {
+        if field.name() == "message" {
+            if let Some(message) = &mut self.message {
+                if let AttributeValue::String(text) = value {
+                    **message = Some(text);
+                }
+                return;
+            }
+        }
+        match self.attributes.iter_mut().find(|(key, _)| key == field.name()) {
+            Some((_, existing)) => *existing = value,
+            None => self.attributes.push((field.name().to_string(), value)),
+        }
+    }
+}
+
+impl Visit for AttributeVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, AttributeValue::Double(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, AttributeValue::Int(value as i64));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, AttributeValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, AttributeValue::String(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, AttributeValue::String(format!("{:?}", value)));
+    }
+}
+
+// Protobuf encoding of an ExportTraceServiceRequest
+// Field numbers are those of opentelemetry/proto/trace/v1/trace.proto and common.proto
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut Proto)) {
+        let mut inner = Proto::default();
+        build(&mut inner);
+        self.bytes(field, &inner.0);
+    }
+}
+
+fn encode_attributes(proto: &mut Proto, field: u32, attributes: &AttributeList) {
+    for (key, value) in attributes {
+        // KeyValue { key = 1; AnyValue value = 2 }
+        proto.message(field, |key_value| {
+            key_value.bytes(1, key.as_bytes());
+            key_value.message(2, |any| match value {
+                AttributeValue::String(value) => any.bytes(1, value.as_bytes()),
+                AttributeValue::Bool(value) => any.uint(2, *value as u64),
+                AttributeValue::Int(value) => any.uint(3, *value as u64),
+                AttributeValue::Double(value) => any.fixed64(4, value.to_bits()),
+            });
+        });
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+fn encode_request(resource: &AttributeList, spans: &[SpanData]) -> Vec<u8> {
+    let mut request = Proto::default();
+    // ExportTraceServiceRequest { ResourceSpans resource_spans = 1 }
+    request.message(1, |resource_spans| {
+        // ResourceSpans { Resource resource = 1; ScopeSpans scope_spans = 2 }
+        resource_spans.message(1, |proto| encode_attributes(proto, 1, resource));
+        resource_spans.message(2, |scope_spans| {
+            // ScopeSpans { InstrumentationScope scope = 1; Span spans = 2 }
+            scope_spans.message(1, |scope| {
+                scope.bytes(1, env!("CARGO_PKG_NAME").as_bytes());
+                scope.bytes(2, env!("CARGO_PKG_VERSION").as_bytes());
+            });
+            for span in spans {
+                scope_spans.message(2, |proto| {
+                    proto.bytes(1, &span.trace_id);
+                    proto.bytes(2, &span.span_id);
+                    if let Some(parent) = &span.parent_span_id {
+                        proto.bytes(4, parent);
+                    }
+                    proto.bytes(5, span.name.as_bytes());
+                    proto.uint(6, 1); // SPAN_KIND_INTERNAL
+                    proto.fixed64(7, unix_nanos(span.start));
+                    proto.fixed64(8, unix_nanos(span.end));
+                    encode_attributes(proto, 9, &span.attributes);
+                    for event in &span.events {
+                        // Event { time_unix_nano = 1; name = 2; attributes = 3 }
+                        proto.message(11, |proto| {
+                            proto.fixed64(1, unix_nanos(event.time));
+                            proto.bytes(2, event.name.as_bytes());
+                            encode_attributes(proto, 3, &event.attributes);
+                        });
+                    }
+                    // Status { message = 2; code = 3 }, with STATUS_CODE_ERROR = 2
+                    if let Some(error) = &span.error {
+                        proto.message(15, |status| {
+                            status.bytes(2, error.as_bytes());
+                            status.uint(3, 2);
+                        });
+                    }
+                });
+            }
+        });
+    });
+    request.0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// Random bytes for ids from getrandom(2), which doesn't fail for small requests once the
+// pool is initialized; ids of all zeros are invalid, so the last byte is never zero
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    let filled = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), N, 0) };
+    if filled != N as isize {
+        // Unlikely, but ids only need to be unique, not secret
+        let nanos = unix_nanos(SystemTime::now()).to_le_bytes();
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte ^= nanos[index % 8].wrapping_add(index as u8);
+        }
+    }
+    bytes[N - 1] |= 1;
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const HEADER: &str = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    // A decoded protobuf field
+    #[derive(Debug, Clone)]
+    enum Wire {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn decode(mut data: &[u8]) -> Vec<(u32, Wire)> {
+        fn varint(data: &mut &[u8]) -> u64 {
+            let mut value = 0u64;
+            for shift in (0..64).step_by(7) {
+                let byte = data[0];
+                *data = &data[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        }
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = varint(&mut data);
+            let value = match key & 7 {
+                0 => Wire::Varint(varint(&mut data)),
+                1 => {
+                    let value = u64::from_le_bytes(data[..8].try_into().unwrap());
+                    data = &data[8..];
+                    Wire::Fixed64(value)
+                }
+                2 => {
+                    let len = varint(&mut data) as usize;
+                    let value = data[..len].to_vec();
+                    data = &data[len..];
+                    Wire::Bytes(value)
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push(((key >> 3) as u32, value));
+        }
+        fields
+    }
+
+    fn all(fields: &[(u32, Wire)], number: u32) -> Vec<Wire> {
+        fields.iter().filter(|(field, _)| *field == number).map(|(_, value)| value.clone()).collect()
+    }
+
+    fn bytes(fields: &[(u32, Wire)], number: u32) -> Vec<u8> {
+        match all(fields, number).first() {
+            Some(Wire::Bytes(value)) => value.clone(),
+            other => panic!("field {} is {:?}", number, other),
+        }
+    }
+
+    fn message(fields: &[(u32, Wire)], number: u32) -> Vec<(u32, Wire)> {
+        decode(&bytes(fields, number))
+    }
+
+    // A stand-in collector that answers one request and returns its head and body
+    fn collector(status: &'static str) -> (Endpoint, std::thread::JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/otlp/", listener.local_addr().unwrap()).parse().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let header_end = loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let head = String::from_utf8(request[..header_end].to_vec()).unwrap();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            while request.len() < header_end + length {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            (head, request[header_end..].to_vec())
+        });
+        (endpoint, handle)
+    }
+
+    #[test]
+    fn test_parse_xray_header() {
+        let context = TraceContext::from_xray_header(HEADER).unwrap();
+        assert_eq!(hex(&context.trace_id), "5759e988bd862e3fe1be46a994272793");
+        assert_eq!(context.parent_span_id.map(|id| hex(&id)).as_deref(), Some("53995c3f42cd8ad8"));
+        assert!(context.sampled);
+        assert_eq!(context.xray_header(context.parent_span_id.unwrap()), HEADER);
+
+        let context = TraceContext::from_xray_header("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0;Lineage=a:1").unwrap();
+        assert_eq!(context.parent_span_id, None);
+        assert!(!context.sampled);
+
+        assert!(TraceContext::from_xray_header("Root=5759e988bd862e3fe1be46a994272793").is_none());
+        assert!(TraceContext::from_xray_header("Parent=53995c3f42cd8ad8").is_none());
+
+        // Generated traces are valid X-Ray traces too
+        let generated = TraceContext::generate();
+        assert!(TraceContext::from_xray_header(&generated.xray_header(random_bytes())).is_some());
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint: Endpoint = "http://localhost:4318".parse().unwrap();
+        assert_eq!(endpoint, Endpoint { host: "localhost".to_string(), port: 4318, path: String::new() });
+        let endpoint: Endpoint = "http://[::1]:4318/collector/".parse().unwrap();
+        assert_eq!(endpoint, Endpoint { host: "::1".to_string(), port: 4318, path: "/collector".to_string() });
+        assert_eq!("http://collector".parse::<Endpoint>().unwrap().port, 80);
+        assert!("https://collector:4318".parse::<Endpoint>().is_err());
+        assert!("http://:4318".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_export_spans_to_collector() {
+        let (endpoint, collector) = collector("200 OK");
+        let exporter = Arc::new(Exporter::new(endpoint));
+        let context = TraceContext::from_xray_header(HEADER).unwrap();
+        // The same stack logging::init builds, so the registry is found through the fmt layers
+        let subscriber = tracing_subscriber::FmtSubscriber::builder()
+            .with_env_filter(crate::logging::env_filter(Level::INFO, None))
+            .with_writer(std::io::sink)
+            .finish()
+            .with(Some(OtlpLayer::new(context.clone(), exporter.clone())));
+
+        let inner_header = tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("create_memfd_and_exec", key = "model.gguf");
+            let _outer = outer.enter();
+            let inner = tracing::info_span!("download_chunk", start = 0u64, end = 1023u64);
+            let _inner = inner.enter();
+            tracing::error!(attempt = 2u64, "Chunk download failed");
+            current_trace_header().unwrap()
+        });
+        exporter.flush();
+        let (head, body) = collector.join().unwrap();
+
+        assert!(head.starts_with("POST /otlp/v1/traces HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: application/x-protobuf\r\n"));
+
+        let request = decode(&body);
+        let resource_spans = message(&request, 1);
+        let resource = message(&resource_spans, 1);
+        assert_eq!(bytes(&message(&resource, 1), 1), b"service.name");
+
+        let scope_spans = message(&resource_spans, 2);
+        assert_eq!(bytes(&message(&scope_spans, 1), 1), b"s3mem-run");
+        let spans: Vec<Vec<(u32, Wire)>> = all(&scope_spans, 2)
+            .into_iter()
+            .map(|span| match span {
+                Wire::Bytes(span) => decode(&span),
+                other => panic!("span is {:?}", other),
+            })
+            .collect();
+
+        // The inner span closes first
+        assert_eq!(spans.len(), 2);
+        let (inner, outer) = (&spans[0], &spans[1]);
+        assert_eq!(bytes(inner, 5), b"download_chunk");
+        assert_eq!(bytes(outer, 5), b"create_memfd_and_exec");
+
+        // Both spans are in the X-Ray trace, the outer one under the Lambda segment
+        assert_eq!(bytes(inner, 1), context.trace_id);
+        assert_eq!(bytes(outer, 1), context.trace_id);
+        assert_eq!(bytes(outer, 4), context.parent_span_id.unwrap());
+        assert_eq!(bytes(inner, 4), bytes(outer, 2));
+
+        // S3 requests made in the inner span name it as their parent
+        let inner_id: [u8; 8] = bytes(inner, 2).try_into().unwrap();
+        assert_eq!(inner_header, context.xray_header(inner_id));
+
+        // Times, attributes, the error event and the error status
+        let (Some(Wire::Fixed64(start)), Some(Wire::Fixed64(end))) = (all(inner, 7).pop(), all(inner, 8).pop()) else {
+            panic!("span times are missing");
+        };
+        assert!(start > 0 && end >= start);
+        let attribute = decode(&bytes(inner, 9));
+        assert_eq!(bytes(&attribute, 1), b"start");
+        assert!(matches!(decode(&bytes(&attribute, 2))[0], (3, Wire::Varint(0))));
+        assert_eq!(bytes(&message(inner, 11), 2), b"Chunk download failed");
+        let status = message(inner, 15);
+        assert!(matches!(all(&status, 3)[..], [Wire::Varint(2)]));
+        assert!(all(outer, 15).is_empty());
+    }
+
+    #[test]
+    fn test_unsampled_and_failed_exports() {
+        // Spans of unsampled traces are never buffered
+        let exporter = Arc::new(Exporter::new("http://127.0.0.1:9".parse().unwrap()));
+        let context = TraceContext::from_xray_header("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0").unwrap();
+        let subscriber = Registry::default().with(OtlpLayer::new(context, exporter.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("download_chunk").entered();
+        });
+        assert!(exporter.finished.lock().unwrap().is_empty());
+
+        // A collector error is reported, not returned
+        let (endpoint, collector) = collector("500 Internal Server Error");
+        let exporter = Exporter::new(endpoint);
+        let error = exporter.post(b"").unwrap_err();
+        assert!(error.to_string().contains("500"));
+        collector.join().unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+// Expansion of {{gguf:...}} and {{memfd_link:...}} placeholders in command arguments
+// A placeholder holds a small expression over the GGUF metadata of the downloaded model:
+//   {{gguf:general.name}}                          a metadata value
+//   {{gguf:{arch}.context_length}}                 {arch} is replaced with general.architecture
+//   {{gguf:min({arch}.context_length, 32768)}}     min/max of numeric expressions
+//   {{gguf:default(general.name, "model")}}        fall back when a key is missing
+
+use crate::gguf::{GgufFile, Value};           // Parsed GGUF metadata
+use anyhow::{Context, Result};                // Error handling with context
+use std::fmt;                                 // Display for evaluated values
+
+pub const GGUF_PLACEHOLDER_PREFIX: &str = "{{gguf:";  // Start of a GGUF metadata placeholder
+pub const MEMFD_LINK_PLACEHOLDER_PREFIX: &str = "{{memfd_link:";  // Start of a named symlink placeholder
+const PLACEHOLDER_SUFFIX: &str = "}}";                 // End of any placeholder
+const ARCH_VARIABLE: &str = "{arch}";                  // Replaced with general.architecture inside keys
+
+// Result of evaluating a placeholder expression
+#[derive(Debug, Clone, PartialEq)]
+enum Evaluated {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Evaluated {
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Evaluated::Int(v) => Some(v as f64),
+            Evaluated::Float(v) => Some(v),
+            Evaluated::Str(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Evaluated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Evaluated::Int(v) => write!(f, "{}", v),
+            Evaluated::Float(v) => write!(f, "{}", v),
+            Evaluated::Str(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+// Parsed placeholder expression
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Key(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Call(String, Vec<Expr>),
+}
+
+// Return true if any argument contains a GGUF placeholder
+pub fn has_gguf_placeholders(args: &[String]) -> bool {
+    args.iter().any(|arg| arg.contains(GGUF_PLACEHOLDER_PREFIX))
+}
+
+// Replace every {{gguf:...}} placeholder in an argument with its evaluated value
+pub fn expand_gguf_placeholders(arg: &str, gguf: &GgufFile) -> Result<String> {
+    expand_placeholders(arg, GGUF_PLACEHOLDER_PREFIX, |body| {
+        let value = parse_expression(body)
+            .and_then(|expr| evaluate(&expr, gguf))
+            .with_context(|| format!("Failed to expand placeholder '{{{{gguf:{}}}}}'", body))?;
+        Ok(value.to_string())
+    })
+}
+
+// Return true if any argument contains a named symlink placeholder
+pub fn has_memfd_link_placeholders(args: &[String]) -> bool {
+    args.iter().any(|arg| arg.contains(MEMFD_LINK_PLACEHOLDER_PREFIX))
+}
+
+// Replace every {{memfd_link:NAME}} or {{memfd_link:N:NAME}} placeholder in an argument
+// `link` is called with the 1-based memfd number (1 when omitted) and the link name,
+// and returns the path of the symlink to substitute
+pub fn expand_memfd_links(
+    arg: &str,
+    mut link: impl FnMut(usize, &str) -> Result<String>,
+) -> Result<String> {
+    expand_placeholders(arg, MEMFD_LINK_PLACEHOLDER_PREFIX, |body| {
+        let (number, name) = match body.split_once(':') {
+            Some((number, name)) if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) => {
+                (number.parse().context("Invalid memfd number")?, name)
+            }
+            _ => (1, body),
+        };
+        link(number, name)
+            .with_context(|| format!("Failed to expand placeholder '{{{{memfd_link:{}}}}}'", body))
+    })
+}
+
+// Replace every placeholder starting with `prefix` with the result of `expand` on its body
+fn expand_placeholders(
+    arg: &str,
+    prefix: &str,
+    mut expand: impl FnMut(&str) -> Result<String>,
+) -> Result<String> {
+    let mut out = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find(prefix) {
+        out.push_str(&rest[..start]);
+        let body_start = start + prefix.len();
+        let body_len = closing_brace_offset(&rest[body_start..])
+            .with_context(|| format!("Unterminated placeholder in argument '{}'", arg))?;
+        out.push_str(&expand(&rest[body_start..body_start + body_len])?);
+        rest = &rest[body_start + body_len + PLACEHOLDER_SUFFIX.len()..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Find where the closing "}}" of a placeholder starts
+// Keys may end in "{arch}", so a run like "}}}" closes on its last two braces
+fn closing_brace_offset(body: &str) -> Option<usize> {
+    let first = body.find(PLACEHOLDER_SUFFIX)?;
+    let run = body[first..].bytes().take_while(|&b| b == b'}').count();
+    Some(first + run - PLACEHOLDER_SUFFIX.len())
+}
+
+// Recursive-descent parser for placeholder expressions
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(c) => anyhow::bail!("Expected '{}' at position {} but found '{}'", expected, self.pos, c),
+            None => anyhow::bail!("Expected '{}' at end of expression", expected),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => anyhow::bail!("Empty expression"),
+            Some('"') => self.string(),
+            Some(c) if c.is_ascii_digit() || c == '-' => self.number(),
+            Some(_) => {
+                let word = self.word();
+                if word.is_empty() {
+                    anyhow::bail!("Unexpected character at position {}", self.pos);
+                }
+                self.skip_whitespace();
+                if self.peek() == Some('(') {
+                    self.pos += 1;
+                    let mut args = vec![self.expression()?];
+                    self.skip_whitespace();
+                    while self.peek() == Some(',') {
+                        self.pos += 1;
+                        args.push(self.expression()?);
+                        self.skip_whitespace();
+                    }
+                    self.expect(')')?;
+                    Ok(Expr::Call(word, args))
+                } else {
+                    Ok(Expr::Key(word))
+                }
+            }
+        }
+    }
+
+    // A metadata key or function name: letters, digits, '_', '.', '-' and the {arch} variable
+    fn word(&mut self) -> String {
+        let start = self.pos;
+        loop {
+            let rest = &self.input[self.pos..];
+            if rest.starts_with(ARCH_VARIABLE) {
+                self.pos += ARCH_VARIABLE.len();
+            } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || "_.-".contains(c)) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        self.input[start..self.pos].to_string()
+    }
+
+    fn number(&mut self) -> Result<Expr> {
+        let start = self.pos;
+        self.pos += 1;
+        while self.input[self.pos..].starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        let text = &self.input[start..self.pos];
+        if let Ok(value) = text.parse::<i64>() {
+            return Ok(Expr::Int(value));
+        }
+        text.parse::<f64>()
+            .map(Expr::Float)
+            .with_context(|| format!("Invalid number '{}'", text))
+    }
+
+    fn string(&mut self) -> Result<Expr> {
+        self.pos += 1;
+        let end = self.input[self.pos..]
+            .find('"')
+            .context("Unterminated string literal")?;
+        let value = self.input[self.pos..self.pos + end].to_string();
+        self.pos += end + 1;
+        Ok(Expr::Str(value))
+    }
+}
+
+// Parse a complete placeholder expression
+fn parse_expression(input: &str) -> Result<Expr> {
+    let mut parser = Parser { input, pos: 0 };
+    let expr = parser.expression()?;
+    parser.skip_whitespace();
+    if parser.pos != input.len() {
+        anyhow::bail!("Unexpected trailing input '{}'", &input[parser.pos..]);
+    }
+    Ok(expr)
+}
+
+// Look up a metadata key, expanding {arch} first
+fn lookup(key: &str, gguf: &GgufFile) -> Result<Option<Evaluated>> {
+    let key = if key.contains(ARCH_VARIABLE) {
+        let arch = gguf
+            .architecture()
+            .context("{arch} used but general.architecture is not set")?;
+        key.replace(ARCH_VARIABLE, arch)
+    } else {
+        key.to_string()
+    };
+
+    let value = match gguf.get(&key) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let evaluated = match *value {
+        Value::String(ref v) => Evaluated::Str(v.clone()),
+        Value::Bool(v) => Evaluated::Str(v.to_string()),
+        Value::F32(v) => Evaluated::Float(v as f64),
+        Value::F64(v) => Evaluated::Float(v),
+        Value::I8(v) => Evaluated::Int(v as i64),
+        Value::I16(v) => Evaluated::Int(v as i64),
+        Value::I32(v) => Evaluated::Int(v as i64),
+        Value::I64(v) => Evaluated::Int(v),
+        Value::U8(v) => Evaluated::Int(v as i64),
+        Value::U16(v) => Evaluated::Int(v as i64),
+        Value::U32(v) => Evaluated::Int(v as i64),
+        Value::U64(v) => i64::try_from(v).map_or(Evaluated::Float(v as f64), Evaluated::Int),
+        Value::Array(_) => anyhow::bail!("Metadata key '{}' is an array and can't be substituted", key),
+    };
+    Ok(Some(evaluated))
+}
+
+// Evaluate an expression against the GGUF metadata
+fn evaluate(expr: &Expr, gguf: &GgufFile) -> Result<Evaluated> {
+    match expr {
+        Expr::Int(v) => Ok(Evaluated::Int(*v)),
+        Expr::Float(v) => Ok(Evaluated::Float(*v)),
+        Expr::Str(v) => Ok(Evaluated::Str(v.clone())),
+        Expr::Key(key) => lookup(key, gguf)?
+            .with_context(|| format!("GGUF metadata key '{}' not found", key)),
+        Expr::Call(name, args) => match name.as_str() {
+            "default" => {
+                if args.len() != 2 {
+                    anyhow::bail!("default() takes 2 arguments, got {}", args.len());
+                }
+                // Only a missing key falls back; other errors are still reported
+                match &args[0] {
+                    Expr::Key(key) => match lookup(key, gguf)? {
+                        Some(value) => Ok(value),
+                        None => evaluate(&args[1], gguf),
+                    },
+                    other => evaluate(other, gguf),
+                }
+            }
+            "min" | "max" => {
+                let mut best: Option<Evaluated> = None;
+                for arg in args {
+                    let value = evaluate(arg, gguf)?;
+                    let number = value
+                        .as_f64()
+                        .with_context(|| format!("{}() needs numbers but got '{}'", name, value))?;
+                    let better = match &best {
+                        None => true,
+                        Some(current) => {
+                            let current = current.as_f64().unwrap_or_default();
+                            if name == "min" { number < current } else { number > current }
+                        }
+                    };
+                    if better {
+                        best = Some(value);
+                    }
+                }
+                best.with_context(|| format!("{}() needs at least one argument", name))
+            }
+            other => anyhow::bail!("Unknown function '{}', expected min, max or default", other),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::tests::{build_gguf, string_value};
+
+    fn sample() -> GgufFile {
+        let bytes = build_gguf(
+            &[
+                ("general.architecture", 8, string_value("qwen2")),
+                ("general.name", 8, string_value("Qwen2.5 7B")),
+                ("qwen2.context_length", 4, 131072u32.to_le_bytes().to_vec()),
+                ("qwen2.rope.freq_base", 6, 1000000f32.to_le_bytes().to_vec()),
+            ],
+            &[],
+            0,
+        );
+        crate::gguf::parse(&bytes, Some(bytes.len() as u64)).unwrap()
+    }
+
+    #[test]
+    fn test_expand_simple_keys() {
+        let gguf = sample();
+        assert_eq!(
+            expand_gguf_placeholders("{{gguf:general.architecture}}", &gguf).unwrap(),
+            "qwen2"
+        );
+        assert_eq!(
+            expand_gguf_placeholders("--alias={{gguf:general.name}}", &gguf).unwrap(),
+            "--alias=Qwen2.5 7B"
+        );
+        assert_eq!(
+            expand_gguf_placeholders("{{gguf:{arch}.context_length}}", &gguf).unwrap(),
+            "131072"
+        );
+        assert_eq!(expand_gguf_placeholders("-m {{memfd}}", &gguf).unwrap(), "-m {{memfd}}");
+    }
+
+    #[test]
+    fn test_expand_functions() {
+        let gguf = sample();
+        assert_eq!(
+            expand_gguf_placeholders("{{gguf:min({arch}.context_length, 32768)}}", &gguf).unwrap(),
+            "32768"
+        );
+        assert_eq!(
+            expand_gguf_placeholders("{{gguf:max(qwen2.context_length,4096)}}", &gguf).unwrap(),
+            "131072"
+        );
+        assert_eq!(
+            expand_gguf_placeholders("{{gguf:default(general.license, \"unknown\")}}", &gguf).unwrap(),
+            "unknown"
+        );
+        assert_eq!(
+            expand_gguf_placeholders("{{gguf:default({arch}.context_length, 2048)}}", &gguf).unwrap(),
+            "131072"
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let gguf = sample();
+        assert!(expand_gguf_placeholders("{{gguf:llama.context_length}}", &gguf).is_err());
+        assert!(expand_gguf_placeholders("{{gguf:min(general.name, 1)}}", &gguf).is_err());
+        assert!(expand_gguf_placeholders("{{gguf:avg(1, 2)}}", &gguf).is_err());
+        assert!(expand_gguf_placeholders("{{gguf:general.name", &gguf).is_err());
+    }
+
+    #[test]
+    fn test_expand_memfd_links() {
+        let mut requested = Vec::new();
+        let expanded = expand_memfd_links(
+            "--model={{memfd_link:model.gguf}} --draft={{memfd_link:2:draft.gguf}}",
+            |number, name| {
+                requested.push((number, name.to_string()));
+                Ok(format!("/run/dir/{}", name))
+            },
+        )
+        .unwrap();
+        assert_eq!(expanded, "--model=/run/dir/model.gguf --draft=/run/dir/draft.gguf");
+        assert_eq!(
+            requested,
+            vec![(1, "model.gguf".to_string()), (2, "draft.gguf".to_string())]
+        );
+
+        // Errors from creating the link are reported with the placeholder
+        let err = expand_memfd_links("{{memfd_link:3:x}}", |_, _| anyhow::bail!("no memfd 3"))
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("{{memfd_link:3:x}}"));
+    }
+}